@@ -46,7 +46,7 @@ fn generate_string() -> String {
 }
 
 fn build_headers() -> HeaderMap {
-    let mut headers_map: HeaderMap = (&*internal_benches::hop_headers())
+    let mut headers_map: HeaderMap = internal_benches::hop_headers()
         .iter()
         .map(|el: &'static HeaderName| (el.clone(), generate_string().parse().unwrap()))
         .collect();
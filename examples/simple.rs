@@ -1,6 +1,7 @@
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::{Body, Request, Response, Server};
+use hyper_reverse_proxy::error_pages::ErrorPages;
 use hyper_reverse_proxy::ReverseProxy;
 use hyper_trust_dns::{RustlsHttpsConnector, TrustDnsResolver};
 use std::net::IpAddr;
@@ -11,6 +12,7 @@ lazy_static::lazy_static! {
         ReverseProxy::new(
             hyper::Client::builder().build::<_, hyper::Body>(TrustDnsResolver::default().into_rustls_webpki_https_connector()),
         )
+        .with_error_pages(ErrorPages::new())
     };
 }
 
@@ -21,27 +23,13 @@ fn debug_request(req: &Request<Body>) -> Result<Response<Body>, Infallible> {
 
 async fn handle(client_ip: IpAddr, req: Request<Body>) -> Result<Response<Body>, Infallible> {
     if req.uri().path().starts_with("/target/first") {
-        match PROXY_CLIENT
-            .call(client_ip, "http://127.0.0.1:13901", req)
-            .await
-        {
-            Ok(response) => Ok(response),
-            Err(_error) => Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::empty())
-                .unwrap()),
-        }
+        Ok(PROXY_CLIENT
+            .respond(client_ip, "http://127.0.0.1:13901", req)
+            .await)
     } else if req.uri().path().starts_with("/target/second") {
-        match PROXY_CLIENT
-            .call(client_ip, "http://127.0.0.1:13902", req)
-            .await
-        {
-            Ok(response) => Ok(response),
-            Err(_error) => Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::empty())
-                .unwrap()),
-        }
+        Ok(PROXY_CLIENT
+            .respond(client_ip, "http://127.0.0.1:13902", req)
+            .await)
     } else {
         debug_request(&req)
     }
@@ -0,0 +1,198 @@
+//! Access logging for proxied exchanges.
+//!
+//! An [`AccessLog`] attached to a [`ReverseProxy`](crate::ReverseProxy) records one line per
+//! proxied exchange. The line is emitted once the response body has been fully sent to the
+//! client (or dropped), so the byte count reflects what was actually transferred.
+
+use hyper::{Method, StatusCode, Version};
+use serde::Serialize;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Output format of the access log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// NCSA Common Log Format.
+    Common,
+    /// NCSA Combined Log Format, which adds the referer and user agent to `Common`.
+    Combined,
+    /// One JSON object per line, with every field of [`AccessLogRecord`].
+    Json,
+}
+
+/// Destination of formatted access log lines.
+pub trait AccessLogSink: Send + Sync {
+    fn write(&self, line: &str);
+}
+
+impl<F: Fn(&str) + Send + Sync> AccessLogSink for F {
+    fn write(&self, line: &str) {
+        self(line)
+    }
+}
+
+/// Sink that emits every line as a `tracing` event with the `access_log` target.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingSink;
+
+impl AccessLogSink for TracingSink {
+    fn write(&self, line: &str) {
+        info!(target: "access_log", "{}", line);
+    }
+}
+
+/// Everything known about a single proxied exchange.
+#[derive(Debug, Clone)]
+pub struct AccessLogRecord {
+    pub timestamp: SystemTime,
+    pub client_ip: IpAddr,
+    pub method: Method,
+    pub uri: String,
+    pub forwarded_uri: String,
+    pub version: Version,
+    /// For requests that failed in the proxy, the status of the error page they are answered
+    /// with by [`respond`](crate::ReverseProxy::respond).
+    pub status: StatusCode,
+    pub bytes: u64,
+    pub upstream_latency: Duration,
+    pub upstream_addr: String,
+    pub upgrade: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessLogRecord {
+    /// Renders the record as a single line in the given format.
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => {
+                let mut line = self.common();
+                let _ = write!(
+                    line,
+                    " \"{}\" \"{}\"",
+                    self.referer.as_deref().unwrap_or("-"),
+                    self.user_agent.as_deref().unwrap_or("-")
+                );
+                line
+            }
+            AccessLogFormat::Json => self.json(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let bytes = if self.bytes == 0 {
+            "-".to_string()
+        } else {
+            self.bytes.to_string()
+        };
+
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {}",
+            self.client_ip,
+            clf_timestamp(self.timestamp),
+            self.method,
+            self.uri,
+            self.version,
+            self.status.as_u16(),
+            bytes
+        )
+    }
+
+    fn json(&self) -> String {
+        let line = JsonLine {
+            timestamp: rfc3339_timestamp(self.timestamp),
+            client_ip: self.client_ip,
+            method: self.method.as_str(),
+            uri: &self.uri,
+            forwarded_uri: &self.forwarded_uri,
+            version: format!("{:?}", self.version),
+            status: self.status.as_u16(),
+            bytes: self.bytes,
+            upstream_latency_ms: self.upstream_latency.as_micros() as f64 / 1000.0,
+            upstream_addr: &self.upstream_addr,
+            upgrade: self.upgrade.as_deref(),
+            referer: self.referer.as_deref(),
+            user_agent: self.user_agent.as_deref(),
+        };
+
+        serde_json::to_string(&line).unwrap_or_default()
+    }
+}
+
+// The fields of a JSON line, in the order they are written
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: String,
+    client_ip: IpAddr,
+    method: &'a str,
+    uri: &'a str,
+    forwarded_uri: &'a str,
+    version: String,
+    status: u16,
+    bytes: u64,
+    upstream_latency_ms: f64,
+    upstream_addr: &'a str,
+    upgrade: Option<&'a str>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// An access log configuration: a line format and the sink receiving the lines.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Arc<dyn AccessLogSink>,
+}
+
+impl AccessLog {
+    pub fn new<S: AccessLogSink + 'static>(format: AccessLogFormat, sink: S) -> Self {
+        Self {
+            format,
+            sink: Arc::new(sink),
+        }
+    }
+
+    pub fn format(&self) -> AccessLogFormat {
+        self.format
+    }
+
+    pub fn log(&self, record: &AccessLogRecord) {
+        self.sink.write(&record.format(self.format));
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// The day, month, year and time of day of an IMF-fixdate such as
+// `Sun, 06 Nov 1994 08:49:37 GMT`
+fn date_fields(time: SystemTime) -> (String, String, String, String) {
+    let date = httpdate::fmt_http_date(time);
+    let mut fields = date.split(' ').skip(1).map(str::to_string);
+    let mut next = || fields.next().unwrap_or_default();
+
+    (next(), next(), next(), next())
+}
+
+fn clf_timestamp(time: SystemTime) -> String {
+    let (day, month, year, clock) = date_fields(time);
+    format!("{}/{}/{}:{} +0000", day, month, year, clock)
+}
+
+fn rfc3339_timestamp(time: SystemTime) -> String {
+    let (day, month, year, clock) = date_fields(time);
+    let month = MONTHS.iter().position(|name| *name == month).unwrap_or(0) + 1;
+    format!("{}-{:02}-{}T{}Z", year, month, day, clock)
+}
@@ -0,0 +1,350 @@
+//! IP based access control.
+//!
+//! An [`AccessControl`] allows or denies requests by the CIDR ranges containing the client IP.
+//! The most specific matching range decides, so a large range can be denied with exceptions
+//! allowed inside it. Ranges are kept in a prefix trie, so lookups cost at most one step per
+//! address bit however long the lists are.
+//!
+//! ```
+//! use hyper_reverse_proxy::acl::AccessControl;
+//!
+//! let acl = AccessControl::new()
+//!     .deny("10.0.0.0/8".parse().unwrap())
+//!     .allow("10.1.0.0/16".parse().unwrap());
+//!
+//! let headers = hyper::HeaderMap::new();
+//! assert!(acl.allows("10.1.2.3".parse().unwrap(), &headers));
+//! assert!(!acl.allows("10.2.0.1".parse().unwrap(), &headers));
+//! // Allowing a network turns the lists into an allowlist
+//! assert!(!acl.allows("192.0.2.1".parse().unwrap(), &headers));
+//! ```
+
+use crate::forwarded;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use std::iter::FromIterator;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Error returned when parsing an invalid [`IpNet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpNet(String);
+
+impl std::fmt::Display for InvalidIpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid network: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpNet {}
+
+/// An IPv4 or IPv6 network in CIDR notation, such as `192.0.2.0/24` or `2001:db8::/32`.
+///
+/// A bare address parses as a network containing only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Fails if the prefix length is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, InvalidIpNet> {
+        if prefix > max_prefix(addr) {
+            return Err(InvalidIpNet(format!("{}/{}", addr, prefix)));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, prefix) = canonical_net(*self);
+
+        match (bits(net), bits(canonical(ip))) {
+            ((net, width), (ip, ip_width)) if width == ip_width => {
+                prefix == 0 || (net ^ ip) >> (width - prefix) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpNet(s.to_string());
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse().map_err(|_| invalid())?;
+                (addr, prefix.parse().map_err(|_| invalid())?)
+            }
+            None => {
+                let addr = s.trim().parse().map_err(|_| invalid())?;
+                (addr, max_prefix(addr))
+            }
+        };
+
+        Self::new(addr, prefix).map_err(|_| invalid())
+    }
+}
+
+impl From<Ipv4Addr> for IpNet {
+    fn from(addr: Ipv4Addr) -> Self {
+        Self {
+            addr: IpAddr::V4(addr),
+            prefix: 32,
+        }
+    }
+}
+
+impl From<Ipv6Addr> for IpNet {
+    fn from(addr: Ipv6Addr) -> Self {
+        Self {
+            addr: IpAddr::V6(addr),
+            prefix: 128,
+        }
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node<V> {
+    children: [Option<usize>; 2],
+    value: Option<V>,
+}
+
+impl<V> Node<V> {
+    fn new() -> Self {
+        Self {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+/// A binary prefix trie mapping networks to values, with longest prefix lookups.
+///
+/// IPv4-mapped IPv6 addresses are looked up as IPv4 addresses.
+#[derive(Debug, Clone)]
+pub struct IpTrie<V> {
+    v4: Vec<Node<V>>,
+    v6: Vec<Node<V>>,
+}
+
+impl<V> Default for IpTrie<V> {
+    fn default() -> Self {
+        Self {
+            v4: vec![Node::new()],
+            v6: vec![Node::new()],
+        }
+    }
+}
+
+impl<V> IpTrie<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of `net`, replacing the previous one.
+    pub fn insert(&mut self, net: IpNet, value: V) {
+        let (addr, prefix) = canonical_net(net);
+        let (addr, width) = bits(addr);
+        let nodes = self.nodes_mut(width);
+        let mut index = 0;
+
+        for position in 0..prefix {
+            let bit = ((addr >> (width - 1 - position)) & 1) as usize;
+            index = match nodes[index].children[bit] {
+                Some(child) => child,
+                None => {
+                    nodes.push(Node::new());
+                    let child = nodes.len() - 1;
+                    nodes[index].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+
+        nodes[index].value = Some(value);
+    }
+
+    /// The value of the most specific network containing `ip`.
+    pub fn longest_match(&self, ip: IpAddr) -> Option<&V> {
+        let (addr, width) = bits(canonical(ip));
+        let nodes = if width == 32 { &self.v4 } else { &self.v6 };
+        let mut index = 0;
+        let mut best = nodes[0].value.as_ref();
+
+        for position in 0..width {
+            let bit = ((addr >> (width - 1 - position)) & 1) as usize;
+            index = match nodes[index].children[bit] {
+                Some(child) => child,
+                None => break,
+            };
+            best = nodes[index].value.as_ref().or(best);
+        }
+
+        best
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.longest_match(ip).is_some()
+    }
+
+    fn nodes_mut(&mut self, width: u8) -> &mut Vec<Node<V>> {
+        if width == 32 {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        }
+    }
+}
+
+impl<V> FromIterator<(IpNet, V)> for IpTrie<V> {
+    fn from_iter<I: IntoIterator<Item = (IpNet, V)>>(iter: I) -> Self {
+        let mut trie = Self::new();
+        for (net, value) in iter {
+            trie.insert(net, value);
+        }
+        trie
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Allow,
+    Deny,
+}
+
+/// Allow and deny lists of networks, see the [module documentation](self).
+///
+/// When no network contains the client IP, the request is denied if any network was allowed and
+/// allowed otherwise. Denied requests are answered with `403 Forbidden`.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    rules: IpTrie<Rule>,
+    has_allow: bool,
+    trusted_proxies: IpTrie<()>,
+    forbidden_body: Option<(HeaderValue, String)>,
+}
+
+impl AccessControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, net: IpNet) -> Self {
+        self.rules.insert(net, Rule::Allow);
+        self.has_allow = true;
+        self
+    }
+
+    pub fn deny(mut self, net: IpNet) -> Self {
+        self.rules.insert(net, Rule::Deny);
+        self
+    }
+
+    /// Trusts the `X-Forwarded-For` header of requests coming from this network, so the
+    /// address of the client behind the proxy is checked.
+    pub fn trusted_proxy(mut self, net: IpNet) -> Self {
+        self.trusted_proxies.insert(net, ());
+        self
+    }
+
+    /// The body of `403` responses. Without one, the response is empty, or gets an
+    /// [error page](crate::error_pages) if the proxy has them.
+    pub fn forbidden_body<S: Into<String>>(mut self, content_type: &'static str, body: S) -> Self {
+        self.forbidden_body = Some((HeaderValue::from_static(content_type), body.into()));
+        self
+    }
+
+    /// The client IP the lists are checked against, see
+    /// [`forwarded::client_ip`](crate::forwarded::client_ip).
+    pub fn client_ip(&self, peer_ip: IpAddr, headers: &HeaderMap) -> IpAddr {
+        forwarded::client_ip(headers, peer_ip, |ip| self.trusted_proxies.contains(ip))
+    }
+
+    /// Whether a request from `peer_ip` with these headers is allowed.
+    pub fn allows(&self, peer_ip: IpAddr, headers: &HeaderMap) -> bool {
+        let client_ip = self.client_ip(peer_ip, headers);
+
+        match self.rules.longest_match(client_ip) {
+            Some(Rule::Allow) => true,
+            Some(Rule::Deny) => false,
+            None => !self.has_allow,
+        }
+    }
+
+    /// Returns the `403` response if the request is denied.
+    pub fn check(&self, peer_ip: IpAddr, headers: &HeaderMap) -> Option<Response<Body>> {
+        if self.allows(peer_ip, headers) {
+            return None;
+        }
+
+        debug!("Denying request from {}", peer_ip);
+        Some(self.forbidden())
+    }
+
+    fn forbidden(&self) -> Response<Body> {
+        match &self.forbidden_body {
+            Some((content_type, body)) => {
+                let mut response = Response::new(Body::from(body.clone()));
+                *response.status_mut() = StatusCode::FORBIDDEN;
+                let headers = response.headers_mut();
+                headers.insert(CONTENT_TYPE, content_type.clone());
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                response
+            }
+            None => crate::error_pages::generated(StatusCode::FORBIDDEN),
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+// Networks of IPv4-mapped IPv6 addresses as IPv4 networks
+fn canonical_net(net: IpNet) -> (IpAddr, u8) {
+    match canonical(net.addr) {
+        IpAddr::V4(v4) if net.addr.is_ipv6() && net.prefix >= 96 => {
+            (IpAddr::V4(v4), net.prefix - 96)
+        }
+        _ => (net.addr, net.prefix),
+    }
+}
+
+// The address as an integer and its width in bits
+fn bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
@@ -0,0 +1,277 @@
+//! Session affinity for stateful backends.
+//!
+//! [`SessionAffinity`] keeps a client on the backend that served its first request. The chosen
+//! backend is recorded in an affinity cookie set on the response, whose value is an opaque token
+//! signed with a secret, so clients can neither learn the backend addresses nor forge tokens.
+//! The token can also be sent in a request header by clients that do not keep cookies.
+//!
+//! A client whose backend is unhealthy is moved to another one and gets a new cookie. Backends
+//! are marked unhealthy explicitly, or for a while when connecting to them fails. Requests
+//! without a body are then retried on another healthy backend right away.
+//!
+//! ```no_run
+//! use hyper_reverse_proxy::affinity::SessionAffinity;
+//! use hyper_reverse_proxy::ReverseProxy;
+//! # async fn run(request: hyper::Request<hyper::Body>) {
+//!
+//! let proxy = ReverseProxy::new(hyper::Client::new());
+//! let affinity = SessionAffinity::new(["http://10.0.0.1:8080", "http://10.0.0.2:8080"], b"secret");
+//!
+//! let response = proxy
+//!     .call_with_affinity("127.0.0.1".parse().unwrap(), &affinity, request)
+//!     .await;
+//! # }
+//! ```
+
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, SET_COOKIE};
+use hyper::{Body, Request, Response};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_COOKIE_NAME: &str = "proxy_affinity";
+const DEFAULT_RECOVERY: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Backend {
+    forward_uri: String,
+    token: String,
+}
+
+/// Configuration and health state of session affinity. Clones share the same health state.
+#[derive(Debug, Clone)]
+pub struct SessionAffinity {
+    backends: Arc<Vec<Backend>>,
+    cookie_name: String,
+    header: Option<HeaderName>,
+    secure: bool,
+    max_age: Option<Duration>,
+    recovery: Duration,
+    // Backends that are unhealthy, until the given instant or until marked healthy
+    unhealthy: Arc<Mutex<HashMap<usize, Option<Instant>>>>,
+    next: Arc<AtomicUsize>,
+}
+
+/// The backend chosen for a request.
+#[derive(Debug, Clone)]
+pub struct Selection<'a> {
+    affinity: &'a SessionAffinity,
+    backend: usize,
+    renewed: bool,
+}
+
+impl SessionAffinity {
+    /// Affinity between clients and `backends`, given like the forward URI of
+    /// [`ReverseProxy::call`](crate::ReverseProxy::call). Tokens are signed with `secret`.
+    pub fn new<I, S>(backends: I, secret: &[u8]) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let backends = backends
+            .into_iter()
+            .map(|forward_uri| {
+                let forward_uri = forward_uri.into();
+                Backend {
+                    token: token(secret, &forward_uri),
+                    forward_uri,
+                }
+            })
+            .collect();
+
+        Self {
+            backends: Arc::new(backends),
+            cookie_name: DEFAULT_COOKIE_NAME.to_string(),
+            header: None,
+            secure: false,
+            max_age: None,
+            recovery: DEFAULT_RECOVERY,
+            unhealthy: Arc::new(Mutex::new(HashMap::new())),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The name of the affinity cookie. Defaults to `proxy_affinity`.
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// A request header that carries the affinity token as well, for clients that do not keep
+    /// cookies. The header is set on responses along with the cookie.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid header name.
+    pub fn header(mut self, name: &str) -> Self {
+        self.header = Some(HeaderName::from_bytes(name.as_bytes()).expect("invalid header name"));
+        self
+    }
+
+    /// Marks the affinity cookie `Secure`.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// The `Max-Age` of the affinity cookie. By default, it is a session cookie.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// How long a backend that could not be connected to is considered unhealthy. Defaults to
+    /// 10 seconds.
+    pub fn recovery(mut self, recovery: Duration) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Stops routing clients to `forward_uri` until it is marked healthy again.
+    pub fn mark_unhealthy(&self, forward_uri: &str) {
+        if let Some(backend) = self.position(forward_uri) {
+            self.unhealthy.lock().unwrap().insert(backend, None);
+        }
+    }
+
+    pub fn mark_healthy(&self, forward_uri: &str) {
+        if let Some(backend) = self.position(forward_uri) {
+            self.unhealthy.lock().unwrap().remove(&backend);
+        }
+    }
+
+    /// The backend for `request`: the one named by its affinity token if it is healthy,
+    /// otherwise the next healthy backend in turn. None if no backend is healthy.
+    pub fn select<B>(&self, request: &Request<B>) -> Option<Selection<'_>> {
+        let headers = request.headers();
+        let token = crate::cookie(headers, &self.cookie_name).or_else(|| {
+            self.header
+                .as_ref()
+                .and_then(|name| headers.get(name))
+                .and_then(|value| value.to_str().ok())
+        });
+
+        if let Some(token) = token {
+            match self.backends.iter().position(|backend| {
+                crate::constant_time_eq(backend.token.as_bytes(), token.as_bytes())
+            }) {
+                Some(backend) if self.is_healthy(backend) => {
+                    return Some(Selection {
+                        affinity: self,
+                        backend,
+                        renewed: false,
+                    });
+                }
+                Some(backend) => debug!(
+                    "Affine backend {} is unhealthy",
+                    self.backends[backend].forward_uri
+                ),
+                None => debug!("Ignoring invalid affinity token"),
+            }
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.backends.len())
+            .map(|i| (start + i) % self.backends.len())
+            .find(|backend| self.is_healthy(*backend))
+            .map(|backend| Selection {
+                affinity: self,
+                backend,
+                renewed: true,
+            })
+    }
+
+    /// Removes the affinity cookie from `headers`, so it is not forwarded to the backend.
+    pub(crate) fn strip_cookie(&self, headers: &mut HeaderMap) {
+        crate::remove_cookie(headers, &self.cookie_name);
+    }
+
+    pub(crate) fn backend_count(&self) -> usize {
+        self.backends.len()
+    }
+
+    fn position(&self, forward_uri: &str) -> Option<usize> {
+        self.backends
+            .iter()
+            .position(|backend| backend.forward_uri == forward_uri)
+    }
+
+    fn is_healthy(&self, backend: usize) -> bool {
+        let mut unhealthy = self.unhealthy.lock().unwrap();
+
+        match unhealthy.get(&backend) {
+            None => true,
+            Some(Some(until)) if *until <= Instant::now() => {
+                unhealthy.remove(&backend);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+}
+
+impl Selection<'_> {
+    /// The forward URI of the selected backend.
+    pub fn forward_uri(&self) -> &str {
+        &self.affinity.backends[self.backend].forward_uri
+    }
+
+    /// Marks the backend unhealthy for the recovery period, after connecting to it failed.
+    pub fn connect_failed(&self) {
+        debug!("Marking {} unhealthy", self.forward_uri());
+
+        let until = Instant::now() + self.affinity.recovery;
+        self.affinity
+            .unhealthy
+            .lock()
+            .unwrap()
+            .insert(self.backend, Some(until));
+    }
+
+    /// Records the selected backend on the response, unless the request already carried its
+    /// token.
+    pub fn apply(&self, response: &mut Response<Body>) {
+        if !self.renewed {
+            return;
+        }
+
+        let affinity = self.affinity;
+        let token = &affinity.backends[self.backend].token;
+
+        let mut cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax",
+            affinity.cookie_name, token
+        );
+        if let Some(max_age) = affinity.max_age {
+            let _ = write!(cookie, "; Max-Age={}", max_age.as_secs());
+        }
+        if affinity.secure {
+            cookie.push_str("; Secure");
+        }
+
+        let headers = response.headers_mut();
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            headers.append(SET_COOKIE, cookie);
+        }
+        if let Some(name) = &affinity.header {
+            headers.insert(name.clone(), HeaderValue::from_str(token).unwrap());
+        }
+    }
+}
+
+// An opaque token for a backend, which cannot be derived without the secret
+fn token(secret: &[u8], forward_uri: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(forward_uri.as_bytes());
+
+    mac.finalize().into_bytes()[..16]
+        .iter()
+        .fold(String::with_capacity(32), |mut token, byte| {
+            let _ = write!(token, "{:02x}", byte);
+            token
+        })
+}
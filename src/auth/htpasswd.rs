@@ -0,0 +1,100 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// Users and password hashes in the format of an Apache `htpasswd` file.
+///
+/// Every line holds a `user:hash` pair. Hashes made with bcrypt (`htpasswd -B`) and SHA-1
+/// (`htpasswd -s`) are supported, users with other hashes can never log in.
+#[derive(Clone, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    // Verified for unknown users, so that they are not rejected faster than known ones
+    dummy_hash: Option<String>,
+}
+
+impl std::fmt::Debug for Htpasswd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Htpasswd")
+            .field("users", &self.users.keys())
+            .field(
+                "dummy_cost",
+                &self.dummy_hash.as_deref().and_then(bcrypt_cost),
+            )
+            .finish()
+    }
+}
+
+impl Htpasswd {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Parses the content of a `htpasswd` file, skipping empty lines and `#` comments.
+    pub fn parse(content: &str) -> Self {
+        let mut users = HashMap::new();
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(':') {
+                Some((user, hash)) => {
+                    if !is_bcrypt(hash) && !hash.starts_with("{SHA}") {
+                        warn!("Unsupported password hash for user {}", user);
+                    }
+                    users.insert(user.to_string(), hash.to_string());
+                }
+                None => warn!("Skipping invalid htpasswd line"),
+            }
+        }
+
+        // As costly to verify as the costliest bcrypt hash of the file
+        let dummy_hash = users
+            .values()
+            .filter_map(|hash| bcrypt_cost(hash))
+            .max()
+            .and_then(|cost| bcrypt::hash("", cost).ok());
+
+        Self { users, dummy_hash }
+    }
+
+    /// Whether `password` is the password of `user`. bcrypt hashes are slow to check on
+    /// purpose, so this should not run on the async executor.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let hash = match (self.users.get(user), &self.dummy_hash) {
+            (Some(hash), _) => hash,
+            (None, Some(dummy_hash)) => {
+                let _ = bcrypt::verify(password, dummy_hash);
+                return false;
+            }
+            (None, None) => return false,
+        };
+
+        if is_bcrypt(hash) {
+            bcrypt::verify(password, hash).unwrap_or(false)
+        } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+            let expected = STANDARD.encode(Sha1::digest(password.as_bytes()));
+            crate::constant_time_eq(expected.as_bytes(), digest.as_bytes())
+        } else {
+            false
+        }
+    }
+}
+
+fn bcrypt_cost(hash: &str) -> Option<u32> {
+    if !is_bcrypt(hash) {
+        return None;
+    }
+    hash.get(4..6)?.parse().ok()
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
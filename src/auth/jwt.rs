@@ -0,0 +1,246 @@
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+const RSA_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+];
+const EC_ALGORITHMS: [Algorithm; 2] = [Algorithm::ES256, Algorithm::ES384];
+const ED_ALGORITHMS: [Algorithm; 1] = [Algorithm::EdDSA];
+const HMAC_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+/// Error returned when loading an invalid key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidKey(String);
+
+impl std::fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid key: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidKey {}
+
+fn invalid<S: Into<String>>(reason: S) -> InvalidKey {
+    InvalidKey(reason.into())
+}
+
+#[derive(Clone)]
+struct Key {
+    kid: Option<String>,
+    key: DecodingKey,
+    // The algorithms of the key type, tokens signed with others are not tried with it
+    algorithms: &'static [Algorithm],
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Value>,
+}
+
+/// Why a token was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenError {
+    Malformed,
+    Signature,
+    Expired,
+    NotYetValid,
+    Issuer,
+    Audience,
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            TokenError::Malformed => "malformed token",
+            TokenError::Signature => "invalid signature",
+            TokenError::Expired => "token expired",
+            TokenError::NotYetValid => "token not yet valid",
+            TokenError::Issuer => "unexpected issuer",
+            TokenError::Audience => "unexpected audience",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Validation of JSON Web Tokens against local keys, with the `jsonwebtoken` crate.
+///
+/// Tokens must carry an `exp` claim in the future, and an `nbf` claim, if any, in the past.
+/// The `RS*`, `PS*`, `ES256`, `ES384`, `EdDSA` and `HS*` algorithms are supported. When the
+/// token names a key with `kid`, only keys with that id or without an id are tried.
+#[derive(Clone, Default)]
+pub struct JwtValidator {
+    keys: Vec<Key>,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway: Duration,
+}
+
+impl std::fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtValidator")
+            .field("keys", &self.keys.len())
+            .field("issuers", &self.issuers)
+            .field("audiences", &self.audiences)
+            .field("leeway", &self.leeway)
+            .finish()
+    }
+}
+
+impl JwtValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the signing keys of a JSON Web Key Set. Keys of unsupported types and encryption
+    /// keys are skipped.
+    pub fn jwks(mut self, jwks: &str) -> Result<Self, InvalidKey> {
+        let jwks: Jwks = serde_json::from_str(jwks).map_err(|err| invalid(err.to_string()))?;
+
+        for jwk in jwks.keys {
+            if jwk
+                .get("use")
+                .is_some_and(|usage| usage != &Value::from("sig"))
+            {
+                continue;
+            }
+
+            let kty = jwk
+                .get("kty")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            let algorithms: &'static [Algorithm] =
+                match (kty.as_str(), jwk.get("crv").and_then(Value::as_str)) {
+                    ("RSA", _) => &RSA_ALGORITHMS,
+                    ("EC", Some("P-256")) | ("EC", Some("P-384")) => &EC_ALGORITHMS,
+                    ("OKP", Some("Ed25519")) => &ED_ALGORITHMS,
+                    ("oct", _) => &HMAC_ALGORITHMS,
+                    _ => {
+                        debug!("Skipping unsupported {} key", kty);
+                        continue;
+                    }
+                };
+
+            let jwk: Jwk = serde_json::from_value(jwk)
+                .map_err(|err| invalid(format!("invalid {} key: {}", kty, err)))?;
+            let key = DecodingKey::from_jwk(&jwk)
+                .map_err(|err| invalid(format!("invalid {} key: {}", kty, err)))?;
+            self.keys.push(Key {
+                kid: jwk.common.key_id,
+                key,
+                algorithms,
+            });
+        }
+
+        Ok(self)
+    }
+
+    /// Adds an RSA, EC or Ed25519 public key in PEM format, as `PUBLIC KEY` or
+    /// `RSA PUBLIC KEY`.
+    pub fn pem(mut self, kid: Option<&str>, pem: &str) -> Result<Self, InvalidKey> {
+        let pem = pem.as_bytes();
+        let (key, algorithms): (_, &'static [Algorithm]) = match DecodingKey::from_rsa_pem(pem) {
+            Ok(key) => (key, &RSA_ALGORITHMS),
+            Err(_) => match DecodingKey::from_ec_pem(pem) {
+                Ok(key) => (key, &EC_ALGORITHMS),
+                Err(_) => match DecodingKey::from_ed_pem(pem) {
+                    Ok(key) => (key, &ED_ALGORITHMS),
+                    Err(err) => return Err(invalid(err.to_string())),
+                },
+            },
+        };
+
+        self.keys.push(Key {
+            kid: kid.map(str::to_string),
+            key,
+            algorithms,
+        });
+        Ok(self)
+    }
+
+    /// Adds a shared secret for the `HS*` algorithms.
+    pub fn secret<S: AsRef<[u8]>>(mut self, kid: Option<&str>, secret: S) -> Self {
+        self.keys.push(Key {
+            kid: kid.map(str::to_string),
+            key: DecodingKey::from_secret(secret.as_ref()),
+            algorithms: &HMAC_ALGORITHMS,
+        });
+        self
+    }
+
+    /// Accepts tokens issued by `issuer`. Without any, the issuer is not checked.
+    pub fn issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuers.push(issuer.into());
+        self
+    }
+
+    /// Accepts tokens meant for `audience`. Without any, the audience is not checked.
+    pub fn audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audiences.push(audience.into());
+        self
+    }
+
+    /// Tolerated clock skew when checking `exp` and `nbf`, in whole seconds. Defaults to none.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// The claims of a valid token.
+    pub(crate) fn validate(&self, token: &str) -> Result<Map<String, Value>, TokenError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| TokenError::Malformed)?;
+
+        let keys = self
+            .keys
+            .iter()
+            .filter(|key| key.algorithms.contains(&header.alg))
+            .filter(|key| match (&header.kid, &key.kid) {
+                (Some(kid), Some(key_id)) => kid == key_id,
+                _ => true,
+            });
+
+        for key in keys {
+            match jsonwebtoken::decode(token, &key.key, &self.validation(header.alg)) {
+                Ok(data) => return Ok(data.claims),
+                // The claims are only checked once the signature was verified
+                Err(err) => match err.kind() {
+                    ErrorKind::ExpiredSignature => return Err(TokenError::Expired),
+                    ErrorKind::MissingRequiredClaim(_) => return Err(TokenError::Malformed),
+                    ErrorKind::ImmatureSignature => return Err(TokenError::NotYetValid),
+                    ErrorKind::InvalidIssuer => return Err(TokenError::Issuer),
+                    ErrorKind::InvalidAudience => return Err(TokenError::Audience),
+                    _ => continue,
+                },
+            }
+        }
+
+        Err(TokenError::Signature)
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = true;
+        validation.set_required_spec_claims(&["exp"]);
+
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+        }
+        if self.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audiences);
+        }
+
+        validation
+    }
+}
@@ -0,0 +1,262 @@
+//! Authentication of requests before they are forwarded.
+//!
+//! An [`Auth`] accepts requests carrying HTTP Basic credentials found in a [`Htpasswd`] file,
+//! a static bearer API key, or a bearer JSON Web Token accepted by a [`JwtValidator`]. Other
+//! requests are answered with `401 Unauthorized` and a `WWW-Authenticate` challenge.
+//!
+//! The verified [`Identity`] is added to the request extensions, and selected claims can be
+//! forwarded to the upstream as headers. Basic and API key identities have a single `sub` claim,
+//! the user or the name of the key. Headers used to forward claims are always removed from the
+//! incoming request, so clients cannot forge them.
+//!
+//! ```
+//! use hyper::header::HeaderName;
+//! use hyper_reverse_proxy::auth::{Auth, JwtValidator};
+//!
+//! let auth = Auth::new()
+//!     .api_key("secret-key", "ci")
+//!     .jwt(
+//!         JwtValidator::new()
+//!             .secret(None, b"shared secret")
+//!             .issuer("https://login.example")
+//!             .audience("api"),
+//!     )
+//!     .forward_claim("sub", HeaderName::from_static("x-auth-subject"))
+//!     .strip_credentials(true);
+//! ```
+
+mod htpasswd;
+mod jwt;
+
+pub use htpasswd::Htpasswd;
+pub use jwt::{InvalidKey, JwtValidator};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The verified identity of a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub claims: Map<String, Value>,
+}
+
+impl Identity {
+    fn subject(subject: &str) -> Self {
+        let mut claims = Map::new();
+        claims.insert("sub".to_string(), Value::String(subject.to_string()));
+        Self { claims }
+    }
+
+    /// The `sub` claim.
+    pub fn sub(&self) -> Option<&str> {
+        self.claims.get("sub").and_then(Value::as_str)
+    }
+}
+
+// The outcome of checking the credentials of a request
+enum Outcome {
+    Verified(Identity),
+    Missing,
+    InvalidBasic,
+    InvalidBearer,
+}
+
+/// Authentication configuration, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Auth {
+    htpasswd: Option<Arc<Htpasswd>>,
+    api_keys: HashMap<[u8; 32], String>,
+    jwt: Option<JwtValidator>,
+    claim_headers: Vec<(String, HeaderName)>,
+    strip_credentials: bool,
+    realm: String,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            htpasswd: None,
+            api_keys: HashMap::new(),
+            jwt: None,
+            claim_headers: Vec::new(),
+            strip_credentials: false,
+            realm: "proxy".to_string(),
+        }
+    }
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accepts HTTP Basic credentials of the users in `htpasswd`.
+    pub fn basic(mut self, htpasswd: Htpasswd) -> Self {
+        self.htpasswd = Some(Arc::new(htpasswd));
+        self
+    }
+
+    /// Accepts `key` as a bearer token, identifying the client as `name`. Only a hash of the
+    /// key is kept.
+    pub fn api_key<K: AsRef<[u8]>, S: Into<String>>(mut self, key: K, name: S) -> Self {
+        self.api_keys
+            .insert(Sha256::digest(key).into(), name.into());
+        self
+    }
+
+    /// Accepts bearer tokens that are JSON Web Tokens valid for `validator`.
+    pub fn jwt(mut self, validator: JwtValidator) -> Self {
+        self.jwt = Some(validator);
+        self
+    }
+
+    /// Forwards `claim` of verified identities in the `header` request header. String claims
+    /// are forwarded as is, others as JSON.
+    pub fn forward_claim<S: Into<String>>(mut self, claim: S, header: HeaderName) -> Self {
+        self.claim_headers.push((claim.into(), header));
+        self
+    }
+
+    /// Whether the `Authorization` header of verified requests is removed before they are
+    /// forwarded. Defaults to false.
+    pub fn strip_credentials(mut self, strip: bool) -> Self {
+        self.strip_credentials = strip;
+        self
+    }
+
+    /// The realm of the challenges. Defaults to `proxy`.
+    pub fn realm<S: Into<String>>(mut self, realm: S) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Verifies the credentials of the request, preparing it for forwarding. Returns the `401`
+    /// response if they are missing or invalid.
+    pub async fn check(&self, request: &mut Request<Body>) -> Option<Response<Body>> {
+        for (_, header) in &self.claim_headers {
+            request.headers_mut().remove(header);
+        }
+
+        let identity = match self.verify(request).await {
+            Outcome::Verified(identity) => identity,
+            outcome => return Some(self.unauthorized(outcome)),
+        };
+
+        for (claim, header) in &self.claim_headers {
+            let value = match identity.claims.get(claim) {
+                Some(Value::String(value)) => HeaderValue::from_str(value),
+                Some(value) => HeaderValue::from_str(&value.to_string()),
+                None => continue,
+            };
+            match value {
+                Ok(value) => {
+                    request.headers_mut().insert(header, value);
+                }
+                Err(_) => debug!("Not forwarding claim {} with invalid characters", claim),
+            }
+        }
+
+        if self.strip_credentials {
+            request.headers_mut().remove(AUTHORIZATION);
+        }
+
+        request.extensions_mut().insert(identity);
+        None
+    }
+
+    async fn verify(&self, request: &Request<Body>) -> Outcome {
+        let authorization = match request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(authorization) => authorization.trim(),
+            None => return Outcome::Missing,
+        };
+        let (scheme, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            if let Some(htpasswd) = &self.htpasswd {
+                return self.verify_basic(htpasswd, credentials).await;
+            }
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            return self.verify_bearer(credentials);
+        }
+
+        Outcome::Missing
+    }
+
+    async fn verify_basic(&self, htpasswd: &Arc<Htpasswd>, credentials: &str) -> Outcome {
+        let decoded = STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        let (user, password) = match decoded.as_deref().and_then(|d| d.split_once(':')) {
+            Some((user, password)) => (user.to_string(), password.to_string()),
+            None => return Outcome::InvalidBasic,
+        };
+
+        // Password hashes are slow to check on purpose, so keep them off the executor
+        let htpasswd = htpasswd.clone();
+        let verify_user = user.clone();
+        let verified =
+            tokio::task::spawn_blocking(move || htpasswd.verify(&verify_user, &password)).await;
+
+        match verified {
+            Ok(true) => Outcome::Verified(Identity::subject(&user)),
+            _ => {
+                debug!("Invalid basic credentials for user {}", user);
+                Outcome::InvalidBasic
+            }
+        }
+    }
+
+    fn verify_bearer(&self, token: &str) -> Outcome {
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        if let Some(name) = self.api_keys.get(&digest) {
+            return Outcome::Verified(Identity::subject(name));
+        }
+
+        match self.jwt.as_ref().map(|jwt| jwt.validate(token)) {
+            Some(Ok(claims)) => Outcome::Verified(Identity { claims }),
+            Some(Err(err)) => {
+                debug!("Rejecting bearer token: {}", err);
+                Outcome::InvalidBearer
+            }
+            None => Outcome::InvalidBearer,
+        }
+    }
+
+    fn unauthorized(&self, outcome: Outcome) -> Response<Body> {
+        let mut response = crate::error_pages::generated(StatusCode::UNAUTHORIZED);
+        let headers = response.headers_mut();
+
+        if self.htpasswd.is_some() {
+            let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm);
+            if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                headers.append(WWW_AUTHENTICATE, challenge);
+            }
+        }
+
+        if self.jwt.is_some() || !self.api_keys.is_empty() {
+            let challenge = match outcome {
+                Outcome::InvalidBearer => {
+                    format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm)
+                }
+                _ => format!("Bearer realm=\"{}\"", self.realm),
+            };
+            if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                headers.append(WWW_AUTHENTICATE, challenge);
+            }
+        }
+
+        response
+    }
+}
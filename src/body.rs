@@ -0,0 +1,533 @@
+use crate::ProxyError;
+use bytes::Bytes;
+#[cfg(feature = "cache")]
+use bytes::BytesMut;
+use futures_util::future;
+#[cfg(feature = "cache")]
+use futures_util::future::BoxFuture;
+use hyper::body::HttpBody;
+use hyper::{Body, HeaderMap};
+#[cfg(feature = "cache")]
+use std::future::Future;
+#[cfg(feature = "mirror")]
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+#[cfg(feature = "mirror")]
+use tokio::sync::mpsc;
+
+// hyper's `Body` can only wrap a stream of chunks, which loses the trailers of
+// the wrapped body, so wrapped bodies are pumped into a channel body by a task
+// instead. The task stops as soon as the returned body is dropped.
+fn into_body<B>(body: B) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: std::fmt::Display + Send,
+{
+    let (mut sender, receiver) = Body::channel();
+
+    tokio::spawn(async move {
+        futures_util::pin_mut!(body);
+
+        loop {
+            // Polling the sender first wakes the task up when the receiver is dropped
+            let next = future::poll_fn(|cx| match sender.poll_ready(cx) {
+                Poll::Ready(Ok(())) => body.as_mut().poll_data(cx).map(Some),
+                Poll::Ready(Err(_)) => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            })
+            .await;
+
+            match next {
+                Some(Some(Ok(chunk))) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Some(Err(err))) => {
+                    debug!("Aborting body: {}", err);
+                    sender.abort();
+                    return;
+                }
+                Some(None) => break,
+                None => return,
+            }
+        }
+
+        match future::poll_fn(|cx| body.as_mut().poll_trailers(cx)).await {
+            Ok(Some(trailers)) => {
+                let _ = sender.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                debug!("Aborting body: {}", err);
+                sender.abort();
+            }
+        }
+    });
+
+    receiver
+}
+
+type CompleteCallback = Box<dyn FnOnce(u64) + Send>;
+
+// Counts the bytes of a body as they are streamed and runs a callback once the
+// body is finished, either because it reached its end or because it was dropped.
+struct ObservedBody {
+    inner: Body,
+    bytes: u64,
+    on_complete: Option<CompleteCallback>,
+}
+
+impl ObservedBody {
+    fn complete(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(self.bytes);
+        }
+    }
+}
+
+impl HttpBody for ObservedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.bytes += chunk.len() as u64;
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(None) => {
+                self.complete();
+                Poll::Ready(None)
+            }
+            other => other,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+}
+
+impl Drop for ObservedBody {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
+
+/// Wraps `body` so that `on_complete` is called with the number of bytes sent
+/// once the body has been fully streamed or dropped.
+pub(crate) fn on_complete<F>(body: Body, on_complete: F) -> Body
+where
+    F: FnOnce(u64) + Send + 'static,
+{
+    if body.is_end_stream() {
+        on_complete(0);
+        return body;
+    }
+
+    into_body(ObservedBody {
+        inner: body,
+        bytes: 0,
+        on_complete: Some(Box::new(on_complete)),
+    })
+}
+
+// Fails the stream with the given error as soon as more than `max` bytes were
+// read from the inner body.
+struct LimitedBody {
+    inner: Body,
+    bytes: u64,
+    max: u64,
+    exceeded: Arc<AtomicBool>,
+    error: fn() -> ProxyError,
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+impl HttpBody for LimitedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, BoxError>>> {
+        if self.exceeded.load(Ordering::Relaxed) {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.bytes += chunk.len() as u64;
+
+                if self.bytes > self.max {
+                    debug!("Body exceeded the limit of {} bytes", self.max);
+                    self.exceeded.store(true, Ordering::Relaxed);
+                    Poll::Ready(Some(Err(Box::new((self.error)()))))
+                } else {
+                    Poll::Ready(Some(Ok(chunk)))
+                }
+            }
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(Box::new(err)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, BoxError>> {
+        if self.exceeded.load(Ordering::Relaxed) {
+            return Poll::Ready(Ok(None));
+        }
+
+        Pin::new(&mut self.inner)
+            .poll_trailers(cx)
+            .map_err(|err| Box::new(err) as BoxError)
+    }
+}
+
+/// Wraps `body` so that it fails with `error` once more than `max` bytes were
+/// streamed. The returned flag is set when that happens.
+pub(crate) fn limited(body: Body, max: u64, error: fn() -> ProxyError) -> (Body, Arc<AtomicBool>) {
+    let exceeded = Arc::new(AtomicBool::new(false));
+
+    if body.is_end_stream() {
+        return (body, exceeded);
+    }
+
+    let body = into_body(LimitedBody {
+        inner: body,
+        bytes: 0,
+        max,
+        exceeded: exceeded.clone(),
+        error,
+    });
+
+    (body, exceeded)
+}
+
+#[cfg(feature = "cache")]
+type TeeCallback = Box<dyn FnOnce(Bytes) -> BoxFuture<'static, ()> + Send>;
+
+// Collects a copy of the streamed body and hands it to a callback once the body
+// reached its end. The end of the body is only passed on after the future
+// returned by the callback completed. Bodies larger than `max` bytes, failed or
+// dropped bodies are not handed over.
+#[cfg(feature = "cache")]
+struct TeeBody {
+    inner: Body,
+    buffer: BytesMut,
+    max: usize,
+    on_complete: Option<TeeCallback>,
+    completing: Option<BoxFuture<'static, ()>>,
+}
+
+#[cfg(feature = "cache")]
+impl HttpBody for TeeBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        if let Some(completing) = self.completing.as_mut() {
+            return match completing.as_mut().poll(cx) {
+                Poll::Ready(()) => {
+                    self.completing = None;
+                    Poll::Ready(None)
+                }
+                Poll::Pending => Poll::Pending,
+            };
+        }
+
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if self.on_complete.is_some() {
+                    if self.buffer.len() + chunk.len() > self.max {
+                        debug!("Body exceeded {} bytes, not keeping a copy", self.max);
+                        self.on_complete = None;
+                        self.buffer = BytesMut::new();
+                    } else {
+                        self.buffer.extend_from_slice(&chunk);
+                    }
+                }
+
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.on_complete = None;
+                Poll::Ready(Some(Err(err)))
+            }
+            Poll::Ready(None) => match self.on_complete.take() {
+                Some(on_complete) => {
+                    let body = std::mem::take(&mut self.buffer).freeze();
+                    self.completing = Some(on_complete(body));
+                    self.poll_data(cx)
+                }
+                None => Poll::Ready(None),
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+}
+
+/// Wraps `body` so that `on_complete` receives a copy of it once it was fully
+/// streamed, if it is not larger than `max` bytes. Empty bodies are returned
+/// as is and their callback is spawned onto the runtime.
+#[cfg(feature = "cache")]
+pub(crate) fn tee<F, Fut>(body: Body, max: usize, on_complete: F) -> Body
+where
+    F: FnOnce(Bytes) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    if body.is_end_stream() {
+        tokio::spawn(on_complete(Bytes::new()));
+        return body;
+    }
+
+    into_body(TeeBody {
+        inner: body,
+        buffer: BytesMut::new(),
+        max,
+        on_complete: Some(Box::new(move |body| Box::pin(on_complete(body)))),
+        completing: None,
+    })
+}
+
+#[cfg(feature = "mirror")]
+enum Frame {
+    Data(Bytes),
+    Trailers(HeaderMap),
+}
+
+// Passes the body on while sending a copy of every chunk and the trailers to a
+// second body. When the copy falls more than the channel capacity behind, or the
+// body fails or is dropped before its end, the copy is aborted instead of holding
+// up the body.
+#[cfg(feature = "mirror")]
+struct SplitBody {
+    inner: Body,
+    sender: Option<mpsc::Sender<Frame>>,
+    aborted: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "mirror")]
+impl SplitBody {
+    fn send(&mut self, frame: Frame) {
+        let sent = self
+            .sender
+            .as_ref()
+            .map(|sender| sender.try_send(frame).is_ok());
+        if sent == Some(false) {
+            debug!("Copy of the body fell behind, aborting it");
+            self.abort();
+        }
+    }
+
+    fn abort(&mut self) {
+        if self.sender.take().is_some() {
+            self.aborted.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "mirror")]
+impl HttpBody for SplitBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.send(Frame::Data(chunk.clone()));
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(err))) => {
+                self.abort();
+                Poll::Ready(Some(Err(err)))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        match Pin::new(&mut self.inner).poll_trailers(cx) {
+            Poll::Ready(Ok(trailers)) => {
+                if let Some(trailers) = &trailers {
+                    self.send(Frame::Trailers(trailers.clone()));
+                }
+                self.sender = None;
+                Poll::Ready(Ok(trailers))
+            }
+            Poll::Ready(Err(err)) => {
+                self.abort();
+                Poll::Ready(Err(err))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "mirror")]
+impl Drop for SplitBody {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+// The copy of a split body, failing if the copy was aborted.
+#[cfg(feature = "mirror")]
+struct CopyBody {
+    receiver: mpsc::Receiver<Frame>,
+    aborted: Arc<AtomicBool>,
+    trailers: Option<HeaderMap>,
+}
+
+#[cfg(feature = "mirror")]
+impl HttpBody for CopyBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, io::Error>>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(Frame::Data(chunk))) => Poll::Ready(Some(Ok(chunk))),
+            Poll::Ready(Some(Frame::Trailers(trailers))) => {
+                self.trailers = Some(trailers);
+                Poll::Ready(None)
+            }
+            Poll::Ready(None) if self.aborted.load(Ordering::Relaxed) => {
+                Poll::Ready(Some(Err(io::Error::other("body copy aborted"))))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, io::Error>> {
+        Poll::Ready(Ok(self.trailers.take()))
+    }
+}
+
+/// Splits `body` into itself and a copy streamed alongside it, buffering up to
+/// `capacity` chunks for the copy.
+#[cfg(feature = "mirror")]
+pub(crate) fn split(body: Body, capacity: usize) -> (Body, Body) {
+    if body.is_end_stream() {
+        return (body, Body::empty());
+    }
+
+    let (sender, receiver) = mpsc::channel(capacity);
+    let aborted = Arc::new(AtomicBool::new(false));
+
+    let copy = into_body(CopyBody {
+        receiver,
+        aborted: aborted.clone(),
+        trailers: None,
+    });
+    let body = into_body(SplitBody {
+        inner: body,
+        sender: Some(sender),
+        aborted,
+    });
+
+    (body, copy)
+}
+
+type ChunkFilter = Box<dyn FnMut(&[u8], bool) -> Bytes + Send>;
+
+// Passes every chunk through a filter, which is told when the body reached its
+// end so it can flush what it held back. Empty outputs are skipped.
+struct FilteredBody {
+    inner: Body,
+    filter: ChunkFilter,
+    done: bool,
+}
+
+impl HttpBody for FilteredBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            match Pin::new(&mut self.inner).poll_data(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let filtered = (self.filter)(&chunk, false);
+                    if !filtered.is_empty() {
+                        return Poll::Ready(Some(Ok(filtered)));
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Ready(None) => {
+                    self.done = true;
+                    let filtered = (self.filter)(&[], true);
+                    if !filtered.is_empty() {
+                        return Poll::Ready(Some(Ok(filtered)));
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+}
+
+/// Wraps `body` so that its chunks are replaced by the output of `filter`,
+/// which is called a last time with an empty chunk and `true` at the end.
+pub(crate) fn filter<F>(body: Body, filter: F) -> Body
+where
+    F: FnMut(&[u8], bool) -> Bytes + Send + 'static,
+{
+    into_body(FilteredBody {
+        inner: body,
+        filter: Box::new(filter),
+        done: false,
+    })
+}
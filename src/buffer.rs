@@ -0,0 +1,245 @@
+//! Opt-in buffering of request bodies.
+//!
+//! A streamed [`Body`] can only be sent once. When [`RequestBuffering`] is enabled on a
+//! [`ReverseProxy`](crate::ReverseProxy), the request body is read completely before it is
+//! forwarded: small bodies are kept in memory, larger ones spill to a temporary file, and
+//! bodies over a maximum size are rejected. The resulting [`BufferedBody`] is stored in the
+//! request extensions, where hooks can inspect it, and it can be replayed to retry the upstream
+//! request. A body replaced by a request hook is buffered again, so that retries replay it.
+
+use crate::ProxyError;
+use bytes::{Bytes, BytesMut};
+use futures_util::{StreamExt, TryStreamExt};
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+const DEFAULT_MEMORY_THRESHOLD: usize = 64 * 1024;
+const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Configuration of request body buffering.
+#[derive(Debug, Clone)]
+pub struct RequestBuffering {
+    memory_threshold: usize,
+    temp_dir: PathBuf,
+    max_size: u64,
+    retries: usize,
+}
+
+impl Default for RequestBuffering {
+    fn default() -> Self {
+        Self {
+            memory_threshold: DEFAULT_MEMORY_THRESHOLD,
+            temp_dir: std::env::temp_dir(),
+            max_size: DEFAULT_MAX_SIZE,
+            retries: 0,
+        }
+    }
+}
+
+impl RequestBuffering {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bodies larger than this many bytes are written to a temporary file. Defaults to 64 KiB.
+    pub fn memory_threshold(mut self, bytes: usize) -> Self {
+        self.memory_threshold = bytes;
+        self
+    }
+
+    /// Directory for spilled bodies. Defaults to [`std::env::temp_dir`].
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.temp_dir = dir.into();
+        self
+    }
+
+    /// Bodies larger than this many bytes are rejected with `413 Payload Too Large` instead of
+    /// being buffered. Defaults to 64 MiB.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Number of times a request is replayed when the upstream connection could not be
+    /// established. Defaults to 0.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub(crate) fn max_retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Reads `body` to its end.
+    pub async fn buffer(&self, mut body: Body) -> Result<BufferedBody, ProxyError> {
+        let mut memory = BytesMut::new();
+
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if (memory.len() + chunk.len()) as u64 > self.max_size {
+                return Err(ProxyError::RequestBodyTooLarge);
+            }
+
+            if memory.len() + chunk.len() > self.memory_threshold {
+                return self.spill(memory.freeze(), chunk, body).await;
+            }
+
+            memory.extend_from_slice(&chunk);
+        }
+
+        debug!("Buffered request body of {} bytes in memory", memory.len());
+
+        Ok(BufferedBody {
+            inner: Arc::new(Buffer::Memory(memory.freeze())),
+        })
+    }
+
+    async fn spill(
+        &self,
+        buffered: Bytes,
+        chunk: Bytes,
+        mut body: Body,
+    ) -> Result<BufferedBody, ProxyError> {
+        let path = self.temp_dir.join(format!(
+            "hyper-reverse-proxy-{}-{}.body",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        debug!("Spilling request body to {}", path.display());
+
+        // Removes the file again if buffering fails
+        let temp_file = TempFile(path);
+
+        let mut file = tokio::fs::File::create(&temp_file.0).await?;
+        file.write_all(&buffered).await?;
+        file.write_all(&chunk).await?;
+        let mut len = (buffered.len() + chunk.len()) as u64;
+
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            len += chunk.len() as u64;
+            if len > self.max_size {
+                return Err(ProxyError::RequestBodyTooLarge);
+            }
+            file.write_all(&chunk).await?;
+        }
+
+        file.flush().await?;
+
+        debug!(
+            "Buffered request body of {} bytes in {}",
+            len,
+            temp_file.0.display()
+        );
+
+        Ok(BufferedBody {
+            inner: Arc::new(Buffer::File {
+                file: temp_file,
+                len,
+            }),
+        })
+    }
+}
+
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.0);
+        let remove = move || {
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!("Failed to remove {}: {}", path.display(), err);
+            }
+        };
+
+        // Keeps the blocking removal off the async workers
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+enum Buffer {
+    Memory(Bytes),
+    File { file: TempFile, len: u64 },
+}
+
+/// A fully read request body that can be inspected and replayed any number of times.
+///
+/// Cloning is cheap, clones share the same buffer. A spilled file is removed once the last
+/// clone is dropped.
+#[derive(Clone)]
+pub struct BufferedBody {
+    inner: Arc<Buffer>,
+}
+
+impl BufferedBody {
+    pub fn len(&self) -> u64 {
+        match &*self.inner {
+            Buffer::Memory(bytes) => bytes.len() as u64,
+            Buffer::File { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The body, if it was small enough to be kept in memory.
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match &*self.inner {
+            Buffer::Memory(bytes) => Some(bytes),
+            Buffer::File { .. } => None,
+        }
+    }
+
+    /// The whole body, read back from disk if it was spilled.
+    pub async fn to_bytes(&self) -> io::Result<Bytes> {
+        match &*self.inner {
+            Buffer::Memory(bytes) => Ok(bytes.clone()),
+            Buffer::File { file, .. } => tokio::fs::read(&file.0).await.map(Bytes::from),
+        }
+    }
+
+    /// A new streaming [`Body`] with the buffered content.
+    pub fn to_body(&self) -> Body {
+        match &*self.inner {
+            Buffer::Memory(bytes) => Body::from(bytes.clone()),
+            Buffer::File { file, .. } => {
+                // Keeps the file alive until the replayed body is dropped
+                let keep_alive = self.clone();
+                let path = file.0.clone();
+                let stream = futures_util::stream::once(async move {
+                    tokio::fs::File::open(path).await.map(ReaderStream::new)
+                })
+                .try_flatten()
+                .map(move |chunk| {
+                    let _ = &keep_alive;
+                    chunk
+                });
+
+                Body::wrap_stream(stream)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for BufferedBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &*self.inner {
+            Buffer::Memory(bytes) => f.debug_tuple("Memory").field(&bytes.len()).finish(),
+            Buffer::File { file, len } => f.debug_tuple("File").field(&file.0).field(len).finish(),
+        }
+    }
+}
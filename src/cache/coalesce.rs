@@ -0,0 +1,368 @@
+use super::vary_names;
+use bytes::Bytes;
+use futures_util::future::{self, Either};
+use futures_util::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::HeaderName;
+use hyper::{Body, HeaderMap, Method, Response, StatusCode, Version};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+// The number of resources whose last response is remembered to key their flights
+const MAX_HINTS: usize = 10_000;
+
+/// The upstream requests currently in flight for cache misses, so that identical requests
+/// arriving meanwhile wait for their response instead of going upstream as well.
+#[derive(Default)]
+pub(crate) struct Inflight {
+    flights: Mutex<HashMap<String, watch::Receiver<Option<SharedResponse>>>>,
+    hints: Mutex<HashMap<String, Hint>>,
+}
+
+// What the last response for a resource told about the ones to come
+enum Hint {
+    /// It was storable, and varied on these request headers.
+    Vary(Vec<HeaderName>),
+    /// It was not storable, so waiting for the next one is likely in vain.
+    Uncacheable,
+}
+
+/// What a request for a flight key gets to do.
+pub(crate) enum Flight {
+    /// Send the request upstream and publish the response to the waiting requests.
+    Lead(Leader),
+    /// Wait for the response of the leading request.
+    Wait(watch::Receiver<Option<SharedResponse>>),
+}
+
+impl Inflight {
+    /// The flight for a request to `key`, made of the request headers the last response for
+    /// it varied on, so requests only wait for a response they can use. None if the last
+    /// response was not storable, and the request should not wait for another.
+    pub fn flight_key(
+        &self,
+        method: &Method,
+        key: &str,
+        request_headers: &HeaderMap,
+    ) -> Option<String> {
+        let mut flight = format!("{} {}", method, key);
+
+        match self.hints.lock().unwrap().get(key) {
+            Some(Hint::Uncacheable) => return None,
+            Some(Hint::Vary(names)) => {
+                for name in names {
+                    let values = request_headers.get_all(name).iter().collect::<Vec<_>>();
+                    let _ = write!(flight, "\n{}: {:?}", name, values);
+                }
+            }
+            None => {}
+        }
+
+        Some(flight)
+    }
+
+    /// Remembers the request headers the last response for `key` varied on, or that it was
+    /// not storable.
+    pub fn learn(&self, key: &str, vary: Option<Vec<HeaderName>>) {
+        let mut hints = self.hints.lock().unwrap();
+        if hints.len() >= MAX_HINTS && !hints.contains_key(key) {
+            hints.clear();
+        }
+
+        let hint = match vary {
+            Some(names) => Hint::Vary(names),
+            None => Hint::Uncacheable,
+        };
+        hints.insert(key.to_string(), hint);
+    }
+
+    pub fn join(self: &Arc<Self>, key: String) -> Flight {
+        let mut flights = self.flights.lock().unwrap();
+
+        if let Some(receiver) = flights.get(&key) {
+            debug!("Waiting for in-flight request to {}", key);
+            return Flight::Wait(receiver.clone());
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        flights.insert(key.clone(), receiver);
+
+        Flight::Lead(Leader {
+            inflight: self.clone(),
+            key,
+            sender,
+        })
+    }
+}
+
+/// Removes the flight when dropped. Waiters that did not get a response then send their own
+/// request.
+pub(crate) struct Leader {
+    inflight: Arc<Inflight>,
+    key: String,
+    sender: watch::Sender<Option<SharedResponse>>,
+}
+
+impl Leader {
+    /// Shares the response with the waiting requests, returning the response of the leading
+    /// request. Requests arriving until its body was fully received join it as well, unless the
+    /// body grew larger than `max_size` bytes: it is no longer kept for them then, and they
+    /// send their own request.
+    pub fn publish(
+        self,
+        response: Response<Body>,
+        request_headers: &HeaderMap,
+        max_size: usize,
+    ) -> Response<Body> {
+        let sender = self.sender.clone();
+        let (shared, response) = SharedResponse::new(response, request_headers, max_size, self);
+        let _ = sender.send(Some(shared));
+        response
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.inflight.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Waits for the response of the leading request, if it shares one.
+pub(crate) async fn wait(
+    mut receiver: watch::Receiver<Option<SharedResponse>>,
+) -> Option<SharedResponse> {
+    loop {
+        if let Some(response) = receiver.borrow_and_update().clone() {
+            return Some(response);
+        }
+
+        if receiver.changed().await.is_err() {
+            return receiver.borrow().clone();
+        }
+    }
+}
+
+// The number of chunks buffered for a client that reads slower than the upstream sends
+const CHUNKS_IN_FLIGHT: usize = 8;
+
+// How long a client may keep its buffer full before it is dropped, so that it does not hold
+// up the other clients
+const LAGGING_CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Chunk = Result<Bytes, io::Error>;
+
+// A client reading the shared response
+#[derive(Clone)]
+struct Client {
+    sender: mpsc::Sender<Chunk>,
+    /// Set when the client is dropped before the end of the body, which then fails instead of
+    /// ending.
+    aborted: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct Progress {
+    /// The body received so far, for requests joining later.
+    chunks: Vec<Bytes>,
+    size: usize,
+    /// Whether requests can no longer join, because the body got too large or every client
+    /// went away.
+    closed: bool,
+    done: bool,
+    failed: bool,
+    clients: Vec<Client>,
+}
+
+struct Shared {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    request_headers: HeaderMap,
+    progress: Mutex<Progress>,
+}
+
+/// A response streamed to several clients. The upstream body is read in the background, at
+/// the pace of the slowest client, except that clients falling too far behind are dropped.
+/// It stops being read once every client went away.
+#[derive(Clone)]
+pub(crate) struct SharedResponse {
+    shared: Arc<Shared>,
+}
+
+impl SharedResponse {
+    // Returns the response of the leading request along with the shared one, so that the body
+    // has a client before it starts being read
+    fn new(
+        response: Response<Body>,
+        request_headers: &HeaderMap,
+        max_size: usize,
+        leader: Leader,
+    ) -> (Self, Response<Body>) {
+        let (parts, body) = response.into_parts();
+        let shared = Arc::new(Shared {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            request_headers: request_headers.clone(),
+            progress: Mutex::new(Progress::default()),
+        });
+
+        let shared = Self { shared };
+        let response = shared.to_response().expect("new shared response is closed");
+        tokio::spawn(pump(shared.shared.clone(), body, max_size, leader));
+
+        (shared, response)
+    }
+
+    /// Returns true if the response is valid for a request with these headers, because they
+    /// agree with the leading request on the headers named in `Vary`.
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        vary_names(&self.shared.headers)
+            .iter()
+            .all(|name| request_headers.get(name) == self.shared.request_headers.get(name))
+    }
+
+    /// The response for another client, or None if it can no longer join.
+    pub fn to_response(&self) -> Option<Response<Body>> {
+        let mut progress = self.shared.progress.lock().unwrap();
+        if progress.closed {
+            return None;
+        }
+
+        let mut received = progress.chunks.iter().cloned().map(Ok).collect::<Vec<_>>();
+        let receiver = if progress.done {
+            if progress.failed {
+                received.push(Err(body_failed()));
+            }
+            None
+        } else {
+            let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+            let aborted = Arc::new(AtomicBool::new(false));
+            progress.clients.push(Client {
+                sender,
+                aborted: aborted.clone(),
+            });
+            Some((receiver, aborted))
+        };
+        drop(progress);
+
+        let live = futures_util::stream::unfold(receiver, |receiver| async move {
+            let (mut receiver, aborted) = receiver?;
+            match receiver.recv().await {
+                Some(chunk) => Some((chunk, Some((receiver, aborted)))),
+                None if aborted.load(Ordering::Relaxed) => Some((Err(body_failed()), None)),
+                None => None,
+            }
+        });
+        let body = futures_util::stream::iter(received).chain(live);
+
+        let mut response = Response::new(Body::wrap_stream(body));
+        *response.status_mut() = self.shared.status;
+        *response.version_mut() = self.shared.version;
+        *response.headers_mut() = self.shared.headers.clone();
+        Some(response)
+    }
+}
+
+// Reads the upstream body and hands its chunks to every client. The flight ends, and requests
+// no longer join, once the body is finished or too large to be kept.
+async fn pump(shared: Arc<Shared>, mut body: Body, max_size: usize, leader: Leader) {
+    let mut leader = Some(leader);
+
+    loop {
+        let clients = shared.progress.lock().unwrap().clients.clone();
+        let gone = future::join_all(clients.iter().map(|client| client.sender.closed()));
+
+        let chunk = match future::select(body.data(), Box::pin(gone)).await {
+            Either::Left((chunk, _)) => chunk,
+            Either::Right(_) => {
+                let mut progress = shared.progress.lock().unwrap();
+                progress.clients.retain(|client| !client.sender.is_closed());
+                if progress.clients.is_empty() {
+                    debug!("Every client left, abandoning the shared response");
+                    progress.closed = true;
+                    progress.chunks.clear();
+                    return;
+                }
+                continue;
+            }
+        };
+
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(err)) => {
+                debug!("Shared response body failed: {}", err);
+                for client in finish(&shared, true) {
+                    client.aborted.store(true, Ordering::Relaxed);
+                }
+                return;
+            }
+            None => {
+                finish(&shared, false);
+                return;
+            }
+        };
+
+        let clients = {
+            let mut progress = shared.progress.lock().unwrap();
+            progress.size += chunk.len();
+            if progress.size > max_size && !progress.closed {
+                debug!("Shared response too large, no longer joining requests");
+                progress.closed = true;
+                progress.chunks = Vec::new();
+                leader.take();
+            } else if !progress.closed {
+                progress.chunks.push(chunk.clone());
+            }
+            progress.clients.clone()
+        };
+
+        // Clients are sent the chunk at the same time, each one waiting for room in its buffer
+        // only so long
+        let sends = clients.iter().map(|client| {
+            tokio::time::timeout(
+                LAGGING_CLIENT_TIMEOUT,
+                client.sender.send(Ok(chunk.clone())),
+            )
+        });
+        let lagging = future::join_all(sends)
+            .await
+            .into_iter()
+            .zip(clients)
+            .filter(|(sent, _)| sent.is_err())
+            .map(|(_, client)| client)
+            .collect::<Vec<_>>();
+
+        if !lagging.is_empty() {
+            debug!(
+                "Dropping {} lagging clients of a shared response",
+                lagging.len()
+            );
+            for client in &lagging {
+                client.aborted.store(true, Ordering::Relaxed);
+            }
+            shared.progress.lock().unwrap().clients.retain(|client| {
+                !lagging
+                    .iter()
+                    .any(|lagging| lagging.sender.same_channel(&client.sender))
+            });
+        }
+    }
+}
+
+// Marks the body as finished, returning the clients still reading it
+fn finish(shared: &Shared, failed: bool) -> Vec<Client> {
+    let mut progress = shared.progress.lock().unwrap();
+    progress.done = true;
+    progress.failed = failed;
+    std::mem::take(&mut progress.clients)
+}
+
+fn body_failed() -> io::Error {
+    io::Error::other("shared response body failed")
+}
@@ -0,0 +1,617 @@
+use super::{CacheStorage, CachedBody, CachedResponse};
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const INDEX_FILE: &str = "index.json";
+const JOURNAL_FILE: &str = "journal.jsonl";
+const OBJECTS_DIR: &str = "objects";
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// The journal is compacted into the index once it has this many records, or as many as there
+// are entries if that is more
+const COMPACTION_RECORDS: usize = 1024;
+
+// The metadata of a stored response, its body lives in the object file named by `body`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    vary: Vec<(String, Option<Vec<u8>>)>,
+    /// Milliseconds since the Unix epoch.
+    response_time: u64,
+    /// Seconds.
+    initial_age: u64,
+    body: String,
+    last_used: u64,
+}
+
+// A change to the index, appended to the journal as a line of JSON
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    /// Adds an entry, replacing the variant of its key with the same `vary`.
+    Insert(Entry),
+    /// Removes the variant of `key` with this `vary`, or all of them.
+    Remove {
+        key: String,
+        vary: Option<Vec<(String, Option<Vec<u8>>)>>,
+    },
+    /// Records a lookup of the variant of `key` with this `vary`, for eviction.
+    Use {
+        key: String,
+        vary: Vec<(String, Option<Vec<u8>>)>,
+        last_used: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Journal {
+    file: tokio::fs::File,
+    records: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, Vec<Entry>>,
+    /// Size and number of references of every object file.
+    objects: HashMap<String, (u64, usize)>,
+    size: u64,
+    clock: u64,
+}
+
+/// On-disk storage of cached responses, surviving restarts of the proxy.
+///
+/// Bodies are stored in content-addressed files under `objects/`, so identical bodies are only
+/// stored once, and the response metadata in an `index.json` next to them. Changes to the
+/// metadata are appended to a `journal.jsonl`, which is compacted into the index from time to
+/// time and when the storage is opened. Lookups are journaled as well, and once the total size
+/// of the bodies goes over its limit, the least recently used responses are evicted. Bodies are
+/// streamed from their files when responses are served, so they are never held in memory.
+pub struct DiskStorage {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<State>,
+    journal: Mutex<Journal>,
+}
+
+impl DiskStorage {
+    /// Opens the storage in `dir`, creating it if needed, holding bodies up to a total of
+    /// `max_size` bytes. Responses stored by a previous process are picked up again.
+    pub fn open<P: AsRef<Path>>(dir: P, max_size: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(dir.join(OBJECTS_DIR))?;
+
+        let entries: Vec<Entry> = match std::fs::read(dir.join(INDEX_FILE)) {
+            Ok(index) => serde_json::from_slice(&index).unwrap_or_else(|err| {
+                warn!("Ignoring unreadable cache index in {:?}: {}", dir, err);
+                Vec::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        let mut indexed = HashMap::<String, Vec<Entry>>::new();
+        for entry in entries {
+            apply(&mut indexed, Record::Insert(entry));
+        }
+        for record in read_journal(&dir.join(JOURNAL_FILE))? {
+            apply(&mut indexed, record);
+        }
+
+        let mut state = State::default();
+        for entry in indexed.into_values().flatten() {
+            let size = match state.objects.get(&entry.body) {
+                Some((size, _)) => *size,
+                None => match std::fs::metadata(dir.join(OBJECTS_DIR).join(&entry.body)) {
+                    Ok(metadata) => metadata.len(),
+                    Err(_) => continue,
+                },
+            };
+
+            state.clock = state.clock.max(entry.last_used);
+            state.add_reference(&entry.body, size);
+            state
+                .entries
+                .entry(entry.key.clone())
+                .or_default()
+                .push(entry);
+        }
+
+        // Objects left over from evictions or writes that were interrupted
+        for file in std::fs::read_dir(dir.join(OBJECTS_DIR))? {
+            let file = file?;
+            let name = file.file_name();
+            if !state.objects.contains_key(&*name.to_string_lossy()) {
+                std::fs::remove_file(file.path())?;
+            }
+        }
+
+        let (_, unused) = state.evict(max_size);
+        for object in unused {
+            std::fs::remove_file(dir.join(OBJECTS_DIR).join(object))?;
+        }
+
+        // Starts from a compacted index and an empty journal
+        if let Some(index) = serialize_index(&state) {
+            let temp = dir.join(format!("{}.tmp", INDEX_FILE));
+            std::fs::write(&temp, index)?;
+            std::fs::rename(&temp, dir.join(INDEX_FILE))?;
+        }
+        let journal = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(JOURNAL_FILE))?;
+        journal.set_len(0)?;
+
+        debug!(
+            "Opened disk cache in {:?} with {} bytes stored",
+            dir, state.size
+        );
+
+        Ok(Self {
+            dir,
+            max_size,
+            state: Mutex::new(state),
+            journal: Mutex::new(Journal {
+                file: tokio::fs::File::from_std(journal),
+                records: 0,
+            }),
+        })
+    }
+
+    fn object_path(&self, object: &str) -> PathBuf {
+        self.dir.join(OBJECTS_DIR).join(object)
+    }
+
+    async fn remove_objects(&self, objects: Vec<String>) {
+        for object in objects {
+            if let Err(err) = tokio::fs::remove_file(self.object_path(&object)).await {
+                warn!("Failed to remove cached object {}: {}", object, err);
+            }
+        }
+    }
+
+    // Appends the records to the journal. The journal is locked before the state is released,
+    // so records are appended in the order the state was changed, while the state is no longer
+    // held during the write. The journal is compacted into the index once it grew large enough.
+    async fn journal(&self, state: tokio::sync::MutexGuard<'_, State>, records: Vec<Record>) {
+        let mut journal = self.journal.lock().await;
+        let entries = state.entries.len();
+        let index = if journal.records + records.len() >= COMPACTION_RECORDS.max(entries) {
+            serialize_index(&state)
+        } else {
+            None
+        };
+        drop(state);
+
+        if let Some(index) = index {
+            self.compact(&mut journal, index).await;
+            return;
+        }
+
+        let mut lines = Vec::new();
+        for record in &records {
+            match serde_json::to_writer(&mut lines, record) {
+                Ok(()) => lines.push(b'\n'),
+                Err(err) => warn!("Failed to serialize a cache journal record: {}", err),
+            }
+        }
+
+        let result = match journal.file.write_all(&lines).await {
+            Ok(()) => journal.file.flush().await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => journal.records += records.len(),
+            Err(err) => warn!("Failed to append to the cache journal: {}", err),
+        }
+    }
+
+    // Replaces the index, writing it to a temporary file first so a crash never leaves a partial
+    // index, then empties the journal. Replaying a journal left over by a crash in between is
+    // harmless, as its records are already part of the index.
+    async fn compact(&self, journal: &mut Journal, index: Vec<u8>) {
+        debug!("Compacting the disk cache journal");
+
+        let temp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let result = match tokio::fs::write(&temp, index).await {
+            Ok(()) => tokio::fs::rename(&temp, self.dir.join(INDEX_FILE)).await,
+            Err(err) => Err(err),
+        };
+        let result = match result {
+            Ok(()) => journal.file.set_len(0).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(()) => journal.records = 0,
+            Err(err) => warn!("Failed to write the cache index: {}", err),
+        }
+    }
+
+    // Writes a body to a temporary file, which becomes the object once the state is locked.
+    // Concurrent writes of the same body each have their own file.
+    async fn write_temp(&self, object: &str, body: &Bytes) -> io::Result<PathBuf> {
+        let temp = self.object_path(&format!(
+            "{}.{}.tmp",
+            object,
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&temp, body).await?;
+        Ok(temp)
+    }
+
+    // The object and its content, unless the body already is one of the objects
+    async fn object_for(&self, body: &CachedBody) -> io::Result<(String, Option<Bytes>)> {
+        let bytes = match body {
+            CachedBody::Memory(bytes) => bytes.clone(),
+            CachedBody::File { path, .. } => {
+                let objects = self.dir.join(OBJECTS_DIR);
+                match path.strip_prefix(&objects).ok().and_then(Path::to_str) {
+                    Some(object) => return Ok((object.to_string(), None)),
+                    None => Bytes::from(tokio::fs::read(path).await?),
+                }
+            }
+        };
+
+        Ok((object_name(&bytes), Some(bytes)))
+    }
+}
+
+impl std::fmt::Debug for DiskStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiskStorage")
+            .field("dir", &self.dir)
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl CacheStorage for DiskStorage {
+    async fn get(&self, key: &str, request_headers: &HeaderMap) -> Option<CachedResponse> {
+        let mut state = self.state.lock().await;
+        state.clock += 1;
+        let clock = state.clock;
+
+        let entry = state
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|entry| entry.matches(request_headers))?;
+        entry.last_used = clock;
+        let entry = entry.clone();
+        let (len, _) = *state.objects.get(&entry.body)?;
+
+        // Lookups are journaled too, so that the least recently used entries are still known
+        // after a restart
+        let record = Record::Use {
+            key: key.to_string(),
+            vary: entry.vary.clone(),
+            last_used: clock,
+        };
+        self.journal(state, vec![record]).await;
+
+        entry.to_response(CachedBody::File {
+            path: self.object_path(&entry.body),
+            len,
+        })
+    }
+
+    async fn insert(&self, key: &str, response: CachedResponse) {
+        let size = response.body.len();
+        if size > self.max_size {
+            return;
+        }
+
+        let (object, bytes) = match self.object_for(&response.body).await {
+            Ok(object) => object,
+            Err(err) => {
+                warn!("Failed to read the body to cache for {}: {}", key, err);
+                return;
+            }
+        };
+
+        // The body is written without holding the state, so that lookups go on meanwhile
+        let known = self.state.lock().await.objects.contains_key(&object);
+        let temp = match bytes {
+            Some(bytes) if !known => match self.write_temp(&object, &bytes).await {
+                Ok(temp) => Some(temp),
+                Err(err) => {
+                    warn!("Failed to write cached object {}: {}", object, err);
+                    return;
+                }
+            },
+            _ => None,
+        };
+
+        // Objects are only renamed and removed while the state is held, so an object being
+        // added cannot be removed by a concurrent eviction
+        let mut state = self.state.lock().await;
+        let stored = state.objects.contains_key(&object);
+        let result = match &temp {
+            Some(temp) if stored => tokio::fs::remove_file(temp).await,
+            Some(temp) => tokio::fs::rename(temp, self.object_path(&object)).await,
+            None if stored => Ok(()),
+            None => {
+                debug!("Not caching {}, its object was evicted meanwhile", key);
+                return;
+            }
+        };
+        if let Err(err) = result {
+            warn!("Failed to write cached object {}: {}", object, err);
+            return;
+        }
+
+        state.clock += 1;
+        let entry = Entry::new(key, &response, object, state.clock);
+        state.add_reference(&entry.body, size);
+
+        let mut records = vec![Record::Insert(entry.clone())];
+        let variants = state.entries.entry(key.to_string()).or_default();
+        let replaced = variants
+            .iter()
+            .position(|variant| variant.vary == entry.vary)
+            .map(|i| variants.swap_remove(i));
+        variants.push(entry);
+
+        let (evicted, mut unused) = state.evict(self.max_size);
+        records.extend(evicted);
+        if let Some(replaced) = replaced {
+            unused.extend(state.remove_reference(&replaced.body));
+        }
+
+        self.remove_objects(unused).await;
+        self.journal(state, records).await;
+    }
+
+    async fn remove(&self, key: &str) {
+        let mut state = self.state.lock().await;
+
+        let variants = match state.entries.remove(key) {
+            Some(variants) => variants,
+            None => return,
+        };
+        let unused = variants
+            .iter()
+            .filter_map(|entry| state.remove_reference(&entry.body))
+            .collect();
+        self.remove_objects(unused).await;
+
+        let record = Record::Remove {
+            key: key.to_string(),
+            vary: None,
+        };
+        self.journal(state, vec![record]).await;
+    }
+}
+
+impl State {
+    fn add_reference(&mut self, object: &str, size: u64) {
+        if !self.objects.contains_key(object) {
+            self.size += size;
+        }
+        self.objects
+            .entry(object.to_string())
+            .or_insert((size, 0))
+            .1 += 1;
+    }
+
+    // Returns the object if it is no longer referenced
+    fn remove_reference(&mut self, object: &str) -> Option<String> {
+        let (size, references) = self.objects.get_mut(object)?;
+        *references -= 1;
+        if *references > 0 {
+            return None;
+        }
+
+        self.size -= *size;
+        self.objects.remove(object);
+        Some(object.to_string())
+    }
+
+    // Evicts the least recently used entries until the total size fits, returning their
+    // journal records and the objects that are no longer referenced
+    fn evict(&mut self, max_size: u64) -> (Vec<Record>, Vec<String>) {
+        let mut evicted = Vec::new();
+        let mut unused = Vec::new();
+
+        while self.size > max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .flat_map(|(key, variants)| {
+                    variants
+                        .iter()
+                        .enumerate()
+                        .map(move |(i, entry)| (entry.last_used, key, i))
+                })
+                .min()
+                .map(|(_, key, i)| (key.clone(), i));
+
+            let (key, i) = match oldest {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            debug!("Evicting {} from the disk cache", key);
+
+            let variants = self.entries.get_mut(&key).unwrap();
+            let entry = variants.swap_remove(i);
+            if variants.is_empty() {
+                self.entries.remove(&key);
+            }
+            unused.extend(self.remove_reference(&entry.body));
+            evicted.push(Record::Remove {
+                key,
+                vary: Some(entry.vary),
+            });
+        }
+
+        (evicted, unused)
+    }
+}
+
+impl Entry {
+    fn new(key: &str, response: &CachedResponse, body: String, last_used: u64) -> Self {
+        Self {
+            key: key.to_string(),
+            status: response.status.as_u16(),
+            headers: response
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            vary: response
+                .vary
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        value.as_ref().map(|value| value.as_bytes().to_vec()),
+                    )
+                })
+                .collect(),
+            response_time: response
+                .response_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            initial_age: response.initial_age.as_secs(),
+            body,
+            last_used,
+        }
+    }
+
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| {
+            request_headers
+                .get(name.as_str())
+                .map(HeaderValue::as_bytes)
+                == value.as_deref()
+        })
+    }
+
+    fn to_response(&self, body: CachedBody) -> Option<CachedResponse> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_bytes(value).ok()?,
+            );
+        }
+
+        let vary = self
+            .vary
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+                let value = match value {
+                    Some(value) => Some(HeaderValue::from_bytes(value).ok()?),
+                    None => None,
+                };
+                Some((name, value))
+            })
+            .collect::<Option<_>>()?;
+
+        Some(CachedResponse {
+            status: StatusCode::from_u16(self.status).ok()?,
+            headers,
+            body,
+            response_time: UNIX_EPOCH + Duration::from_millis(self.response_time),
+            initial_age: Duration::from_secs(self.initial_age),
+            vary,
+        })
+    }
+}
+
+fn serialize_index(state: &State) -> Option<Vec<u8>> {
+    let entries = state.entries.values().flatten().collect::<Vec<_>>();
+    serde_json::to_vec(&entries)
+        .map_err(|err| warn!("Failed to serialize the cache index: {}", err))
+        .ok()
+}
+
+// Reads the records of the journal, up to a line left incomplete by a crash
+fn read_journal(path: &Path) -> io::Result<Vec<Record>> {
+    let journal = match std::fs::read(path) {
+        Ok(journal) => journal,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut records = Vec::new();
+    for line in journal
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        match serde_json::from_slice(line) {
+            Ok(record) => records.push(record),
+            Err(err) => {
+                warn!("Ignoring the rest of the cache journal {:?}: {}", path, err);
+                break;
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+fn apply(entries: &mut HashMap<String, Vec<Entry>>, record: Record) {
+    match record {
+        Record::Insert(entry) => {
+            let variants = entries.entry(entry.key.clone()).or_default();
+            variants.retain(|variant| variant.vary != entry.vary);
+            variants.push(entry);
+        }
+        Record::Remove { key, vary: None } => {
+            entries.remove(&key);
+        }
+        Record::Remove {
+            key,
+            vary: Some(vary),
+        } => {
+            if let Some(variants) = entries.get_mut(&key) {
+                variants.retain(|variant| variant.vary != vary);
+                if variants.is_empty() {
+                    entries.remove(&key);
+                }
+            }
+        }
+        Record::Use {
+            key,
+            vary,
+            last_used,
+        } => {
+            let variant = entries
+                .get_mut(&key)
+                .and_then(|variants| variants.iter_mut().find(|variant| variant.vary == vary));
+            if let Some(variant) = variant {
+                variant.last_used = last_used;
+            }
+        }
+    }
+}
+
+// Names an object file after the SHA-256 digest of its content
+fn object_name(body: &Bytes) -> String {
+    Sha256::digest(body)
+        .iter()
+        .fold(String::with_capacity(64), |mut name, byte| {
+            let _ = write!(name, "{:02x}", byte);
+            name
+        })
+}
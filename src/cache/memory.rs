@@ -0,0 +1,132 @@
+use super::{CacheStorage, CachedResponse};
+use async_trait::async_trait;
+use hyper::HeaderMap;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+struct Slot {
+    response: CachedResponse,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Vec<Slot>>,
+    size: usize,
+    clock: u64,
+}
+
+/// In-memory storage of cached responses, evicting the least recently used ones once the total
+/// size goes over its limit.
+pub struct MemoryStorage {
+    max_size: usize,
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    /// Storage holding responses up to a total of `max_size` bytes.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryStorage")
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl CacheStorage for MemoryStorage {
+    async fn get(&self, key: &str, request_headers: &HeaderMap) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let slot = state
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|slot| slot.response.matches(request_headers))?;
+        slot.last_used = clock;
+
+        Some(slot.response.clone())
+    }
+
+    async fn insert(&self, key: &str, response: CachedResponse) {
+        let size = response.size();
+        if size > self.max_size {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let variants = state.entries.entry(key.to_string()).or_default();
+        let replaced = variants
+            .iter()
+            .position(|slot| slot.response.vary == response.vary)
+            .map(|i| variants.swap_remove(i).response.size())
+            .unwrap_or(0);
+        variants.push(Slot {
+            response,
+            last_used: clock,
+        });
+
+        state.size = state.size - replaced + size;
+
+        while state.size > self.max_size {
+            if !state.evict_least_recently_used() {
+                break;
+            }
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(variants) = state.entries.remove(key) {
+            state.size -= variants
+                .iter()
+                .map(|slot| slot.response.size())
+                .sum::<usize>();
+        }
+    }
+}
+
+impl State {
+    fn evict_least_recently_used(&mut self) -> bool {
+        let oldest = self
+            .entries
+            .iter()
+            .flat_map(|(key, variants)| {
+                variants
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, slot)| (slot.last_used, key, i))
+            })
+            .min()
+            .map(|(_, key, i)| (key.clone(), i));
+
+        let (key, i) = match oldest {
+            Some(oldest) => oldest,
+            None => return false,
+        };
+
+        debug!("Evicting {} from the cache", key);
+
+        let variants = self.entries.get_mut(&key).unwrap();
+        self.size -= variants.swap_remove(i).response.size();
+        if variants.is_empty() {
+            self.entries.remove(&key);
+        }
+
+        true
+    }
+}
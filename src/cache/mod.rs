@@ -0,0 +1,706 @@
+//! A shared HTTP response cache, following RFC 9111.
+//!
+//! A [`Cache`] attached to a [`ReverseProxy`](crate::ReverseProxy) stores cacheable responses to
+//! `GET` requests and answers later `GET` and `HEAD` requests for the same upstream URI from the
+//! cache while they are fresh. Stale responses are revalidated with conditional requests using
+//! their `ETag` and `Last-Modified` validators, and `stale-while-revalidate` and
+//! `stale-if-error` are honored. Concurrent misses for the same variant of a resource are
+//! coalesced into a single upstream request whose response is streamed to all of them.
+//!
+//! Responses marked `private` or `no-store`, responses setting cookies and responses to
+//! requests carrying `Authorization` (unless explicitly allowed by the response) are not stored.
+//!
+//! Responses are kept in a [`CacheStorage`], by default a [`MemoryStorage`]. A [`DiskStorage`]
+//! keeps them on disk instead, so they survive restarts of the proxy.
+
+mod coalesce;
+mod disk;
+mod memory;
+mod policy;
+
+pub use disk::DiskStorage;
+pub use memory::MemoryStorage;
+
+#[cfg(feature = "auth")]
+use crate::auth::Identity;
+use crate::{body, ProxyError, Upstream};
+use async_trait::async_trait;
+use bytes::Bytes;
+use coalesce::{Flight, Inflight};
+use futures_util::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::client::connect::Connect;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CONTENT_LENGTH, ETAG, IF_MATCH,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED, SET_COOKIE, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use policy::CacheControl;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_util::io::ReaderStream;
+
+const DEFAULT_MAX_SIZE: usize = 64 * 1024 * 1024;
+const DEFAULT_MAX_OBJECT_SIZE: usize = 1024 * 1024;
+
+// Status codes that are cacheable by default, see RFC 9110 section 15.1
+const HEURISTICALLY_CACHEABLE: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Storage of cached responses.
+///
+/// A key can hold several variants of a response, told apart by the request headers named in
+/// their `Vary` header.
+#[async_trait]
+pub trait CacheStorage: Send + Sync {
+    /// The stored variant of `key` matching the request headers.
+    async fn get(&self, key: &str, request_headers: &HeaderMap) -> Option<CachedResponse>;
+
+    /// Stores a response, replacing the variant with the same varying request headers.
+    async fn insert(&self, key: &str, response: CachedResponse);
+
+    /// Drops all variants stored for `key`.
+    async fn remove(&self, key: &str);
+}
+
+/// The body of a cached response.
+#[derive(Debug, Clone)]
+pub enum CachedBody {
+    Memory(Bytes),
+    /// A file holding the body, which is streamed from it when the response is served. If the
+    /// file is gone by then, the body fails.
+    File {
+        path: PathBuf,
+        len: u64,
+    },
+}
+
+impl CachedBody {
+    pub fn len(&self) -> u64 {
+        match self {
+            CachedBody::Memory(bytes) => bytes.len() as u64,
+            CachedBody::File { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn to_body(&self) -> Body {
+        match self {
+            CachedBody::Memory(bytes) => Body::from(bytes.clone()),
+            CachedBody::File { path, .. } => {
+                let path = path.clone();
+                let stream = futures_util::stream::once(async move {
+                    tokio::fs::File::open(path).await.map(ReaderStream::new)
+                })
+                .try_flatten();
+
+                Body::wrap_stream(stream)
+            }
+        }
+    }
+}
+
+impl From<Bytes> for CachedBody {
+    fn from(bytes: Bytes) -> Self {
+        CachedBody::Memory(bytes)
+    }
+}
+
+/// A response as kept in the cache.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: CachedBody,
+    /// When the response was received.
+    pub response_time: SystemTime,
+    /// The age of the response when it was received.
+    pub initial_age: Duration,
+    /// The request headers named in `Vary`, with the values they had when the response was
+    /// stored.
+    pub vary: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl CachedResponse {
+    fn new(
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        request_headers: &HeaderMap,
+        response_time: SystemTime,
+    ) -> Self {
+        let vary = vary_names(&headers)
+            .into_iter()
+            .map(|name| {
+                let value = request_headers.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+
+        Self {
+            status,
+            initial_age: policy::initial_age(&headers, response_time),
+            headers,
+            body: body.into(),
+            response_time,
+            vary,
+        }
+    }
+
+    /// Returns true if the response was stored for a request with the same varying headers.
+    pub fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+
+    /// An estimate of the memory taken by the response.
+    pub fn size(&self) -> usize {
+        self.body.len() as usize
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>()
+    }
+
+    fn age(&self, now: SystemTime) -> Duration {
+        self.initial_age + now.duration_since(self.response_time).unwrap_or_default()
+    }
+
+    fn cache_control(&self) -> CacheControl {
+        CacheControl::parse(&self.headers)
+    }
+
+    fn freshness_lifetime(&self) -> Duration {
+        policy::freshness_lifetime(&self.headers, &self.cache_control(), true)
+    }
+
+    fn to_response(&self, age: Duration, request: &RequestInfo) -> Response<Body> {
+        let mut headers = self.headers.clone();
+        headers.insert(AGE, HeaderValue::from(age.as_secs()));
+
+        if request.is_not_modified(&self.headers) {
+            headers.remove(CONTENT_LENGTH);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            *response.headers_mut() = headers;
+            return response;
+        }
+
+        let body = if request.method == Method::HEAD {
+            Body::empty()
+        } else {
+            self.body.to_body()
+        };
+
+        let mut response = Response::new(body);
+        *response.status_mut() = self.status;
+        *response.headers_mut() = headers;
+        response
+    }
+
+    // Updates the stored headers with the ones of a 304 response, see RFC 9111 section 4.3.4
+    fn refresh(&mut self, not_modified: &HeaderMap, response_time: SystemTime) {
+        for name in not_modified.keys() {
+            if name == CONTENT_LENGTH {
+                continue;
+            }
+
+            self.headers.remove(name);
+            for value in not_modified.get_all(name) {
+                self.headers.append(name.clone(), value.clone());
+            }
+        }
+
+        self.response_time = response_time;
+        self.initial_age = policy::initial_age(&self.headers, response_time);
+    }
+}
+
+// The parts of a request needed after it was forwarded
+#[derive(Clone)]
+struct RequestInfo {
+    method: Method,
+    uri: hyper::Uri,
+    version: hyper::Version,
+    headers: HeaderMap,
+    cache_control: CacheControl,
+    authenticated: bool,
+}
+
+impl RequestInfo {
+    fn new(request: &Request<Body>) -> Self {
+        Self {
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            version: request.version(),
+            headers: request.headers().clone(),
+            cache_control: CacheControl::parse(request.headers()),
+            authenticated: is_authenticated(request),
+        }
+    }
+
+    fn to_request(&self) -> Request<Body> {
+        let mut request = Request::new(Body::empty());
+        *request.method_mut() = Method::GET;
+        *request.uri_mut() = self.uri.clone();
+        *request.version_mut() = self.version;
+        *request.headers_mut() = self.headers.clone();
+        request
+    }
+
+    // Evaluates the client's own conditional headers against a cached response
+    fn is_not_modified(&self, cached: &HeaderMap) -> bool {
+        if let Some(if_none_match) = self.headers.get(IF_NONE_MATCH) {
+            let etag = match cached.get(ETAG).and_then(|etag| etag.to_str().ok()) {
+                Some(etag) => etag.trim_start_matches("W/"),
+                None => return false,
+            };
+
+            return if_none_match.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+            });
+        }
+
+        match (
+            policy::header_time(&self.headers, IF_MODIFIED_SINCE),
+            policy::header_time(cached, LAST_MODIFIED),
+        ) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+}
+
+/// Configuration and storage of the response cache. Clones share the same storage.
+#[derive(Clone)]
+pub struct Cache {
+    store: Arc<dyn CacheStorage>,
+    max_object_size: usize,
+    inflight: Arc<Inflight>,
+    revalidations: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            store: Arc::new(MemoryStorage::new(DEFAULT_MAX_SIZE)),
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            inflight: Arc::new(Inflight::default()),
+            revalidations: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where responses are stored. Defaults to a [`MemoryStorage`] of 64 MiB.
+    pub fn storage<S: CacheStorage + 'static>(mut self, storage: S) -> Self {
+        self.store = Arc::new(storage);
+        self
+    }
+
+    /// Responses with larger bodies are not stored. Defaults to 1 MiB.
+    pub fn max_object_size(mut self, bytes: usize) -> Self {
+        self.max_object_size = bytes;
+        self
+    }
+
+    /// Returns true if the request can be answered by the cache.
+    pub(crate) fn handles(&self, request: &Request<Body>) -> bool {
+        (request.method() == Method::GET || request.method() == Method::HEAD)
+            && !request.headers().contains_key(hyper::header::UPGRADE)
+            && !request.headers().contains_key(IF_MATCH)
+            && !request.headers().contains_key(IF_UNMODIFIED_SINCE)
+    }
+
+    /// Drops the stored responses for `key`, after a successful unsafe request to it.
+    pub(crate) async fn invalidate(&self, key: &str) {
+        debug!("Invalidating cached {}", key);
+        self.store.remove(key).await;
+    }
+
+    pub(crate) async fn call<T: Connect + Clone + Send + Sync + 'static>(
+        &self,
+        upstream: &Upstream<'_, T>,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        let key = crate::forward_uri(forward_uri, &request);
+        let info = RequestInfo::new(&request);
+
+        if info.cache_control.no_store {
+            return upstream.call(client_ip, forward_uri, request).await;
+        }
+
+        let cached = match self.store.get(&key, request.headers()).await {
+            Some(cached) => cached,
+            None if info.cache_control.only_if_cached => return Ok(gateway_timeout()),
+            None => {
+                debug!("Cache miss for {}", key);
+                return self
+                    .fetch(upstream, client_ip, forward_uri, key, &info, request)
+                    .await;
+            }
+        };
+
+        let now = SystemTime::now();
+        let age = cached.age(now);
+        let lifetime = cached.freshness_lifetime();
+        let cache_control = cached.cache_control();
+        let request_cc = &info.cache_control;
+
+        let staleness = age.saturating_sub(lifetime);
+        let may_serve_stale = |window: Option<Duration>| {
+            age >= lifetime
+                && !cache_control.no_cache
+                && !cache_control.requires_revalidation()
+                && !request_cc.no_cache
+                && window.is_some_and(|window| staleness <= window)
+        };
+
+        let fresh = age < lifetime
+            && !cache_control.no_cache
+            && !request_cc.no_cache
+            && request_cc.max_age.is_none_or(|max_age| age <= max_age);
+
+        if fresh || may_serve_stale(request_cc.max_stale) {
+            debug!("Cache hit for {}", key);
+            return Ok(cached.to_response(age, &info));
+        }
+
+        if may_serve_stale(cache_control.stale_while_revalidate) {
+            debug!("Serving stale {} while revalidating", key);
+
+            // A single background revalidation runs per key, later stale hits rely on it
+            if let Some(revalidation) = Revalidation::start(&self.revalidations, &key) {
+                let cache = self.clone();
+                let client = upstream.client.clone();
+                #[cfg(feature = "buffer")]
+                let retries = upstream.retries;
+                let forward_uri = forward_uri.to_string();
+                let background_info = info.clone();
+                let background_cached = cached.clone();
+                tokio::spawn(async move {
+                    let request = background_info.to_request();
+                    let upstream = Upstream {
+                        client: &client,
+                        #[cfg(feature = "buffer")]
+                        retries,
+                    };
+                    let _ = cache
+                        .revalidate(
+                            &upstream,
+                            client_ip,
+                            &forward_uri,
+                            key,
+                            &background_info,
+                            background_cached,
+                            request,
+                        )
+                        .await;
+                    drop(revalidation);
+                });
+            }
+
+            return Ok(cached.to_response(age, &info));
+        }
+
+        if request_cc.only_if_cached {
+            debug!("Stale {} cannot be served without revalidation", key);
+            return Ok(gateway_timeout());
+        }
+
+        if info.method == Method::HEAD {
+            return upstream.call(client_ip, forward_uri, request).await;
+        }
+
+        let stale_if_error =
+            may_serve_stale(request_cc.stale_if_error.or(cache_control.stale_if_error));
+
+        match self
+            .revalidate(
+                upstream,
+                client_ip,
+                forward_uri,
+                key,
+                &info,
+                cached.clone(),
+                request,
+            )
+            .await
+        {
+            Ok(response) if response.status().is_server_error() && stale_if_error => {
+                debug!("Serving stale response after upstream error");
+                Ok(cached.to_response(age, &info))
+            }
+            Err(_) if stale_if_error => {
+                debug!("Serving stale response after upstream failure");
+                Ok(cached.to_response(age, &info))
+            }
+            result => result,
+        }
+    }
+
+    // Forwards a cache miss. Identical requests arriving while it is in flight wait for its
+    // response and get it streamed as well, if it is storable and valid for them.
+    async fn fetch<T: Connect + Clone + Send + Sync + 'static>(
+        &self,
+        upstream: &Upstream<'_, T>,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        key: String,
+        info: &RequestInfo,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        let flight = self
+            .inflight
+            .flight_key(&info.method, &key, &info.headers)
+            .map(|flight| self.inflight.join(flight));
+
+        let leader = match flight {
+            Some(Flight::Lead(leader)) => leader,
+            flight => {
+                // Requests to resources that were not storable last time do not wait
+                if let Some(Flight::Wait(receiver)) = flight {
+                    if let Some(shared) = coalesce::wait(receiver).await {
+                        if shared.matches(&info.headers) {
+                            if let Some(response) = shared.to_response() {
+                                return Ok(response);
+                            }
+                        }
+                    }
+                }
+
+                debug!("Forwarding {} without waiting", key);
+                let response = upstream.call(client_ip, forward_uri, request).await?;
+                self.learn(&key, info, &response);
+                return Ok(self.store_response(key, info, response).await);
+            }
+        };
+
+        let response = upstream.call(client_ip, forward_uri, request).await?;
+        self.learn(&key, info, &response);
+        if !self.may_store(info, &response) {
+            return Ok(response);
+        }
+
+        let response = self.store_response(key, info, response).await;
+        Ok(leader.publish(response, &info.headers, self.max_object_size))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn revalidate<T: Connect + Clone + Send + Sync + 'static>(
+        &self,
+        upstream: &Upstream<'_, T>,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        key: String,
+        info: &RequestInfo,
+        mut cached: CachedResponse,
+        mut request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        debug!("Revalidating cached {}", key);
+
+        let headers = request.headers_mut();
+        headers.remove(IF_NONE_MATCH);
+        headers.remove(IF_MODIFIED_SINCE);
+        if let Some(etag) = cached.headers.get(ETAG) {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = cached.headers.get(LAST_MODIFIED) {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+
+        let response = upstream.call(client_ip, forward_uri, request).await?;
+        let response_time = SystemTime::now();
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            debug!("Cached {} is still valid", key);
+
+            cached.refresh(response.headers(), response_time);
+            self.store.insert(&key, cached.clone()).await;
+
+            return Ok(cached.to_response(cached.age(response_time), info));
+        }
+
+        Ok(self.store_response(key, info, response).await)
+    }
+
+    // Keys later flights for `key` by the request headers the response varies on
+    fn learn(&self, key: &str, request: &RequestInfo, response: &Response<Body>) {
+        let vary = if self.may_store(request, response) {
+            Some(vary_names(response.headers()))
+        } else {
+            None
+        };
+        self.inflight.learn(key, vary);
+    }
+
+    fn may_store(&self, request: &RequestInfo, response: &Response<Body>) -> bool {
+        is_storable(request, response)
+            && crate::limits::content_length(response.headers())
+                .is_none_or(|length| length <= self.max_object_size as u64)
+    }
+
+    // Hands the response on, keeping a copy in the store once it was fully streamed
+    async fn store_response(
+        &self,
+        key: String,
+        request: &RequestInfo,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        if !self.may_store(request, &response) {
+            return response;
+        }
+
+        let response_time = SystemTime::now();
+        let (parts, body) = response.into_parts();
+
+        if body.is_end_stream() {
+            debug!("Storing {} in the cache", key);
+
+            let cached = CachedResponse::new(
+                parts.status,
+                parts.headers.clone(),
+                Bytes::new(),
+                &request.headers,
+                response_time,
+            );
+            self.store.insert(&key, cached).await;
+
+            return Response::from_parts(parts, body);
+        }
+
+        let status = parts.status;
+        let headers = parts.headers.clone();
+        let request_headers = request.headers.clone();
+        let store = self.store.clone();
+
+        let body = body::tee(body, self.max_object_size, move |body| async move {
+            debug!("Storing {} in the cache", key);
+
+            let cached =
+                CachedResponse::new(status, headers, body, &request_headers, response_time);
+            store.insert(&key, cached).await;
+        });
+
+        Response::from_parts(parts, body)
+    }
+}
+
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cache")
+            .field("max_object_size", &self.max_object_size)
+            .finish()
+    }
+}
+
+// Whether the request carries credentials, or carried those stripped by `Auth`
+fn is_authenticated(request: &Request<Body>) -> bool {
+    #[cfg(feature = "auth")]
+    if request.extensions().get::<Identity>().is_some() {
+        return true;
+    }
+
+    request.headers().contains_key(AUTHORIZATION)
+}
+
+fn is_storable(request: &RequestInfo, response: &Response<Body>) -> bool {
+    if request.method != Method::GET || request.cache_control.no_store {
+        return false;
+    }
+
+    let headers = response.headers();
+    let cc = CacheControl::parse(headers);
+
+    if cc.no_store || cc.private || headers.contains_key(SET_COOKIE) {
+        return false;
+    }
+
+    if headers.get_all(VARY).iter().any(|value| {
+        value
+            .to_str()
+            .is_ok_and(|value| value.split(',').any(|name| name.trim() == "*"))
+    }) {
+        return false;
+    }
+
+    if request.authenticated && !(cc.public || cc.must_revalidate || cc.s_maxage.is_some()) {
+        return false;
+    }
+
+    let explicitly_fresh = cc.max_age.is_some()
+        || cc.s_maxage.is_some()
+        || headers.contains_key(hyper::header::EXPIRES)
+        || cc.public;
+
+    if explicitly_fresh {
+        return response.status() != StatusCode::PARTIAL_CONTENT
+            && !response.status().is_informational()
+            && response.status() != StatusCode::NOT_MODIFIED;
+    }
+
+    HEURISTICALLY_CACHEABLE.contains(&response.status().as_u16())
+        && (headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED))
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| {
+            let name = name.trim();
+            if name == "*" {
+                None
+            } else {
+                HeaderName::from_bytes(name.as_bytes()).ok()
+            }
+        })
+        .collect()
+}
+
+// A background revalidation in progress, the key is released when it is dropped
+struct Revalidation {
+    revalidations: Arc<Mutex<HashSet<String>>>,
+    key: String,
+}
+
+impl Revalidation {
+    fn start(revalidations: &Arc<Mutex<HashSet<String>>>, key: &str) -> Option<Self> {
+        if !revalidations.lock().unwrap().insert(key.to_string()) {
+            return None;
+        }
+
+        Some(Self {
+            revalidations: revalidations.clone(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl Drop for Revalidation {
+    fn drop(&mut self) {
+        self.revalidations.lock().unwrap().remove(&self.key);
+    }
+}
+
+fn gateway_timeout() -> Response<Body> {
+    crate::error_pages::generated(StatusCode::GATEWAY_TIMEOUT)
+}
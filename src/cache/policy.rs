@@ -0,0 +1,132 @@
+use hyper::header::{HeaderMap, HeaderName, AGE, CACHE_CONTROL, DATE, EXPIRES, LAST_MODIFIED};
+use std::time::{Duration, SystemTime};
+
+// Upper bound for the heuristic freshness of responses without explicit expiration
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The `Cache-Control` directives relevant to a shared cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub only_if_cached: bool,
+    pub max_age: Option<Duration>,
+    pub s_maxage: Option<Duration>,
+    pub max_stale: Option<Duration>,
+    pub stale_while_revalidate: Option<Duration>,
+    pub stale_if_error: Option<Duration>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = CacheControl::default();
+
+        for value in headers.get_all(CACHE_CONTROL) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            for directive in value.split(',') {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => {
+                        (name.trim(), Some(argument.trim().trim_matches('"')))
+                    }
+                    None => (directive.trim(), None),
+                };
+                let seconds = || {
+                    argument
+                        .and_then(|argument| argument.parse::<u64>().ok())
+                        .map(Duration::from_secs)
+                };
+
+                match name.to_ascii_lowercase().as_str() {
+                    "no-store" => cc.no_store = true,
+                    // no-cache with field names still allows reuse, treat it like the plain form
+                    "no-cache" => cc.no_cache = true,
+                    "private" => cc.private = true,
+                    "public" => cc.public = true,
+                    "must-revalidate" => cc.must_revalidate = true,
+                    "proxy-revalidate" => cc.proxy_revalidate = true,
+                    "only-if-cached" => cc.only_if_cached = true,
+                    "max-age" => cc.max_age = seconds(),
+                    "s-maxage" => cc.s_maxage = seconds(),
+                    "max-stale" => cc.max_stale = seconds().or(Some(Duration::MAX)),
+                    "stale-while-revalidate" => cc.stale_while_revalidate = seconds(),
+                    "stale-if-error" => cc.stale_if_error = seconds(),
+                    _ => {}
+                }
+            }
+        }
+
+        cc
+    }
+
+    /// Returns true if a stale response must not be served without revalidation.
+    pub fn requires_revalidation(&self) -> bool {
+        self.must_revalidate || self.proxy_revalidate || self.s_maxage.is_some()
+    }
+}
+
+pub(crate) fn header_time(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// The freshness lifetime of a response, see RFC 9111 section 4.2.1.
+pub(crate) fn freshness_lifetime(
+    headers: &HeaderMap,
+    cc: &CacheControl,
+    heuristic: bool,
+) -> Duration {
+    if let Some(s_maxage) = cc.s_maxage {
+        return s_maxage;
+    }
+
+    if let Some(max_age) = cc.max_age {
+        return max_age;
+    }
+
+    let date = header_time(headers, DATE);
+
+    if headers.contains_key(EXPIRES) {
+        // An invalid Expires value means the response is already expired
+        return match (header_time(headers, EXPIRES), date) {
+            (Some(expires), Some(date)) => expires.duration_since(date).unwrap_or_default(),
+            (Some(expires), None) => expires
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+            _ => Duration::ZERO,
+        };
+    }
+
+    if heuristic {
+        if let (Some(date), Some(last_modified)) = (date, header_time(headers, LAST_MODIFIED)) {
+            let since_modified = date.duration_since(last_modified).unwrap_or_default();
+            return (since_modified / 10).min(MAX_HEURISTIC_FRESHNESS);
+        }
+    }
+
+    Duration::ZERO
+}
+
+/// The age of a response when it was received, see RFC 9111 section 4.2.3.
+pub(crate) fn initial_age(headers: &HeaderMap, response_time: SystemTime) -> Duration {
+    let apparent_age = header_time(headers, DATE)
+        .and_then(|date| response_time.duration_since(date).ok())
+        .unwrap_or_default();
+    let age = headers
+        .get(AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+
+    apparent_age.max(age)
+}
@@ -0,0 +1,282 @@
+//! Compression of upstream responses.
+//!
+//! [`Compression`] compresses responses that the upstream sent uncompressed, when the client
+//! accepts one of the enabled encodings and the response is of an allowed content type and large
+//! enough to be worth it.
+
+use crate::headers::{
+    add_vary, has_content_encoding, header_contains_token, matches_media_type, media_type,
+    weaken_etag,
+};
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder,
+    ZstdDecoder, ZstdEncoder,
+};
+use async_compression::Level;
+use futures_util::TryStreamExt;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE};
+use hyper::{Body, Method, Response, StatusCode};
+use std::io;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+const DEFAULT_MIN_SIZE: u64 = 1024;
+
+const DEFAULT_CONTENT_TYPES: [&str; 7] = [
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+    "font/",
+];
+
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// A content coding, as used in `Accept-Encoding` and `Content-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// Parses a content coding token, ignoring case.
+    pub fn from_token(token: &str) -> Option<Encoding> {
+        let token = token.trim();
+
+        if token.eq_ignore_ascii_case("gzip") || token.eq_ignore_ascii_case("x-gzip") {
+            Some(Encoding::Gzip)
+        } else if token.eq_ignore_ascii_case("deflate") {
+            Some(Encoding::Deflate)
+        } else if token.eq_ignore_ascii_case("br") {
+            Some(Encoding::Brotli)
+        } else if token.eq_ignore_ascii_case("zstd") {
+            Some(Encoding::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Configuration of response compression.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    content_types: Vec<String>,
+    min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            encodings: vec![
+                Encoding::Brotli,
+                Encoding::Zstd,
+                Encoding::Gzip,
+                Encoding::Deflate,
+            ],
+            content_types: DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The enabled encodings, in order of preference when the client accepts several of them
+    /// equally. Defaults to brotli, zstd, gzip and deflate.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Content types that are compressed. An entry ending in `/` matches a whole top-level
+    /// type, except for `text/event-stream`, which must be listed itself. Defaults to text
+    /// and common textual application types.
+    pub fn content_types<S: AsRef<str>>(mut self, content_types: &[S]) -> Self {
+        self.content_types = content_types
+            .iter()
+            .map(|t| t.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Responses with a `Content-Length` below this are left alone. Defaults to 1024 bytes.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Picks the encoding to use for a client sending the given `Accept-Encoding` header.
+    pub fn negotiate(&self, accept_encoding: Option<&HeaderValue>) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+
+        for encoding in &self.encodings {
+            let quality = quality(accept_encoding, *encoding);
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((*encoding, quality));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn is_compressible(&self, method: &Method, response: &Response<Body>) -> bool {
+        let status = response.status();
+        if method == Method::HEAD
+            || !status.is_success()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::PARTIAL_CONTENT
+        {
+            return false;
+        }
+
+        let headers = response.headers();
+        if headers.contains_key(CONTENT_RANGE) || has_content_encoding(headers) {
+            return false;
+        }
+
+        if header_contains_token(headers, &CACHE_CONTROL, "no-transform") {
+            return false;
+        }
+
+        if crate::limits::content_length(headers).is_some_and(|length| length < self.min_size) {
+            return false;
+        }
+
+        let content_type = match media_type(headers) {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+
+        // The encoder holds events back until its buffer fills, so event streams are only
+        // compressed when they are listed explicitly
+        if content_type == EVENT_STREAM {
+            return self
+                .content_types
+                .iter()
+                .any(|allowed| allowed == EVENT_STREAM);
+        }
+
+        matches_media_type(&self.content_types, &content_type)
+    }
+
+    /// Compresses `response` if the client accepts a compressed representation and the
+    /// response qualifies for compression.
+    pub(crate) fn compress(
+        &self,
+        method: &Method,
+        accept_encoding: Option<&HeaderValue>,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        if !self.is_compressible(method, &response) {
+            return response;
+        }
+
+        // Even when nothing is compressed, the representation depends on Accept-Encoding
+        let mut response = response;
+        add_vary(response.headers_mut(), "accept-encoding");
+
+        let encoding = match self.negotiate(accept_encoding) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+
+        debug!("Compressing response with {}", encoding.as_str());
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        weaken_etag(&mut parts.headers);
+
+        Response::from_parts(parts, encode(body, encoding))
+    }
+}
+
+/// The quality value a client sending the given `Accept-Encoding` header assigns to `encoding`,
+/// 0 if it is not acceptable.
+pub(crate) fn quality(accept_encoding: Option<&HeaderValue>, encoding: Encoding) -> f32 {
+    let accept_encoding = match accept_encoding.and_then(|value| value.to_str().ok()) {
+        Some(accept_encoding) => accept_encoding,
+        None => return 0.0,
+    };
+    let mut wildcard = None;
+
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let token = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                if name.trim().eq_ignore_ascii_case("q") {
+                    value.trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+
+        if token == "*" {
+            wildcard = Some(quality);
+        } else if Encoding::from_token(token) == Some(encoding) {
+            return quality;
+        }
+    }
+
+    wildcard.unwrap_or(0.0)
+}
+
+fn reader(
+    body: Body,
+) -> StreamReader<impl futures_util::Stream<Item = io::Result<bytes::Bytes>>, bytes::Bytes> {
+    StreamReader::new(body.map_err(io::Error::other))
+}
+
+/// Compresses a streamed body with the given encoding.
+pub(crate) fn encode(body: Body, encoding: Encoding) -> Body {
+    let reader = reader(body);
+
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(DeflateEncoder::new(reader))),
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::with_quality(
+            reader,
+            Level::Precise(4),
+        ))),
+        Encoding::Zstd => Body::wrap_stream(ReaderStream::new(ZstdEncoder::new(reader))),
+    }
+}
+
+/// Decompresses a streamed body that was compressed with the given encoding.
+pub(crate) fn decode(body: Body, encoding: Encoding) -> Body {
+    let reader = reader(body);
+
+    match encoding {
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipDecoder::new(reader))),
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(DeflateDecoder::new(reader))),
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliDecoder::new(reader))),
+        Encoding::Zstd => Body::wrap_stream(ReaderStream::new(ZstdDecoder::new(reader))),
+    }
+}
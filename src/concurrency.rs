@@ -0,0 +1,335 @@
+//! Concurrency limiting and load shedding.
+//!
+//! A [`ConcurrencyLimit`] bounds the number of requests in flight, over all upstreams, per
+//! upstream, or both. A request is in flight from the moment it is sent until its response body
+//! is finished. Requests over the limit wait in a bounded queue for a slot to free up, and are
+//! answered with `503 Service Unavailable` when the queue is full or they waited too long.
+//!
+//! With an [`Aimd`] configuration the limits adapt to the upstream: they grow by one after a
+//! full limit of fast, successful responses, and shrink by a factor when a response is slow or
+//! fails, down to a minimum. Responses to requests sent before the last decrease do not shrink the
+//! limit again, so a burst of slow responses shrinks it once.
+//!
+//! ```
+//! use hyper_reverse_proxy::concurrency::{Aimd, ConcurrencyLimit};
+//! use std::time::Duration;
+//!
+//! let limit = ConcurrencyLimit::new()
+//!     .global(1000)
+//!     .per_upstream(100)
+//!     .queue(50, Duration::from_secs(1))
+//!     .adaptive(Aimd::new(Duration::from_millis(250)));
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Additive increase, multiplicative decrease of a limit, driven by upstream latency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aimd {
+    latency_threshold: Duration,
+    backoff: f64,
+    min_limit: usize,
+}
+
+impl Aimd {
+    /// Responses taking longer than `latency_threshold` shrink the limit.
+    pub fn new(latency_threshold: Duration) -> Self {
+        Self {
+            latency_threshold,
+            backoff: 0.9,
+            min_limit: 1,
+        }
+    }
+
+    /// The factor the limit is multiplied by on slow or failed responses. Defaults to 0.9.
+    pub fn backoff(mut self, backoff: f64) -> Self {
+        self.backoff = backoff.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The limit never shrinks below this, at least one. Defaults to 1.
+    pub fn min_limit(mut self, min_limit: usize) -> Self {
+        self.min_limit = min_limit.max(1);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    limit: f64,
+    in_flight: usize,
+    successes: usize,
+    last_decrease: Option<Instant>,
+    waiters: VecDeque<oneshot::Sender<()>>,
+}
+
+impl State {
+    fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    // Hands free slots to the waiters at the front of the queue
+    fn dispatch(&mut self) {
+        while self.in_flight < self.limit() {
+            match self.waiters.pop_front() {
+                Some(waiter) => {
+                    if waiter.send(()).is_ok() {
+                        self.in_flight += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Limiter {
+    max: usize,
+    queue: usize,
+    queue_timeout: Duration,
+    adaptive: Option<Aimd>,
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    fn new(max: usize, config: &ConcurrencyLimit) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            queue: config.queue,
+            queue_timeout: config.queue_timeout,
+            adaptive: config.adaptive,
+            state: Mutex::new(State {
+                limit: max as f64,
+                in_flight: 0,
+                successes: 0,
+                last_decrease: None,
+                waiters: VecDeque::new(),
+            }),
+        })
+    }
+
+    async fn acquire(self: &Arc<Self>) -> Option<Slot> {
+        let mut receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight < state.limit() {
+                state.in_flight += 1;
+                return Some(Slot::new(self.clone()));
+            }
+
+            if state.waiters.len() >= self.queue {
+                return None;
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push_back(sender);
+            receiver
+        };
+
+        if let Ok(Ok(())) = tokio::time::timeout(self.queue_timeout, &mut receiver).await {
+            return Some(Slot::new(self.clone()));
+        }
+
+        // The slot may have been handed over right as the timeout fired
+        receiver.close();
+        if receiver.try_recv().is_ok() {
+            return Some(Slot::new(self.clone()));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.waiters.retain(|waiter| !waiter.is_closed());
+        None
+    }
+
+    fn observe(&self, acquired: Instant, success: bool) {
+        let aimd = match &self.adaptive {
+            Some(aimd) => aimd,
+            None => return,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if !success || acquired.elapsed() > aimd.latency_threshold {
+            // The request was sent under the previous limit, which was already decreased
+            if state.last_decrease.is_some_and(|last| acquired < last) {
+                return;
+            }
+
+            state.limit = (state.limit * aimd.backoff).max(aimd.min_limit as f64);
+            state.successes = 0;
+            state.last_decrease = Some(Instant::now());
+            debug!("Decreased concurrency limit to {}", state.limit());
+        } else {
+            state.successes += 1;
+            if state.successes >= state.limit() && state.limit() < self.max {
+                state.limit = (state.limit.floor() + 1.0).min(self.max as f64);
+                state.successes = 0;
+                debug!("Increased concurrency limit to {}", state.limit());
+                state.dispatch();
+            }
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.dispatch();
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    limiter: Arc<Limiter>,
+}
+
+impl Slot {
+    fn new(limiter: Arc<Limiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+/// The slots held by a request in flight, released when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    slots: Vec<Slot>,
+    acquired: Instant,
+}
+
+impl Permit {
+    /// Reports the outcome of the upstream request to adaptive limits. The latency is the time
+    /// since the permit was acquired.
+    pub fn observe(&self, success: bool) {
+        for slot in &self.slots {
+            slot.limiter.observe(self.acquired, success);
+        }
+    }
+}
+
+/// Limits of requests in flight, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    global: Option<Arc<Limiter>>,
+    global_max: Option<usize>,
+    per_upstream: Option<usize>,
+    upstreams: Arc<Mutex<HashMap<String, Arc<Limiter>>>>,
+    queue: usize,
+    queue_timeout: Duration,
+    adaptive: Option<Aimd>,
+}
+
+impl Default for ConcurrencyLimit {
+    fn default() -> Self {
+        Self {
+            global: None,
+            global_max: None,
+            per_upstream: None,
+            upstreams: Arc::new(Mutex::new(HashMap::new())),
+            queue: 0,
+            queue_timeout: Duration::ZERO,
+            adaptive: None,
+        }
+    }
+}
+
+impl ConcurrencyLimit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum number of requests in flight over all upstreams.
+    pub fn global(mut self, max: usize) -> Self {
+        self.global_max = Some(max);
+        self.rebuild()
+    }
+
+    /// The maximum number of requests in flight to each upstream, told apart by the authority
+    /// of their forward URI.
+    pub fn per_upstream(mut self, max: usize) -> Self {
+        self.per_upstream = Some(max);
+        self.rebuild()
+    }
+
+    /// Lets up to `size` requests wait for up to `timeout` when a limit is reached. Without a
+    /// queue, such requests are rejected right away.
+    pub fn queue(mut self, size: usize, timeout: Duration) -> Self {
+        self.queue = size;
+        self.queue_timeout = timeout;
+        self.rebuild()
+    }
+
+    /// Adapts the limits to the upstream latency. The configured limits become the maximums.
+    pub fn adaptive(mut self, aimd: Aimd) -> Self {
+        self.adaptive = Some(aimd);
+        self.rebuild()
+    }
+
+    /// The current global limit.
+    pub fn global_limit(&self) -> Option<usize> {
+        self.global
+            .as_ref()
+            .map(|limiter| limiter.state.lock().unwrap().limit())
+    }
+
+    /// The current limit of the upstream of `forward_uri`.
+    pub fn upstream_limit(&self, forward_uri: &str) -> Option<usize> {
+        self.per_upstream?;
+        let upstreams = self.upstreams.lock().unwrap();
+        let limit = match upstreams.get(&upstream(forward_uri)) {
+            Some(limiter) => limiter.state.lock().unwrap().limit(),
+            None => self.per_upstream?,
+        };
+        Some(limit)
+    }
+
+    /// Takes a slot for a request to `forward_uri`, waiting in the queue if needed. Returns
+    /// `None` when the request should be shed.
+    ///
+    /// The slot of the upstream is taken first, so requests queued for a busy upstream do not
+    /// hold global slots that requests to other upstreams could use.
+    pub async fn acquire(&self, forward_uri: &str) -> Option<Permit> {
+        let mut slots = Vec::with_capacity(2);
+
+        if let Some(max) = self.per_upstream {
+            let limiter = self
+                .upstreams
+                .lock()
+                .unwrap()
+                .entry(upstream(forward_uri))
+                .or_insert_with(|| Limiter::new(max, self))
+                .clone();
+            slots.push(limiter.acquire().await?);
+        }
+
+        if let Some(global) = &self.global {
+            slots.push(global.acquire().await?);
+        }
+
+        Some(Permit {
+            slots,
+            acquired: Instant::now(),
+        })
+    }
+
+    // Limiters are built from the whole configuration, so they are rebuilt on every change
+    fn rebuild(mut self) -> Self {
+        self.global = self.global_max.map(|max| Limiter::new(max, &self));
+        self.upstreams = Arc::new(Mutex::new(HashMap::new()));
+        self
+    }
+}
+
+fn upstream(forward_uri: &str) -> String {
+    forward_uri
+        .parse::<hyper::Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+        .unwrap_or_else(|| forward_uri.to_string())
+}
@@ -0,0 +1,323 @@
+//! Cross-origin resource sharing (CORS).
+//!
+//! A [`Cors`] policy answers preflight `OPTIONS` requests at the proxy, without contacting the
+//! upstream, and adds the `Access-Control-*` headers to the responses to cross-origin requests
+//! from allowed origins. `Access-Control-*` headers sent by the upstream are replaced, and
+//! `Vary: Origin` is added whenever the response depends on the origin.
+//!
+//! Origins are allowed exactly, by wildcard subdomain such as `https://*.example.com`, or by
+//! regular expression. Preflight requests from other origins, or asking for methods or headers
+//! that are not allowed, are answered with `403 Forbidden`.
+//!
+//! ```
+//! use hyper::Method;
+//! use hyper_reverse_proxy::cors::Cors;
+//! use std::time::Duration;
+//!
+//! let cors = Cors::new()
+//!     .allow_origin("https://app.example.com")
+//!     .allow_origin("https://*.preview.example.com")
+//!     .allow_origin_regex(r"http://localhost:\d+")
+//!     .unwrap()
+//!     .allow_methods([Method::GET, Method::POST, Method::DELETE])
+//!     .allow_credentials(true)
+//!     .max_age(Duration::from_secs(600));
+//! ```
+
+use crate::headers::add_vary;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use regex::Regex;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+enum AllowedOrigin {
+    Any,
+    Exact(String),
+    // The scheme and the suffix following `*`, such as `https://` and `.example.com`
+    Subdomain(String, String),
+    Regex(Regex),
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+            AllowedOrigin::Subdomain(scheme, suffix) => {
+                let origin = origin.to_ascii_lowercase();
+                origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|host| host.strip_suffix(suffix.as_str()))
+                    .is_some_and(|subdomain| {
+                        !subdomain.is_empty()
+                            && subdomain
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                    })
+            }
+            AllowedOrigin::Regex(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+/// A CORS policy, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Cors {
+    origins: Vec<AllowedOrigin>,
+    methods: Vec<Method>,
+    headers: Option<Vec<HeaderName>>,
+    exposed_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            origins: Vec::new(),
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: None,
+            exposed_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows an origin such as `https://app.example.com`. A `*` in place of the leftmost
+    /// labels of the host allows its subdomains, and `*` alone allows any origin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any origin is allowed with `*` while credentials are allowed.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        let origin = origin.trim().trim_end_matches('/').to_ascii_lowercase();
+        let allowed = if origin == "*" {
+            AllowedOrigin::Any
+        } else {
+            match origin.split_once("://*") {
+                Some((scheme, suffix)) if suffix.starts_with('.') => {
+                    AllowedOrigin::Subdomain(format!("{}://", scheme), suffix.to_string())
+                }
+                _ => AllowedOrigin::Exact(origin),
+            }
+        };
+
+        self.origins.push(allowed);
+        self.check_credentials();
+        self
+    }
+
+    /// Allows the origins matching a regular expression. The expression must match the whole
+    /// origin, as if it was enclosed in `^` and `$`.
+    pub fn allow_origin_regex(mut self, regex: &str) -> Result<Self, regex::Error> {
+        let regex = Regex::new(&format!("^(?:{})$", regex))?;
+        self.origins.push(AllowedOrigin::Regex(regex));
+        Ok(self)
+    }
+
+    /// The methods allowed in cross-origin requests. Defaults to `GET`, `HEAD` and `POST`.
+    pub fn allow_methods<I: IntoIterator<Item = Method>>(mut self, methods: I) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// The request headers allowed in cross-origin requests. By default, all the headers asked
+    /// for by a preflight request are allowed.
+    pub fn allow_headers<I: IntoIterator<Item = HeaderName>>(mut self, headers: I) -> Self {
+        self.headers = Some(headers.into_iter().collect());
+        self
+    }
+
+    /// The response headers readable by cross-origin scripts, besides the CORS-safelisted ones.
+    pub fn expose_headers<I: IntoIterator<Item = HeaderName>>(mut self, headers: I) -> Self {
+        self.exposed_headers = headers.into_iter().collect();
+        self
+    }
+
+    /// Whether cross-origin requests may include credentials such as cookies. Defaults to
+    /// false.
+    ///
+    /// # Panics
+    ///
+    /// Panics if credentials are allowed while any origin is allowed with `*`, which would let
+    /// every site make credentialed requests.
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self.check_credentials();
+        self
+    }
+
+    /// How long browsers may cache preflight responses.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Whether the request is a preflight request.
+    pub fn is_preflight<B>(request: &Request<B>) -> bool {
+        request.method() == Method::OPTIONS
+            && request.headers().contains_key(ORIGIN)
+            && request
+                .headers()
+                .contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// The response to a preflight request, or `None` if the request is not one.
+    pub fn preflight<B>(&self, request: &Request<B>) -> Option<Response<Body>> {
+        if !Self::is_preflight(request) {
+            return None;
+        }
+
+        let headers = request.headers();
+        let allowed_origin = headers.get(ORIGIN).and_then(|origin| self.allowed(origin));
+        let method_allowed = headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+            .is_some_and(|method| self.methods.contains(&method));
+        let requested_headers = requested_headers(headers);
+        let headers_allowed = match &self.headers {
+            Some(allowed) => requested_headers
+                .iter()
+                .all(|name| allowed.iter().any(|allowed| allowed == name)),
+            None => true,
+        };
+
+        let allowed_origin = match allowed_origin {
+            Some(origin) if method_allowed && headers_allowed => origin,
+            _ => {
+                debug!("Rejecting CORS preflight request");
+                let mut response = crate::error_pages::generated(StatusCode::FORBIDDEN);
+                add_vary(response.headers_mut(), "Origin");
+                return Some(response);
+            }
+        };
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let response_headers = response.headers_mut();
+        self.add_origin(response_headers, allowed_origin);
+
+        response_headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            join(self.methods.iter().map(Method::as_str)),
+        );
+        let allowed_headers = match &self.headers {
+            Some(allowed) => join(allowed.iter().map(HeaderName::as_str)),
+            None => join(requested_headers.iter().map(HeaderName::as_str)),
+        };
+        if !allowed_headers.is_empty() {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            response_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+        }
+        add_vary(response_headers, "Access-Control-Request-Method");
+        add_vary(response_headers, "Access-Control-Request-Headers");
+
+        Some(response)
+    }
+
+    /// Adds the CORS headers to the response to a request from `origin`.
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        for name in [
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_MAX_AGE,
+        ] {
+            headers.remove(name);
+        }
+
+        match origin.and_then(|origin| self.allowed(origin)) {
+            Some(allowed_origin) => {
+                self.add_origin(headers, allowed_origin);
+                if !self.exposed_headers.is_empty() {
+                    headers.insert(
+                        ACCESS_CONTROL_EXPOSE_HEADERS,
+                        join(self.exposed_headers.iter().map(HeaderName::as_str)),
+                    );
+                }
+            }
+            None if self.varies() => add_vary(headers, "Origin"),
+            None => {}
+        }
+    }
+
+    // The value of `Access-Control-Allow-Origin` for an origin, if it is allowed
+    fn allowed(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let origin_str = origin.to_str().ok()?;
+        let allowed = self
+            .origins
+            .iter()
+            .find(|allowed| allowed.matches(origin_str))?;
+
+        match allowed {
+            AllowedOrigin::Any => Some(HeaderValue::from_static("*")),
+            _ => Some(origin.clone()),
+        }
+    }
+
+    fn check_credentials(&self) {
+        let any_origin = self
+            .origins
+            .iter()
+            .any(|allowed| matches!(allowed, AllowedOrigin::Any));
+        assert!(
+            !(any_origin && self.credentials),
+            "credentials cannot be allowed for any origin"
+        );
+    }
+
+    // Whether the headers of responses depend on the origin of the request
+    fn varies(&self) -> bool {
+        self.credentials
+            || self
+                .origins
+                .iter()
+                .any(|allowed| !matches!(allowed, AllowedOrigin::Any))
+    }
+
+    fn add_origin(&self, headers: &mut HeaderMap, allowed_origin: HeaderValue) {
+        let wildcard = allowed_origin == "*";
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if !wildcard {
+            add_vary(headers, "Origin");
+        }
+    }
+}
+
+fn requested_headers(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers
+        .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect()
+}
+
+fn join<'a, I: Iterator<Item = &'a str>>(items: I) -> HeaderValue {
+    let joined = items.collect::<Vec<_>>().join(", ");
+    HeaderValue::from_str(&joined).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
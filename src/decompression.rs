@@ -0,0 +1,125 @@
+//! Decompression of upstream responses.
+//!
+//! With [`Decompression`] enabled, the proxy asks the upstream for a compressed response but
+//! decodes it before handing it to [response hooks](crate::hooks::ResponseHook) and
+//! [body rewriting](crate::rewrite::BodyRewrite), so they can work on plain bodies. The decoded
+//! response is then re-encoded by [`Compression`](crate::compression::Compression), if enabled,
+//! as negotiated with the client. When neither is configured and the client accepts the
+//! upstream encoding, the response is passed through untouched. Range requests are forwarded
+//! with the client's `Accept-Encoding`, since a range of an encoded body cannot be decoded.
+
+use crate::compression::{self, Encoding};
+use crate::headers::weaken_etag;
+use crate::{body, ProxyError};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::{Body, Method, Response, StatusCode};
+
+/// Configuration of upstream response decompression.
+#[derive(Debug, Clone)]
+pub struct Decompression {
+    encodings: Vec<Encoding>,
+    accept_encoding: HeaderValue,
+}
+
+impl Default for Decompression {
+    fn default() -> Self {
+        let decompression = Self {
+            encodings: Vec::new(),
+            accept_encoding: HeaderValue::from_static(""),
+        };
+
+        decompression.encodings(&[
+            Encoding::Brotli,
+            Encoding::Zstd,
+            Encoding::Gzip,
+            Encoding::Deflate,
+        ])
+    }
+}
+
+impl Decompression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The encodings requested from the upstream. Defaults to brotli, zstd, gzip and deflate.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        let accept_encoding = encodings
+            .iter()
+            .map(|encoding| encoding.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        self.encodings = encodings.to_vec();
+        self.accept_encoding = HeaderValue::from_str(&accept_encoding).unwrap();
+        self
+    }
+
+    /// The `Accept-Encoding` header sent to the upstream in place of the client's one, except
+    /// for range requests.
+    pub(crate) fn accept_encoding(&self) -> HeaderValue {
+        self.accept_encoding.clone()
+    }
+
+    /// Decodes `response`, unless it can be passed through because the client accepts its
+    /// encoding as is.
+    pub(crate) fn decompress(
+        &self,
+        method: &Method,
+        client_accept_encoding: Option<&HeaderValue>,
+        pass_through: bool,
+        max_size: Option<u64>,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        // Error responses are decoded too, as the client may not accept their encoding. Ranges
+        // of an encoded representation cannot be decoded, but they are only returned to range
+        // requests, which keep the client's `Accept-Encoding`.
+        let status = response.status();
+        if method == Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+        {
+            return response;
+        }
+
+        let encoding = match content_encoding(response.headers()) {
+            Some(encoding) if self.encodings.contains(&encoding) => encoding,
+            _ => return response,
+        };
+
+        if pass_through && compression::quality(client_accept_encoding, encoding) > 0.0 {
+            debug!("Passing through {} encoded response", encoding.as_str());
+            return response;
+        }
+
+        debug!("Decompressing {} encoded response", encoding.as_str());
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(CONTENT_ENCODING);
+        parts.headers.remove(CONTENT_LENGTH);
+        weaken_etag(&mut parts.headers);
+
+        let body = compression::decode(body, encoding);
+        // The size limit applies to the decoded body, which can be far larger than the encoded one
+        let body = match max_size {
+            Some(max) => body::limited(body, max, || ProxyError::ResponseBodyTooLarge).0,
+            None => body,
+        };
+
+        Response::from_parts(parts, body)
+    }
+}
+
+// Only a single known coding is decoded, stacked codings are passed through
+fn content_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let mut values = headers.get_all(CONTENT_ENCODING).iter();
+    let value = values.next()?.to_str().ok()?;
+
+    if values.next().is_some() || value.contains(',') {
+        return None;
+    }
+
+    Encoding::from_token(value)
+}
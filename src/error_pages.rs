@@ -0,0 +1,193 @@
+//! Error pages for proxy failures and upstream errors.
+//!
+//! [`ErrorPages`] gives the error responses generated by the proxy, such as a `413` for a
+//! request body over the limit or a `502` when the upstream cannot be reached, a body rendered
+//! from a template. HTML or JSON is chosen by the client's `Accept` header. Upstream `4xx` and
+//! `5xx` responses can be intercepted and given an error page as well.
+//!
+//! Templates may contain the placeholders `{status}` and `{reason}`, replaced by the status code
+//! and its canonical reason phrase.
+
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+};
+use hyper::{Body, Response, StatusCode};
+use std::collections::HashMap;
+
+const DEFAULT_HTML: &str = "<!DOCTYPE html>\n<html>\n<head><title>{status} {reason}</title></head>\n<body>\n<h1>{status} {reason}</h1>\n</body>\n</html>\n";
+const DEFAULT_JSON: &str = "{\"status\":{status},\"error\":\"{reason}\"}";
+
+/// Marks responses generated by the proxy itself.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Generated;
+
+/// An empty response generated by the proxy, which gets an error page if configured.
+pub(crate) fn generated(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response.extensions_mut().insert(Generated);
+    response
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Html,
+    Json,
+}
+
+/// Configuration of error pages.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    html: HashMap<StatusCode, String>,
+    json: HashMap<StatusCode, String>,
+    default_html: String,
+    default_json: String,
+    intercept_upstream: bool,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        Self {
+            html: HashMap::new(),
+            json: HashMap::new(),
+            default_html: DEFAULT_HTML.to_string(),
+            default_json: DEFAULT_JSON.to_string(),
+            intercept_upstream: false,
+        }
+    }
+}
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The HTML template for `status`.
+    pub fn html<S: Into<String>>(mut self, status: StatusCode, template: S) -> Self {
+        self.html.insert(status, template.into());
+        self
+    }
+
+    /// The JSON template for `status`.
+    pub fn json<S: Into<String>>(mut self, status: StatusCode, template: S) -> Self {
+        self.json.insert(status, template.into());
+        self
+    }
+
+    /// The HTML template for statuses without their own one.
+    pub fn default_html<S: Into<String>>(mut self, template: S) -> Self {
+        self.default_html = template.into();
+        self
+    }
+
+    /// The JSON template for statuses without their own one.
+    pub fn default_json<S: Into<String>>(mut self, template: S) -> Self {
+        self.default_json = template.into();
+        self
+    }
+
+    /// Whether upstream `4xx` and `5xx` responses get an error page in place of their body.
+    /// Defaults to false.
+    pub fn intercept_upstream(mut self, intercept: bool) -> Self {
+        self.intercept_upstream = intercept;
+        self
+    }
+
+    /// An error response with `status`, in the format preferred by a request with these
+    /// headers.
+    pub fn render(&self, status: StatusCode, request_headers: &HeaderMap) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        self.fill(request_headers.get(ACCEPT), &mut response);
+        response
+    }
+
+    /// Gives a generated or, if enabled, an upstream error response its error page.
+    pub(crate) fn apply(
+        &self,
+        accept: Option<&HeaderValue>,
+        mut response: Response<Body>,
+    ) -> Response<Body> {
+        let generated = response.extensions().get::<Generated>().is_some();
+        let upstream_error = self.intercept_upstream
+            && (response.status().is_client_error() || response.status().is_server_error());
+
+        if generated || upstream_error {
+            debug!("Rendering error page for {}", response.status());
+            self.fill(accept, &mut response);
+        }
+
+        response
+    }
+
+    fn fill(&self, accept: Option<&HeaderValue>, response: &mut Response<Body>) {
+        let status = response.status();
+        let (template, content_type) = match negotiate(accept) {
+            Format::Html => (
+                self.html.get(&status).unwrap_or(&self.default_html),
+                "text/html; charset=utf-8",
+            ),
+            Format::Json => (
+                self.json.get(&status).unwrap_or(&self.default_json),
+                "application/json",
+            ),
+        };
+
+        let body = template
+            .replace("{status}", status.as_str())
+            .replace("{reason}", status.canonical_reason().unwrap_or(""));
+
+        let headers = response.headers_mut();
+        headers.remove(CONTENT_ENCODING);
+        headers.remove(ETAG);
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        *response.body_mut() = Body::from(body);
+    }
+}
+
+// JSON is only chosen when the client prefers it over HTML
+fn negotiate(accept: Option<&HeaderValue>) -> Format {
+    let accept = match accept.and_then(|accept| accept.to_str().ok()) {
+        Some(accept) => accept,
+        None => return Format::Html,
+    };
+
+    if quality(accept, "application", "json") > quality(accept, "text", "html") {
+        Format::Json
+    } else {
+        Format::Html
+    }
+}
+
+// The quality of the most specific media range in `accept` matching the media type
+fn quality(accept: &str, kind: &str, subtype: &str) -> f32 {
+    let mut best = (0, 0.0);
+
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let (range_kind, range_subtype) =
+            match params.next().and_then(|media| media.split_once('/')) {
+                Some(media) => media,
+                None => continue,
+            };
+
+        let specificity = match (range_kind, range_subtype) {
+            (k, s) if k.eq_ignore_ascii_case(kind) && s.eq_ignore_ascii_case(subtype) => 3,
+            (k, "*") if k.eq_ignore_ascii_case(kind) => 2,
+            ("*", "*") => 1,
+            _ => continue,
+        };
+        let q = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if specificity > best.0 {
+            best = (specificity, q);
+        }
+    }
+
+    best.1
+}
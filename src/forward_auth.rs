@@ -0,0 +1,190 @@
+//! Authorization of requests by an external service.
+//!
+//! Like nginx `auth_request` or Traefik ForwardAuth, a [`ForwardAuth`] sends a `GET` subrequest
+//! to an authorization service before a request is forwarded. The subrequest carries the method,
+//! URI and host of the original request in `X-Forwarded-Method`, `X-Forwarded-Uri` and
+//! `X-Forwarded-Host`, the client in `X-Forwarded-For`, and the selected request headers.
+//!
+//! A `2xx` response allows the request, and the selected headers of that response are copied
+//! into it, for example to pass the user resolved by the service to the upstream. Any other
+//! response is returned to the client as is, so the service can redirect to a login page or send
+//! its own challenge. Requests are answered with `500 Internal Server Error` when the service
+//! cannot be reached.
+//!
+//! ```
+//! use hyper::header::{HeaderName, AUTHORIZATION, COOKIE};
+//! use hyper_reverse_proxy::forward_auth::ForwardAuth;
+//!
+//! let forward_auth = ForwardAuth::new("http://auth.internal:9000/verify")
+//!     .request_header(AUTHORIZATION)
+//!     .request_header(COOKIE)
+//!     .response_header(HeaderName::from_static("x-auth-user"));
+//! ```
+
+use crate::forwarded;
+use hyper::body::HttpBody;
+use hyper::client::connect::Connect;
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use std::net::IpAddr;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Allowing responses with bodies up to this size are read to their end, so that their
+// connection can be reused for the next subrequest
+const MAX_DRAINED_BODY: usize = 64 * 1024;
+
+const X_FORWARDED_METHOD: HeaderName = HeaderName::from_static("x-forwarded-method");
+const X_FORWARDED_URI: HeaderName = HeaderName::from_static("x-forwarded-uri");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Configuration of external authorization, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct ForwardAuth {
+    address: String,
+    request_headers: Vec<HeaderName>,
+    response_headers: Vec<HeaderName>,
+    timeout: Duration,
+}
+
+impl ForwardAuth {
+    /// Authorizes requests with the service at the absolute URI `address`.
+    pub fn new<S: Into<String>>(address: S) -> Self {
+        Self {
+            address: address.into(),
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sends this header of the original request to the service.
+    pub fn request_header(mut self, name: HeaderName) -> Self {
+        self.request_headers.push(name);
+        self
+    }
+
+    /// Copies this header of allowing responses into the forwarded request, replacing the
+    /// header sent by the client if any.
+    pub fn response_header(mut self, name: HeaderName) -> Self {
+        self.response_headers.push(name);
+        self
+    }
+
+    /// How long to wait for the service to respond. Defaults to 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Asks the service whether the request is allowed, preparing it for forwarding. Returns
+    /// the response to send to the client if it is not.
+    pub async fn check<T: Connect + Clone + Send + Sync + 'static>(
+        &self,
+        client: &Client<T>,
+        client_ip: IpAddr,
+        request: &mut Request<Body>,
+    ) -> Option<Response<Body>> {
+        let subrequest = match self.subrequest(client_ip, request) {
+            Some(subrequest) => subrequest,
+            None => {
+                warn!("Could not build forward auth request to {}", self.address);
+                return Some(internal_error());
+            }
+        };
+
+        let mut response =
+            match tokio::time::timeout(self.timeout, client.request(subrequest)).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(err)) => {
+                    warn!("Forward auth request failed: {}", err);
+                    return Some(internal_error());
+                }
+                Err(_) => {
+                    warn!("Forward auth request timed out");
+                    return Some(internal_error());
+                }
+            };
+
+        if !response.status().is_success() {
+            debug!("Request denied by forward auth with {}", response.status());
+            crate::remove_hop_headers(response.headers_mut());
+            crate::remove_connection_headers(response.headers_mut());
+            return Some(response);
+        }
+
+        for name in &self.response_headers {
+            request.headers_mut().remove(name);
+            for value in response.headers().get_all(name) {
+                request.headers_mut().append(name, value.clone());
+            }
+        }
+
+        let body = drain(response.into_body());
+        if tokio::time::timeout(self.timeout, body).await.is_err() {
+            debug!("Forward auth response body timed out");
+        }
+
+        None
+    }
+
+    fn subrequest(&self, client_ip: IpAddr, request: &Request<Body>) -> Option<Request<Body>> {
+        let uri = self.address.parse::<Uri>().ok()?;
+        uri.authority()?;
+
+        let mut subrequest = Request::new(Body::empty());
+        *subrequest.uri_mut() = uri;
+
+        let headers = subrequest.headers_mut();
+        for name in &self.request_headers {
+            for value in request.headers().get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+
+        headers.insert(
+            X_FORWARDED_METHOD,
+            HeaderValue::from_str(request.method().as_str()).ok()?,
+        );
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str());
+        headers.insert(X_FORWARDED_URI, HeaderValue::from_str(path).ok()?);
+
+        let host = match request.headers().get(HOST) {
+            Some(host) => Some(host.clone()),
+            None => request
+                .uri()
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()),
+        };
+        if let Some(host) = host {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+
+        for value in request.headers().get_all(forwarded::X_FORWARDED_FOR) {
+            headers.append(forwarded::X_FORWARDED_FOR, value.clone());
+        }
+        forwarded::append_forwarded_for(headers, client_ip).ok()?;
+
+        Some(subrequest)
+    }
+}
+
+fn internal_error() -> Response<Body> {
+    crate::error_pages::generated(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// Reads the body to its end, unless it is larger than `MAX_DRAINED_BODY`
+async fn drain(mut body: Body) {
+    let mut size = 0;
+    while let Some(Ok(chunk)) = body.data().await {
+        size += chunk.len();
+        if size > MAX_DRAINED_BODY {
+            debug!("Not draining large forward auth response body");
+            return;
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Forwarding headers.
+//!
+//! The proxy appends the client address to the `X-Forwarded-For` chain of every request it
+//! forwards. The functions here do the same for custom request pipelines.
+//!
+//! ```
+//! use hyper::header::HeaderMap;
+//! use hyper_reverse_proxy::forwarded::{append_forwarded_for, forwarded_for};
+//!
+//! let mut headers = HeaderMap::new();
+//! headers.append("x-forwarded-for", "203.0.113.7".parse().unwrap());
+//! headers.append("x-forwarded-for", "198.51.100.1, 10.0.0.2".parse().unwrap());
+//!
+//! append_forwarded_for(&mut headers, "10.0.0.3".parse().unwrap()).unwrap();
+//! assert_eq!(
+//!     forwarded_for(&headers).unwrap(),
+//!     ["203.0.113.7", "198.51.100.1", "10.0.0.2", "10.0.0.3"]
+//! );
+//! ```
+
+use crate::ProxyError;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use std::net::IpAddr;
+
+/// The `X-Forwarded-For` header name.
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// The addresses in the `X-Forwarded-For` chain, from the original client to the last proxy.
+///
+/// Multiple header instances are read in order, as if they were a single comma separated list.
+/// Fails with [`ProxyError::ForwardHeaderError`] when a value is not visible ASCII.
+pub fn forwarded_for(headers: &HeaderMap) -> Result<Vec<&str>, ProxyError> {
+    let mut chain = Vec::new();
+
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        chain.extend(
+            value
+                .to_str()?
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty()),
+        );
+    }
+
+    Ok(chain)
+}
+
+/// Appends `client_ip` to the `X-Forwarded-For` chain.
+///
+/// All header instances are merged into a single one, so upstreams that only read the first
+/// instance see the whole chain. Instances that are not visible ASCII are dropped.
+pub fn append_forwarded_for(headers: &mut HeaderMap, client_ip: IpAddr) -> Result<(), ProxyError> {
+    let client_ip = client_ip.to_string();
+    let mut chain = Vec::new();
+
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        match value.to_str() {
+            Ok(value) => chain.extend(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|addr| !addr.is_empty()),
+            ),
+            Err(_) => debug!("Dropping invalid X-Forwarded-For value {:?}", value),
+        }
+    }
+    chain.push(&client_ip);
+
+    let value = HeaderValue::from_str(&chain.join(", "))?;
+    debug!("Setting X-Forwarded-For to {:?}", value);
+    headers.insert(X_FORWARDED_FOR, value);
+
+    Ok(())
+}
+
+/// The address of the client, seen through the proxies trusted by `is_trusted`.
+///
+/// Starting from the peer, the `X-Forwarded-For` chain is walked from the right as long as the
+/// address that appended the next entry is trusted. Invalid entries are not followed.
+pub fn client_ip<F>(headers: &HeaderMap, peer_ip: IpAddr, is_trusted: F) -> IpAddr
+where
+    F: Fn(IpAddr) -> bool,
+{
+    let mut client_ip = peer_ip;
+    if !is_trusted(client_ip) {
+        return client_ip;
+    }
+
+    let chain = match forwarded_for(headers) {
+        Ok(chain) => chain,
+        Err(_) => return client_ip,
+    };
+
+    for addr in chain.iter().rev() {
+        match addr.parse() {
+            Ok(addr) => client_ip = addr,
+            Err(_) => break,
+        }
+
+        if !is_trusted(client_ip) {
+            break;
+        }
+    }
+
+    client_ip
+}
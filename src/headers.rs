@@ -0,0 +1,73 @@
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, ETAG};
+#[cfg(any(feature = "compression", feature = "cors"))]
+use hyper::header::{HeaderName, VARY};
+
+/// Whether the comma separated values of the `name` headers contain `token`, ignoring case.
+#[cfg(any(feature = "compression", feature = "cors"))]
+pub(crate) fn header_contains_token(headers: &HeaderMap, name: &HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    })
+}
+
+/// Adds `token` to the `Vary` header unless it is already covered.
+#[cfg(any(feature = "compression", feature = "cors"))]
+pub(crate) fn add_vary(headers: &mut HeaderMap, token: &str) {
+    if header_contains_token(headers, &VARY, token) || header_contains_token(headers, &VARY, "*") {
+        return;
+    }
+
+    headers.append(VARY, HeaderValue::from_str(token).unwrap());
+}
+
+/// The media type of the `Content-Type` header, lowercased and without parameters.
+pub(crate) fn media_type(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+
+    Some(
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase(),
+    )
+}
+
+/// Whether `media_type` is one of `allowed`, where an entry ending in `/` matches a whole
+/// top-level type.
+pub(crate) fn matches_media_type(allowed: &[String], media_type: &str) -> bool {
+    allowed.iter().any(|allowed| {
+        if allowed.ends_with('/') {
+            media_type.starts_with(allowed.as_str())
+        } else {
+            media_type == allowed
+        }
+    })
+}
+
+/// Returns true if the headers declare a content coding other than `identity`.
+pub(crate) fn has_content_encoding(headers: &HeaderMap) -> bool {
+    headers.get_all(CONTENT_ENCODING).iter().any(|value| {
+        value.to_str().map_or(true, |value| {
+            value.split(',').any(|coding| {
+                !coding.trim().is_empty() && !coding.trim().eq_ignore_ascii_case("identity")
+            })
+        })
+    })
+}
+
+// A strong validator no longer matches once the representation is re-encoded
+pub(crate) fn weaken_etag(headers: &mut HeaderMap) {
+    if let Some(etag) = headers.get(ETAG).and_then(|etag| etag.to_str().ok()) {
+        if etag.starts_with('"') {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                headers.insert(ETAG, weak);
+            }
+        }
+    }
+}
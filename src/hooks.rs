@@ -0,0 +1,39 @@
+//! User hooks into the proxy pipeline.
+
+use hyper::{Body, Request, Response};
+use std::net::IpAddr;
+
+/// Called for every request before it is forwarded.
+///
+/// When [request buffering](crate::buffer::RequestBuffering) is enabled, the request extensions
+/// contain the [`BufferedBody`](crate::buffer::BufferedBody).
+pub trait RequestHook: Send + Sync {
+    /// Returning a response answers the request directly, without contacting the upstream.
+    fn on_request(&self, client_ip: IpAddr, request: &mut Request<Body>) -> Option<Response<Body>>;
+}
+
+impl<F> RequestHook for F
+where
+    F: Fn(IpAddr, &mut Request<Body>) -> Option<Response<Body>> + Send + Sync,
+{
+    fn on_request(&self, client_ip: IpAddr, request: &mut Request<Body>) -> Option<Response<Body>> {
+        self(client_ip, request)
+    }
+}
+
+/// Called for every upstream response before it is returned to the client.
+///
+/// When [decompression](crate::decompression::Decompression) is enabled, the body is no longer
+/// encoded, and [compression](crate::compression::Compression) runs after the hooks.
+pub trait ResponseHook: Send + Sync {
+    fn on_response(&self, response: &mut Response<Body>);
+}
+
+impl<F> ResponseHook for F
+where
+    F: Fn(&mut Response<Body>) + Send + Sync,
+{
+    fn on_response(&self, response: &mut Response<Body>) {
+        self(response)
+    }
+}
@@ -3,14 +3,92 @@
 #[macro_use]
 extern crate tracing;
 
-use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+#[cfg(feature = "access-log")]
+pub mod access_log;
+pub mod acl;
+#[cfg(feature = "affinity")]
+pub mod affinity;
+#[cfg(feature = "auth")]
+pub mod auth;
+mod body;
+#[cfg(feature = "buffer")]
+pub mod buffer;
+#[cfg(feature = "cache")]
+pub mod cache;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod concurrency;
+#[cfg(feature = "cors")]
+pub mod cors;
+#[cfg(feature = "compression")]
+pub mod decompression;
+pub mod error_pages;
+pub mod forward_auth;
+pub mod forwarded;
+mod headers;
+pub mod hooks;
+pub mod limits;
+#[cfg(feature = "mirror")]
+pub mod mirror;
+pub mod rate_limit;
+pub mod rewrite;
+pub mod security_headers;
+pub mod split;
+pub mod validation;
+#[cfg(feature = "waf")]
+pub mod waf;
+
+#[cfg(feature = "access-log")]
+use access_log::{AccessLog, AccessLogRecord};
+use acl::AccessControl;
+#[cfg(feature = "affinity")]
+use affinity::SessionAffinity;
+#[cfg(feature = "auth")]
+use auth::Auth;
+#[cfg(feature = "buffer")]
+use buffer::{BufferedBody, RequestBuffering};
+#[cfg(feature = "cache")]
+use cache::Cache;
+#[cfg(feature = "compression")]
+use compression::Compression;
+use concurrency::ConcurrencyLimit;
+#[cfg(feature = "cors")]
+use cors::Cors;
+#[cfg(feature = "compression")]
+use decompression::Decompression;
+use error_pages::ErrorPages;
+use forward_auth::ForwardAuth;
+use hooks::{RequestHook, ResponseHook};
+#[cfg(feature = "affinity")]
+use hyper::body::HttpBody;
+#[cfg(feature = "cors")]
+use hyper::header::ORIGIN;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, HOST};
+#[cfg(feature = "compression")]
+use hyper::header::{ACCEPT_ENCODING, RANGE};
+#[cfg(feature = "access-log")]
+use hyper::header::{REFERER, USER_AGENT};
 use hyper::http::header::{InvalidHeaderValue, ToStrError};
 use hyper::http::uri::InvalidUri;
 use hyper::upgrade::OnUpgrade;
 use hyper::{Body, Client, Error, Request, Response, StatusCode};
 use lazy_static::lazy_static;
+use limits::BodyLimits;
+#[cfg(feature = "mirror")]
+use mirror::Mirror;
+use rate_limit::{Decision, RateLimit};
+use rewrite::{BodyRewrite, CookieRewrite, RedirectRewrite};
+use security_headers::SecurityHeaders;
+use split::TrafficSplit;
 use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+#[cfg(feature = "access-log")]
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::copy_bidirectional;
+use validation::Violation;
+#[cfg(feature = "waf")]
+use waf::Waf;
 
 lazy_static! {
     static ref TE_HEADER: HeaderName = HeaderName::from_static("te");
@@ -30,8 +108,6 @@ lazy_static! {
         HeaderName::from_static("transfer-encoding"),
         HeaderName::from_static("upgrade"),
     ];
-
-    static ref X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 }
 
 #[derive(Debug)]
@@ -39,7 +115,53 @@ pub enum ProxyError {
     InvalidUri(InvalidUri),
     HyperError(Error),
     ForwardHeaderError,
+    InvalidRequest(Violation),
     UpgradeError(String),
+    RequestBodyTooLarge,
+    ResponseBodyTooLarge,
+    IoError(std::io::Error),
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::InvalidUri(err) => write!(f, "invalid forward uri: {}", err),
+            ProxyError::HyperError(err) => write!(f, "upstream request failed: {}", err),
+            ProxyError::ForwardHeaderError => write!(f, "failed to build forwarding headers"),
+            ProxyError::InvalidRequest(violation) => write!(f, "invalid request: {}", violation),
+            ProxyError::UpgradeError(msg) => write!(f, "upgrade failed: {}", msg),
+            ProxyError::RequestBodyTooLarge => write!(f, "request body too large"),
+            ProxyError::ResponseBodyTooLarge => write!(f, "response body too large"),
+            ProxyError::IoError(err) => write!(f, "i/o error: {}", err),
+        }
+    }
+}
+
+impl ProxyError {
+    /// The status of the response answering a request that failed with this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::HyperError(err) if err.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::RequestBodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ProxyError::ForwardHeaderError | ProxyError::InvalidRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ProxyError::InvalidUri(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::InvalidUri(err) => Some(err),
+            ProxyError::HyperError(err) => Some(err),
+            ProxyError::IoError(err) => Some(err),
+            ProxyError::InvalidRequest(violation) => Some(violation),
+            _ => None,
+        }
+    }
 }
 
 impl From<Error> for ProxyError {
@@ -48,6 +170,12 @@ impl From<Error> for ProxyError {
     }
 }
 
+impl From<std::io::Error> for ProxyError {
+    fn from(err: std::io::Error) -> ProxyError {
+        ProxyError::IoError(err)
+    }
+}
+
 impl From<InvalidUri> for ProxyError {
     fn from(err: InvalidUri) -> ProxyError {
         ProxyError::InvalidUri(err)
@@ -75,7 +203,7 @@ fn remove_hop_headers(headers: &mut HeaderMap) {
 }
 
 fn get_upgrade_type(headers: &HeaderMap) -> Option<String> {
-    #[allow(clippy::blocks_in_if_conditions)]
+    #[allow(clippy::blocks_in_conditions)]
     if headers
         .get(&*CONNECTION_HEADER)
         .map(|value| {
@@ -128,7 +256,7 @@ fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> String {
 
     let split_url = forward_url.split('?').collect::<Vec<&str>>();
 
-    let mut base_url: &str = split_url.get(0).unwrap_or(&"");
+    let mut base_url: &str = split_url.first().unwrap_or(&"");
     let forward_url_query: &str = split_url.get(1).unwrap_or(&"");
 
     let path2 = req.uri().path();
@@ -209,6 +337,11 @@ fn create_proxied_request<B>(
 ) -> Result<Request<B>, ProxyError> {
     info!("Creating proxied request");
 
+    validation::validate_request(&request).map_err(|violation| {
+        debug!("Rejecting invalid request: {}", violation);
+        ProxyError::InvalidRequest(violation)
+    })?;
+
     let contains_te_trailers_value = request
         .headers()
         .get(&*TE_HEADER)
@@ -253,35 +386,18 @@ fn create_proxied_request<B>(
     }
 
     // Add forwarding information in the headers
-    match request.headers_mut().entry(&*X_FORWARDED_FOR) {
-        hyper::header::Entry::Vacant(entry) => {
-            debug!("X-Fowraded-for header was vacant");
-            entry.insert(client_ip.to_string().parse()?);
-        }
-
-        hyper::header::Entry::Occupied(entry) => {
-            debug!("X-Fowraded-for header was occupied");
-            let client_ip_str = client_ip.to_string();
-            let mut addr =
-                String::with_capacity(entry.get().as_bytes().len() + 2 + client_ip_str.len());
-
-            addr.push_str(std::str::from_utf8(entry.get().as_bytes()).unwrap());
-            addr.push(',');
-            addr.push(' ');
-            addr.push_str(&client_ip_str);
-        }
-    }
+    forwarded::append_forwarded_for(request.headers_mut(), client_ip)?;
 
     debug!("Created proxied request");
 
     Ok(request)
 }
 
-pub async fn call<'a, T: hyper::client::connect::Connect + Clone + Send + Sync + 'static>(
+pub async fn call<T: hyper::client::connect::Connect + Clone + Send + Sync + 'static>(
     client_ip: IpAddr,
     forward_uri: &str,
     mut request: Request<Body>,
-    client: &'a Client<T>,
+    client: &Client<T>,
 ) -> Result<Response<Body>, ProxyError> {
     info!(
         "Received proxy call from {} to {}, client: {}",
@@ -345,11 +461,334 @@ pub async fn call<'a, T: hyper::client::connect::Connect + Clone + Send + Sync +
 
 pub struct ReverseProxy<T: hyper::client::connect::Connect + Clone + Send + Sync + 'static> {
     client: Client<T>,
+    #[cfg(feature = "access-log")]
+    access_log: Option<AccessLog>,
+    access_control: Option<AccessControl>,
+    #[cfg(feature = "cors")]
+    cors: Option<Cors>,
+    rate_limits: Vec<RateLimit>,
+    #[cfg(feature = "auth")]
+    auth: Option<Auth>,
+    forward_auth: Option<ForwardAuth>,
+    #[cfg(feature = "waf")]
+    waf: Option<Waf>,
+    concurrency: Option<ConcurrencyLimit>,
+    limits: BodyLimits,
+    #[cfg(feature = "buffer")]
+    buffering: Option<RequestBuffering>,
+    request_hooks: Vec<Arc<dyn RequestHook>>,
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
+    #[cfg(feature = "compression")]
+    decompression: Option<Decompression>,
+    response_hooks: Vec<Arc<dyn ResponseHook>>,
+    #[cfg(feature = "cache")]
+    cache: Option<Cache>,
+    #[cfg(feature = "mirror")]
+    mirror: Option<Mirror>,
+    redirect_rewrite: Option<RedirectRewrite>,
+    cookie_rewrite: Option<CookieRewrite>,
+    body_rewrite: Option<BodyRewrite>,
+    error_pages: Option<ErrorPages>,
+    security_headers: Option<SecurityHeaders>,
 }
 
 impl<T: hyper::client::connect::Connect + Clone + Send + Sync + 'static> ReverseProxy<T> {
     pub fn new(client: Client<T>) -> Self {
-        Self { client }
+        Self {
+            client,
+            #[cfg(feature = "access-log")]
+            access_log: None,
+            access_control: None,
+            #[cfg(feature = "cors")]
+            cors: None,
+            rate_limits: Vec::new(),
+            #[cfg(feature = "auth")]
+            auth: None,
+            forward_auth: None,
+            #[cfg(feature = "waf")]
+            waf: None,
+            concurrency: None,
+            limits: BodyLimits::default(),
+            #[cfg(feature = "buffer")]
+            buffering: None,
+            request_hooks: Vec::new(),
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "compression")]
+            decompression: None,
+            response_hooks: Vec::new(),
+            #[cfg(feature = "cache")]
+            cache: None,
+            #[cfg(feature = "mirror")]
+            mirror: None,
+            redirect_rewrite: None,
+            cookie_rewrite: None,
+            body_rewrite: None,
+            error_pages: None,
+            security_headers: None,
+        }
+    }
+
+    /// Records every exchange handled by this proxy in the given access log.
+    #[cfg(feature = "access-log")]
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Answers requests from clients denied by the access control with `403 Forbidden`. For
+    /// different lists per route, use one proxy per route or call [`AccessControl::check`].
+    pub fn with_access_control(mut self, access_control: AccessControl) -> Self {
+        self.access_control = Some(access_control);
+        self
+    }
+
+    /// Answers CORS preflight requests and adds CORS headers to responses, see [`cors`]. For
+    /// different policies per route, use one proxy per route or call [`Cors::preflight`] and
+    /// [`Cors::apply`].
+    #[cfg(feature = "cors")]
+    pub fn with_cors(mut self, cors: Cors) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Adds a rate limit, answering requests over it with `429 Too Many Requests`. A request
+    /// must be within all the limits, and its response gets the headers of the one with the
+    /// fewest remaining requests. Limits are checked before [`with_auth`](Self::with_auth), except
+    /// those keyed by [`Key::Identity`](rate_limit::Key::Identity), which are checked after it and
+    /// after [`with_forward_auth`](Self::with_forward_auth).
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limits.push(rate_limit);
+        self
+    }
+
+    /// Authenticates requests before they are forwarded, answering the others with
+    /// `401 Unauthorized`. Rate limits are checked before the credentials, so rejected requests
+    /// count against them, except for limits keyed by the verified identity.
+    #[cfg(feature = "auth")]
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Asks an authorization service whether requests are allowed before forwarding them, after
+    /// the checks of [`with_auth`](Self::with_auth).
+    pub fn with_forward_auth(mut self, forward_auth: ForwardAuth) -> Self {
+        self.forward_auth = Some(forward_auth);
+        self
+    }
+
+    /// Evaluates firewall rules on requests before they are forwarded, see [`waf`]. Rules on
+    /// the body need [request buffering](Self::with_request_buffering).
+    #[cfg(feature = "waf")]
+    pub fn with_waf(mut self, waf: Waf) -> Self {
+        self.waf = Some(waf);
+        self
+    }
+
+    /// Bounds the number of requests in flight, answering shed requests with
+    /// `503 Service Unavailable`.
+    pub fn with_concurrency_limit(mut self, concurrency: ConcurrencyLimit) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Enforces the given request and response body size limits.
+    pub fn with_limits(mut self, limits: BodyLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Reads request bodies completely before forwarding them, see [`buffer`].
+    #[cfg(feature = "buffer")]
+    pub fn with_request_buffering(mut self, buffering: RequestBuffering) -> Self {
+        self.buffering = Some(buffering);
+        self
+    }
+
+    /// Adds a hook that runs before every request is forwarded. Hooks run in the order they
+    /// were added.
+    pub fn with_request_hook<H: RequestHook + 'static>(mut self, hook: H) -> Self {
+        self.request_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Compresses uncompressed upstream responses for clients that accept it.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Requests compressed responses from the upstream and decodes them before the response
+    /// hooks run, see [`decompression`].
+    #[cfg(feature = "compression")]
+    pub fn with_decompression(mut self, decompression: Decompression) -> Self {
+        self.decompression = Some(decompression);
+        self
+    }
+
+    /// Adds a hook that runs on every upstream response. Hooks run in the order they were
+    /// added.
+    pub fn with_response_hook<H: ResponseHook + 'static>(mut self, hook: H) -> Self {
+        self.response_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Answers requests from the given response cache when possible, see [`cache`].
+    #[cfg(feature = "cache")]
+    pub fn with_cache(mut self, cache: Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sends copies of requests to a shadow upstream, see [`mirror`].
+    #[cfg(feature = "mirror")]
+    pub fn with_mirror(mut self, mirror: Mirror) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    /// Rewrites upstream addresses in redirect headers, see [`rewrite`].
+    pub fn with_redirect_rewrite(mut self, rewrite: RedirectRewrite) -> Self {
+        self.redirect_rewrite = Some(rewrite);
+        self
+    }
+
+    /// Rewrites the attributes of cookies set by the upstream, see [`rewrite`].
+    pub fn with_cookie_rewrite(mut self, rewrite: CookieRewrite) -> Self {
+        self.cookie_rewrite = Some(rewrite);
+        self
+    }
+
+    /// Rewrites upstream URLs in response bodies, see [`rewrite`].
+    pub fn with_body_rewrite(mut self, rewrite: BodyRewrite) -> Self {
+        self.body_rewrite = Some(rewrite);
+        self
+    }
+
+    /// Renders error pages for the error responses generated by the proxy and, if enabled,
+    /// for upstream errors, see [`error_pages`]. They are used by [`respond`](Self::respond)
+    /// for failed requests as well.
+    pub fn with_error_pages(mut self, error_pages: ErrorPages) -> Self {
+        self.error_pages = Some(error_pages);
+        self
+    }
+
+    /// Sets security headers on responses and removes the headers revealing the upstream
+    /// software, see [`security_headers`].
+    pub fn with_security_headers(mut self, security_headers: SecurityHeaders) -> Self {
+        self.security_headers = Some(security_headers);
+        self
+    }
+
+    /// Like [`call`](Self::call), but answers failed requests with an error page instead of
+    /// returning the error.
+    pub async fn respond(
+        &self,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        request: Request<Body>,
+    ) -> Response<Body> {
+        let headers = request.headers().clone();
+
+        match self.call(client_ip, forward_uri, request).await {
+            Ok(response) => response,
+            Err(err) => {
+                debug!("Answering failed request with an error page: {}", err);
+                let mut response = match &self.error_pages {
+                    Some(error_pages) => error_pages.render(err.status(), &headers),
+                    None => ErrorPages::default().render(err.status(), &headers),
+                };
+                if let Some(security_headers) = &self.security_headers {
+                    security_headers.apply(response.headers_mut());
+                }
+                response
+            }
+        }
+    }
+
+    /// Forwards the request to the backend it has affinity with, see [`affinity`]. Requests
+    /// without a body are retried on another healthy backend when connecting fails, and the
+    /// client is moved to it. Answers with a 503 when no backend is healthy.
+    #[cfg(feature = "affinity")]
+    pub async fn call_with_affinity(
+        &self,
+        client_ip: IpAddr,
+        affinity: &SessionAffinity,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        let retry = request.body().is_end_stream() && get_upgrade_type(request.headers()).is_none();
+        let method = request.method().clone();
+        let uri = request.uri().clone();
+        let version = request.version();
+        let headers = request.headers().clone();
+        let mut next = Some(request);
+        let mut attempt = 0;
+
+        loop {
+            let request = next.take().unwrap_or_else(|| {
+                let mut request = Request::new(Body::empty());
+                *request.method_mut() = method.clone();
+                *request.uri_mut() = uri.clone();
+                *request.version_mut() = version;
+                *request.headers_mut() = headers.clone();
+                request
+            });
+
+            let selection = match affinity.select(&request) {
+                Some(selection) => selection,
+                None => {
+                    debug!("No healthy backend for the request");
+                    let response = error_pages::generated(StatusCode::SERVICE_UNAVAILABLE);
+                    return Ok(match &self.error_pages {
+                        Some(error_pages) => error_pages.apply(headers.get(ACCEPT), response),
+                        None => response,
+                    });
+                }
+            };
+
+            let mut request = request;
+            affinity.strip_cookie(request.headers_mut());
+
+            match self.call(client_ip, selection.forward_uri(), request).await {
+                Ok(mut response) => {
+                    selection.apply(&mut response);
+                    return Ok(response);
+                }
+                Err(ProxyError::HyperError(err)) if err.is_connect() => {
+                    selection.connect_failed();
+                    attempt += 1;
+                    if !retry || attempt >= affinity.backend_count() {
+                        return Err(ProxyError::HyperError(err));
+                    }
+                    debug!("Retrying on another backend after connect error: {}", err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Forwards the request to the upstream selected by the traffic split, see [`split`].
+    /// Answers with a 503 when no upstream has a weight.
+    pub async fn call_with_split(
+        &self,
+        client_ip: IpAddr,
+        split: &TrafficSplit,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        match split.select(client_ip, &request) {
+            Some(forward_uri) => self.call(client_ip, forward_uri, request).await,
+            None => {
+                debug!("No upstream to split the request to");
+                let response = error_pages::generated(StatusCode::SERVICE_UNAVAILABLE);
+                Ok(match &self.error_pages {
+                    Some(error_pages) => error_pages.apply(request.headers().get(ACCEPT), response),
+                    None => response,
+                })
+            }
+        }
     }
 
     pub async fn call(
@@ -358,8 +797,500 @@ impl<T: hyper::client::connect::Connect + Clone + Send + Sync + 'static> Reverse
         forward_uri: &str,
         request: Request<Body>,
     ) -> Result<Response<Body>, ProxyError> {
-        call::<T>(client_ip, forward_uri, request, &self.client).await
+        #[cfg(feature = "access-log")]
+        if let Some(access_log) = &self.access_log {
+            return self
+                .forward_logged(access_log, client_ip, forward_uri, request)
+                .await;
+        }
+
+        self.forward(client_ip, forward_uri, request).await
+    }
+
+    #[cfg(feature = "access-log")]
+    async fn forward_logged(
+        &self,
+        access_log: &AccessLog,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        let mut record = AccessLogRecord {
+            timestamp: SystemTime::now(),
+            client_ip,
+            method: request.method().clone(),
+            uri: request.uri().to_string(),
+            forwarded_uri: self::forward_uri(forward_uri, &request),
+            version: request.version(),
+            status: StatusCode::OK,
+            bytes: 0,
+            upstream_latency: Duration::default(),
+            upstream_addr: forward_uri
+                .parse::<hyper::Uri>()
+                .ok()
+                .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+                .unwrap_or_else(|| forward_uri.to_string()),
+            upgrade: get_upgrade_type(request.headers()),
+            referer: header_string(request.headers(), REFERER),
+            user_agent: header_string(request.headers(), USER_AGENT),
+        };
+
+        let start = Instant::now();
+        let result = self.forward(client_ip, forward_uri, request).await;
+        record.upstream_latency = start.elapsed();
+
+        match result {
+            Ok(response) => {
+                record.status = response.status();
+
+                let access_log = access_log.clone();
+                let (parts, body) = response.into_parts();
+                let body = body::on_complete(body, move |bytes| {
+                    record.bytes = bytes;
+                    access_log.log(&record);
+                });
+
+                Ok(Response::from_parts(parts, body))
+            }
+            Err(err) => {
+                record.status = err.status();
+                access_log.log(&record);
+                Err(err)
+            }
+        }
+    }
+
+    async fn forward(
+        &self,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        let accept = request.headers().get(ACCEPT).cloned();
+        #[cfg(feature = "cors")]
+        let origin = request.headers().get(ORIGIN).cloned();
+        #[cfg(feature = "cors")]
+        let preflight = Cors::is_preflight(&request);
+        let mut response = self
+            .forward_request(client_ip, forward_uri, request)
+            .await?;
+
+        if let Some(error_pages) = &self.error_pages {
+            response = error_pages.apply(accept.as_ref(), response);
+        }
+
+        // Error responses get CORS headers too, so that scripts can read them
+        #[cfg(feature = "cors")]
+        if let Some(cors) = self.cors.as_ref().filter(|_| !preflight) {
+            cors.apply(origin.as_ref(), response.headers_mut());
+        }
+
+        if let Some(security_headers) = &self.security_headers {
+            security_headers.apply(response.headers_mut());
+        }
+
+        Ok(response)
+    }
+
+    // Counts the request against the limits checked before or after authentication, keeping the
+    // decision with the fewest remaining requests in `rate_limit`
+    async fn check_rate_limits(
+        &self,
+        client_ip: IpAddr,
+        request: &Request<Body>,
+        authenticated: bool,
+        rate_limit: &mut Option<Decision>,
+    ) -> Option<Response<Body>> {
+        let limits = self
+            .rate_limits
+            .iter()
+            .filter(|limit| limit.needs_identity() == authenticated);
+
+        for limit in limits {
+            match limit.check(client_ip, request).await {
+                Some(decision) if !decision.allowed => return Some(decision.too_many_requests()),
+                Some(decision) if rate_limit.is_none_or(|d| decision.remaining < d.remaining) => {
+                    *rate_limit = Some(decision);
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    async fn forward_request(
+        &self,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        mut request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        if let Some(access_control) = &self.access_control {
+            if let Some(response) = access_control.check(client_ip, request.headers()) {
+                return Ok(response);
+            }
+        }
+
+        #[cfg(feature = "cors")]
+        if let Some(response) = self.cors.as_ref().and_then(|cors| cors.preflight(&request)) {
+            return Ok(response);
+        }
+
+        let mut rate_limit: Option<Decision> = None;
+        if let Some(response) = self
+            .check_rate_limits(client_ip, &request, false, &mut rate_limit)
+            .await
+        {
+            return Ok(response);
+        }
+
+        #[cfg(feature = "auth")]
+        if let Some(auth) = &self.auth {
+            if let Some(response) = auth.check(&mut request).await {
+                return Ok(response);
+            }
+        }
+
+        if let Some(forward_auth) = &self.forward_auth {
+            if let Some(response) = forward_auth
+                .check(&self.client, client_ip, &mut request)
+                .await
+            {
+                return Ok(response);
+            }
+        }
+
+        if let Some(response) = self
+            .check_rate_limits(client_ip, &request, true, &mut rate_limit)
+            .await
+        {
+            return Ok(response);
+        }
+
+        if self.limits.rejects_request(request.headers()) {
+            return Ok(limits::payload_too_large());
+        }
+
+        let method = request.method().clone();
+        #[cfg(feature = "compression")]
+        let accept_encoding = request.headers().get(ACCEPT_ENCODING).cloned();
+        let host = match &self.redirect_rewrite {
+            Some(_) => header_string(request.headers(), HOST).or_else(|| {
+                request
+                    .uri()
+                    .authority()
+                    .map(|authority| authority.to_string())
+            }),
+            None => None,
+        };
+
+        #[cfg(feature = "compression")]
+        if let Some(decompression) = &self.decompression {
+            if !request.headers().contains_key(RANGE) {
+                request
+                    .headers_mut()
+                    .insert(ACCEPT_ENCODING, decompression.accept_encoding());
+            }
+        }
+
+        let request_exceeded = self.limits.limit_request(&mut request);
+        let exceeded = || {
+            request_exceeded
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::Relaxed))
+        };
+
+        #[cfg(feature = "buffer")]
+        match &self.buffering {
+            Some(buffering) if get_upgrade_type(request.headers()).is_none() => {
+                let body = std::mem::take(request.body_mut());
+
+                match buffering.buffer(body).await {
+                    Ok(buffered) => {
+                        *request.body_mut() = buffered.to_body();
+                        request.extensions_mut().insert(buffered);
+                    }
+                    Err(ProxyError::RequestBodyTooLarge) => {
+                        debug!("Request body exceeded the buffering limit");
+                        return Ok(limits::payload_too_large());
+                    }
+                    Err(_) if exceeded() => {
+                        debug!("Request body exceeded the limit while buffering");
+                        return Ok(limits::payload_too_large());
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            _ => {}
+        }
+
+        #[cfg(feature = "waf")]
+        if let Some(waf) = &self.waf {
+            if let Some(response) = waf.check(client_ip, &mut request) {
+                return Ok(response);
+            }
+        }
+
+        for hook in &self.request_hooks {
+            if let Some(response) = hook.on_request(client_ip, &mut request) {
+                debug!("Request answered by hook");
+                return Ok(response);
+            }
+        }
+
+        // Hooks may have replaced the body, which retries and mirrors replay from the buffer
+        #[cfg(feature = "buffer")]
+        match &self.buffering {
+            Some(buffering)
+                if !self.request_hooks.is_empty()
+                    && request.extensions().get::<BufferedBody>().is_some() =>
+            {
+                let body = std::mem::take(request.body_mut());
+                let buffered = match buffering.buffer(body).await {
+                    Ok(buffered) => buffered,
+                    Err(ProxyError::RequestBodyTooLarge) => return Ok(limits::payload_too_large()),
+                    Err(err) => return Err(err),
+                };
+                *request.body_mut() = buffered.to_body();
+                request.extensions_mut().insert(buffered);
+            }
+            _ => {}
+        }
+
+        #[cfg(feature = "mirror")]
+        if let Some(mirror) = &self.mirror {
+            if get_upgrade_type(request.headers()).is_none() {
+                mirror.mirror(&self.client, client_ip, &mut request);
+            }
+        }
+
+        let permit = match &self.concurrency {
+            Some(concurrency) => match concurrency.acquire(forward_uri).await {
+                Some(permit) => Some(permit),
+                None => {
+                    debug!("Shedding request over the concurrency limit");
+                    return Ok(error_pages::generated(StatusCode::SERVICE_UNAVAILABLE));
+                }
+            },
+            None => None,
+        };
+
+        #[cfg(feature = "cache")]
+        let invalidated_key = match &self.cache {
+            Some(_) if !method.is_safe() => Some(self::forward_uri(forward_uri, &request)),
+            _ => None,
+        };
+
+        let upstream = Upstream {
+            client: &self.client,
+            #[cfg(feature = "buffer")]
+            retries: self
+                .buffering
+                .as_ref()
+                .map_or(0, RequestBuffering::max_retries),
+        };
+        #[cfg(feature = "cache")]
+        let result = match &self.cache {
+            Some(cache) if cache.handles(&request) => {
+                cache.call(&upstream, client_ip, forward_uri, request).await
+            }
+            _ => upstream.call(client_ip, forward_uri, request).await,
+        };
+        #[cfg(not(feature = "cache"))]
+        let result = upstream.call(client_ip, forward_uri, request).await;
+
+        if let Some(permit) = &permit {
+            permit.observe(
+                result
+                    .as_ref()
+                    .is_ok_and(|response| !response.status().is_server_error()),
+            );
+        }
+
+        #[cfg(feature = "cache")]
+        if let (Some(cache), Some(key), Ok(response)) = (&self.cache, invalidated_key, &result) {
+            if response.status().is_success() || response.status().is_redirection() {
+                cache.invalidate(&key).await;
+            }
+        }
+
+        let mut response = match result {
+            Ok(response) => self.limits.limit_response(&method, response)?,
+            Err(_) if exceeded() => {
+                debug!("Request body exceeded the limit while streaming");
+                return Ok(limits::payload_too_large());
+            }
+            Err(err) => return Err(err),
+        };
+
+        if let Some(rewrite) = &self.redirect_rewrite {
+            rewrite.rewrite(forward_uri, host.as_deref(), response.headers_mut());
+        }
+
+        if let Some(rewrite) = &self.cookie_rewrite {
+            rewrite.rewrite(response.headers_mut());
+        }
+
+        #[cfg(feature = "compression")]
+        if let Some(decompression) = &self.decompression {
+            response = decompression.decompress(
+                &method,
+                accept_encoding.as_ref(),
+                self.response_hooks.is_empty() && self.body_rewrite.is_none(),
+                self.limits.response_limit(),
+                response,
+            );
+        }
+
+        if let Some(rewrite) = &self.body_rewrite {
+            response = rewrite.rewrite(&method, response);
+        }
+
+        for hook in &self.response_hooks {
+            hook.on_response(&mut response);
+        }
+
+        if let Some(decision) = rate_limit {
+            decision.apply(response.headers_mut());
+        }
+
+        #[cfg(feature = "compression")]
+        let response = match &self.compression {
+            Some(compression) => compression.compress(&method, accept_encoding.as_ref(), response),
+            None => response,
+        };
+
+        // The request stays in flight until its response body is finished
+        Ok(match permit {
+            Some(permit) => {
+                let (parts, body) = response.into_parts();
+                let body = body::on_complete(body, move |_| drop(permit));
+                Response::from_parts(parts, body)
+            }
+            None => response,
+        })
+    }
+}
+
+// The upstream client. Buffered requests are replayed up to `retries` times when the
+// connection to the upstream could not be established.
+pub(crate) struct Upstream<'a, T> {
+    pub(crate) client: &'a Client<T>,
+    #[cfg(feature = "buffer")]
+    pub(crate) retries: usize,
+}
+
+impl<T: hyper::client::connect::Connect + Clone + Send + Sync + 'static> Upstream<'_, T> {
+    pub(crate) async fn call(
+        &self,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        #[cfg(feature = "buffer")]
+        if let Some(buffered) = request.extensions().get::<BufferedBody>() {
+            if self.retries > 0 {
+                let buffered = buffered.clone();
+                return self.retry(client_ip, forward_uri, request, buffered).await;
+            }
+        }
+
+        call::<T>(client_ip, forward_uri, request, self.client).await
+    }
+
+    #[cfg(feature = "buffer")]
+    async fn retry(
+        &self,
+        client_ip: IpAddr,
+        forward_uri: &str,
+        request: Request<Body>,
+        buffered: BufferedBody,
+    ) -> Result<Response<Body>, ProxyError> {
+        let method = request.method().clone();
+        let uri = request.uri().clone();
+        let version = request.version();
+        let headers = request.headers().clone();
+        let mut next = Some(request);
+        let mut attempt = 0;
+
+        loop {
+            let request = next.take().unwrap_or_else(|| {
+                let mut request = Request::new(buffered.to_body());
+                *request.method_mut() = method.clone();
+                *request.uri_mut() = uri.clone();
+                *request.version_mut() = version;
+                *request.headers_mut() = headers.clone();
+                request.extensions_mut().insert(buffered.clone());
+                request
+            });
+
+            match call::<T>(client_ip, forward_uri, request, self.client).await {
+                Err(ProxyError::HyperError(err)) if err.is_connect() && attempt < self.retries => {
+                    attempt += 1;
+                    debug!(
+                        "Retrying request after connect error ({}): {}",
+                        attempt, err
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn header_string(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// The value of the request cookie `name`, from any of the `Cookie` headers.
+pub(crate) fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// Removes the request cookie `name` from the `Cookie` headers, dropping headers left empty.
+#[cfg(feature = "affinity")]
+pub(crate) fn remove_cookie(headers: &mut HeaderMap, name: &str) {
+    if cookie(headers, name).is_none() {
+        return;
     }
+
+    let values: Vec<String> = headers
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .map(str::trim)
+                .filter(|pair| pair.split_once('=').is_none_or(|(key, _)| key != name))
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+        .filter(|value| !value.is_empty())
+        .collect();
+
+    headers.remove(hyper::header::COOKIE);
+    for value in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.append(hyper::header::COOKIE, value);
+        }
+    }
+}
+
+// Compares secrets without revealing through timing how much of them matched
+#[cfg(any(feature = "affinity", feature = "auth"))]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
 }
 
 #[cfg(feature = "__bench")]
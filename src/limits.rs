@@ -0,0 +1,112 @@
+//! Request and response body size limits.
+
+use crate::{body, ProxyError};
+use hyper::header::{HeaderMap, CONTENT_LENGTH};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Maximum body sizes enforced by a [`ReverseProxy`](crate::ReverseProxy).
+///
+/// A declared `Content-Length` above the limit is rejected before anything is sent, and bodies
+/// without one are counted while they are streamed. Oversize requests are answered with
+/// `413 Payload Too Large`, oversize responses fail with
+/// [`ProxyError::ResponseBodyTooLarge`], which aborts the response if it is already streaming.
+/// Responses decoded by [`Decompression`](crate::decompression::Decompression) are limited
+/// again after decoding.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BodyLimits {
+    max_request_body: Option<u64>,
+    max_response_body: Option<u64>,
+}
+
+impl BodyLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_request_body(mut self, bytes: u64) -> Self {
+        self.max_request_body = Some(bytes);
+        self
+    }
+
+    pub fn max_response_body(mut self, bytes: u64) -> Self {
+        self.max_response_body = Some(bytes);
+        self
+    }
+
+    #[cfg(feature = "compression")]
+    pub(crate) fn response_limit(&self) -> Option<u64> {
+        self.max_response_body
+    }
+
+    /// Returns true if the declared length of the request is above the limit.
+    pub(crate) fn rejects_request(&self, headers: &HeaderMap) -> bool {
+        match (self.max_request_body, content_length(headers)) {
+            (Some(max), Some(length)) if length > max => {
+                debug!(
+                    "Rejecting request with a declared body larger than {} bytes",
+                    max
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Limits the streamed request body. The returned flag is set if the body turns out to be
+    /// larger than allowed while it is being forwarded.
+    pub(crate) fn limit_request(&self, request: &mut Request<Body>) -> Option<Arc<AtomicBool>> {
+        let max = self.max_request_body?;
+        let body = std::mem::take(request.body_mut());
+        let (body, exceeded) = body::limited(body, max, || ProxyError::RequestBodyTooLarge);
+        *request.body_mut() = body;
+
+        Some(exceeded)
+    }
+
+    pub(crate) fn limit_response(
+        &self,
+        method: &Method,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, ProxyError> {
+        let max = match self.max_response_body {
+            Some(max) => max,
+            None => return Ok(response),
+        };
+
+        // These responses declare the length of a body they do not carry
+        let status = response.status();
+        if method == Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return Ok(response);
+        }
+
+        if content_length(response.headers()).is_some_and(|length| length > max) {
+            debug!(
+                "Rejecting response with a declared body larger than {} bytes",
+                max
+            );
+            return Err(ProxyError::ResponseBodyTooLarge);
+        }
+
+        let (parts, body) = response.into_parts();
+        let (body, _) = body::limited(body, max, || ProxyError::ResponseBodyTooLarge);
+
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+pub(crate) fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+pub(crate) fn payload_too_large() -> Response<Body> {
+    crate::error_pages::generated(StatusCode::PAYLOAD_TOO_LARGE)
+}
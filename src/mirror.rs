@@ -0,0 +1,108 @@
+//! Mirroring of requests to a shadow upstream.
+//!
+//! With a [`Mirror`] attached to a [`ReverseProxy`](crate::ReverseProxy), a share of the
+//! forwarded requests is sent to a second upstream as well. Mirrored requests are fire and
+//! forget: their responses are discarded and their failures never affect the client. Request
+//! bodies are copied while they are streamed to the primary upstream, or taken from the
+//! [buffered body](crate::buffer::BufferedBody) when request buffering is enabled. A mirror
+//! that cannot keep up with the primary upstream has its copy of the body aborted.
+
+use crate::body;
+use crate::buffer::BufferedBody;
+use hyper::body::HttpBody;
+use hyper::client::connect::Connect;
+use hyper::{Body, Client, Request};
+use std::net::IpAddr;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Chunks of the request body held for a mirror that is slower than the primary upstream
+const BODY_CHANNEL_CAPACITY: usize = 16;
+
+/// Configuration of request mirroring.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    upstream: String,
+    percentage: f64,
+    timeout: Duration,
+}
+
+impl Mirror {
+    /// Mirrors requests to `upstream`, given like the forward URI of
+    /// [`ReverseProxy::call`](crate::ReverseProxy::call).
+    pub fn new<S: Into<String>>(upstream: S) -> Self {
+        Self {
+            upstream: upstream.into(),
+            percentage: 100.0,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// The percentage of requests that are mirrored. Defaults to 100.
+    pub fn percentage(mut self, percentage: f64) -> Self {
+        self.percentage = percentage.clamp(0.0, 100.0);
+        self
+    }
+
+    /// How long a mirrored request, including its response body, may take before it is
+    /// dropped. Defaults to 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn sample(&self) -> bool {
+        self.percentage >= 100.0 || rand::random::<f64>() * 100.0 < self.percentage
+    }
+
+    /// Sends a copy of `request` to the mirror, if it was sampled.
+    pub(crate) fn mirror<T: Connect + Clone + Send + Sync + 'static>(
+        &self,
+        client: &Client<T>,
+        client_ip: IpAddr,
+        request: &mut Request<Body>,
+    ) {
+        if !self.sample() {
+            return;
+        }
+
+        let body = match request.extensions().get::<BufferedBody>() {
+            Some(buffered) => buffered.to_body(),
+            None => {
+                let (body, copy) =
+                    body::split(std::mem::take(request.body_mut()), BODY_CHANNEL_CAPACITY);
+                *request.body_mut() = body;
+                copy
+            }
+        };
+
+        let mut mirrored = Request::new(body);
+        *mirrored.method_mut() = request.method().clone();
+        *mirrored.uri_mut() = request.uri().clone();
+        *mirrored.version_mut() = request.version();
+        *mirrored.headers_mut() = request.headers().clone();
+
+        let client = client.clone();
+        let upstream = self.upstream.clone();
+        let timeout = self.timeout;
+
+        tokio::spawn(async move {
+            let exchange = async {
+                let response = crate::call(client_ip, &upstream, mirrored, &client).await?;
+                let status = response.status();
+                let mut body = response.into_body();
+                while let Some(chunk) = body.data().await {
+                    chunk?;
+                }
+                Ok::<_, crate::ProxyError>(status)
+            };
+
+            match tokio::time::timeout(timeout, exchange).await {
+                Ok(Ok(status)) => debug!("Mirror {} answered {}", upstream, status),
+                Ok(Err(err)) => debug!("Mirrored request to {} failed: {:?}", upstream, err),
+                Err(_) => debug!("Mirrored request to {} timed out", upstream),
+            }
+        });
+    }
+}
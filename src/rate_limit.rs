@@ -0,0 +1,337 @@
+//! Rate limiting of requests.
+//!
+//! A [`RateLimit`] counts requests per key, by default the client IP, with the generic cell rate
+//! algorithm (GCRA), which behaves like a token bucket refilled at a steady rate: a key may send
+//! a burst of requests at once, then as many requests as the bucket refills. Requests over the
+//! limit are answered with `429 Too Many Requests` and a `Retry-After` header. All responses carry
+//! the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+//!
+//! Limits are checked before the request is authenticated, so clients guessing credentials are
+//! limited too. Limits keyed by the verified [`Identity`](crate::auth::Identity) with
+//! [`Key::Identity`] are checked once the request was authenticated.
+//!
+//! The state of each key is a single timestamp kept in a [`RateLimitStore`], by default a
+//! [`MemoryStore`]. Stores shared between several proxy instances let them enforce a common limit.
+//!
+//! ```
+//! use hyper_reverse_proxy::rate_limit::{Key, Quota, RateLimit};
+//!
+//! let rate_limit = RateLimit::new(Quota::per_minute(600).burst(20))
+//!     .key(Key::Header(hyper::header::HeaderName::from_static("x-api-key")));
+//! ```
+
+#[cfg(feature = "auth")]
+use crate::auth::Identity;
+use async_trait::async_trait;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::{Body, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+// Number of updates between two sweeps of expired keys from a memory store
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// Storage of the rate limiting state of each key.
+///
+/// The state is the theoretical arrival time of the next request, in nanoseconds since the Unix
+/// epoch. A key whose time has passed is equivalent to a missing key and can be dropped.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<u64>;
+
+    /// Sets the time of `key` to `new` if it is still `current`, and returns whether it did.
+    async fn compare_and_swap(&self, key: &str, current: Option<u64>, new: u64) -> bool;
+}
+
+#[derive(Default)]
+struct MemoryState {
+    times: HashMap<String, u64>,
+    updates: u64,
+}
+
+/// In-memory storage of rate limiting state, sweeping expired keys as it goes.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Debug for MemoryStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let keys = self.state.lock().unwrap().times.len();
+        f.debug_struct("MemoryStore").field("keys", &keys).finish()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn get(&self, key: &str) -> Option<u64> {
+        self.state.lock().unwrap().times.get(key).copied()
+    }
+
+    async fn compare_and_swap(&self, key: &str, current: Option<u64>, new: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.times.get(key).copied() != current {
+            return false;
+        }
+
+        state.times.insert(key.to_string(), new);
+        state.updates += 1;
+
+        if state.updates.is_multiple_of(SWEEP_INTERVAL) {
+            let now = now();
+            state.times.retain(|_, time| *time > now);
+        }
+
+        true
+    }
+}
+
+/// The rate requests are allowed at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    interval: Duration,
+    burst: u32,
+}
+
+impl Quota {
+    /// `count` requests per `period`, all of which may be sent at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero.
+    pub fn new(count: u32, period: Duration) -> Self {
+        assert!(count > 0, "a quota allows at least one request");
+
+        Self {
+            interval: period / count,
+            burst: count,
+        }
+    }
+
+    pub fn per_second(count: u32) -> Self {
+        Self::new(count, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(count: u32) -> Self {
+        Self::new(count, Duration::from_secs(60))
+    }
+
+    pub fn per_hour(count: u32) -> Self {
+        Self::new(count, Duration::from_secs(60 * 60))
+    }
+
+    /// The number of requests that may be sent at once, at least one.
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+}
+
+/// Extracts the key requests are counted by.
+pub trait KeyExtractor: Send + Sync {
+    /// Requests without a key are not limited.
+    fn key(&self, client_ip: IpAddr, request: &Request<Body>) -> Option<String>;
+
+    /// Whether the key depends on the verified [`Identity`], so that the limit is checked after
+    /// authentication.
+    fn needs_identity(&self) -> bool {
+        false
+    }
+}
+
+impl<F> KeyExtractor for F
+where
+    F: Fn(IpAddr, &Request<Body>) -> Option<String> + Send + Sync,
+{
+    fn key(&self, client_ip: IpAddr, request: &Request<Body>) -> Option<String> {
+        self(client_ip, request)
+    }
+}
+
+/// The built-in keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Key {
+    /// The client IP address.
+    ClientIp,
+    /// The value of a request header such as an API key, falling back to the client IP when it
+    /// is missing.
+    ///
+    /// Clients choose this value: unless the header carries a credential verified by
+    /// [`Auth`](crate::auth::Auth), a client escapes the limit by sending a new value with each
+    /// request. Such limits should be combined with a limit on the client IP.
+    Header(HeaderName),
+    /// The subject of the [`Identity`] verified by [`Auth`](crate::auth::Auth), falling back to
+    /// the client IP for requests without one.
+    Identity,
+    /// The request path, limiting all clients of a route together.
+    Route,
+}
+
+impl KeyExtractor for Key {
+    fn key(&self, client_ip: IpAddr, request: &Request<Body>) -> Option<String> {
+        let key = match self {
+            Key::ClientIp => format!("ip:{}", client_ip),
+            Key::Header(name) => match request.headers().get(name) {
+                Some(value) => format!("header:{}", String::from_utf8_lossy(value.as_bytes())),
+                None => format!("ip:{}", client_ip),
+            },
+            #[cfg(feature = "auth")]
+            Key::Identity => match request
+                .extensions()
+                .get::<Identity>()
+                .and_then(Identity::sub)
+            {
+                Some(subject) => format!("identity:{}", subject),
+                None => format!("ip:{}", client_ip),
+            },
+            #[cfg(not(feature = "auth"))]
+            Key::Identity => format!("ip:{}", client_ip),
+            Key::Route => format!("route:{}", request.uri().path()),
+        };
+
+        Some(key)
+    }
+
+    fn needs_identity(&self) -> bool {
+        *self == Key::Identity
+    }
+}
+
+/// The outcome of counting a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The burst size of the quota.
+    pub limit: u32,
+    /// The number of requests that could be sent at once after this one.
+    pub remaining: u32,
+    /// The time until the quota is fully available again.
+    pub reset: Duration,
+    /// The time until the request would be allowed, if it was not.
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    /// Adds the `RateLimit-*` headers, and `Retry-After` if the request was not allowed.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(seconds(self.reset)));
+
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(RETRY_AFTER, HeaderValue::from(seconds(retry_after)));
+        }
+    }
+
+    /// The `429` response to a request that was not allowed.
+    pub fn too_many_requests(&self) -> Response<Body> {
+        let mut response = crate::error_pages::generated(StatusCode::TOO_MANY_REQUESTS);
+        self.apply(response.headers_mut());
+        response
+    }
+}
+
+/// A rate limit, see the [module documentation](self).
+#[derive(Clone)]
+pub struct RateLimit {
+    quota: Quota,
+    key: Arc<dyn KeyExtractor>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl std::fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("quota", &self.quota)
+            .finish()
+    }
+}
+
+impl RateLimit {
+    /// A limit keyed by client IP, kept in a [`MemoryStore`].
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            key: Arc::new(Key::ClientIp),
+            store: Arc::new(MemoryStore::new()),
+        }
+    }
+
+    pub fn key<K: KeyExtractor + 'static>(mut self, key: K) -> Self {
+        self.key = Arc::new(key);
+        self
+    }
+
+    /// The store keeping the state of this limit. Several limits should not share a store, as
+    /// they would count the same keys together.
+    pub fn store<S: RateLimitStore + 'static>(mut self, store: S) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    pub(crate) fn needs_identity(&self) -> bool {
+        self.key.needs_identity()
+    }
+
+    /// Counts the request against the limit of its key. Returns `None` for requests without a
+    /// key.
+    pub async fn check(&self, client_ip: IpAddr, request: &Request<Body>) -> Option<Decision> {
+        let key = self.key.key(client_ip, request)?;
+        let interval = self.quota.interval.as_nanos() as u64;
+        let window = interval.saturating_mul(u64::from(self.quota.burst));
+
+        loop {
+            let now = now();
+            let current = self.store.get(&key).await;
+            let arrival = current.unwrap_or(now).max(now).saturating_add(interval);
+            let allowed_at = arrival.saturating_sub(window);
+
+            if now < allowed_at {
+                debug!("Rate limit exceeded for {}", key);
+                let next = arrival - interval;
+                return Some(Decision {
+                    allowed: false,
+                    limit: self.quota.burst,
+                    remaining: 0,
+                    reset: Duration::from_nanos(next - now),
+                    retry_after: Some(Duration::from_nanos(allowed_at - now)),
+                });
+            }
+
+            if self.store.compare_and_swap(&key, current, arrival).await {
+                return Some(Decision {
+                    allowed: true,
+                    limit: self.quota.burst,
+                    remaining: (now.saturating_add(window).saturating_sub(arrival)
+                        / interval.max(1)) as u32,
+                    reset: Duration::from_nanos(arrival - now),
+                    retry_after: None,
+                });
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+// Whole seconds, rounded up
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
@@ -0,0 +1,527 @@
+//! Rewriting of upstream addresses in responses.
+//!
+//! Backends often build absolute URLs from the address they are reached at, which is the
+//! internal one behind the proxy. [`RedirectRewrite`] maps the URLs in `Location`,
+//! `Content-Location` and `Refresh` headers back to the public origin the client used, like
+//! `proxy_redirect` in nginx. [`CookieRewrite`] adjusts the `Domain` and `Path` attributes of
+//! cookies set by the backend to where it is mounted, and can force security attributes on them.
+//! [`BodyRewrite`] rewrites upstream URLs in HTML, CSS and JavaScript bodies.
+
+use crate::body;
+use crate::headers::{has_content_encoding, matches_media_type, media_type, weaken_etag};
+use bytes::{Bytes, BytesMut};
+use hyper::header::{
+    Entry, HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, SET_COOKIE,
+};
+use hyper::{Body, Method, Response, StatusCode};
+use std::sync::Arc;
+
+const REDIRECT_HEADERS: [&str; 3] = ["location", "content-location", "refresh"];
+
+/// Configuration of the rewriting of redirect headers.
+///
+/// By default, URLs pointing at the forward URI of the request, with its path prefix, are
+/// rewritten to the origin from the request's `Host` header. Explicit rules are applied first.
+#[derive(Debug, Clone)]
+pub struct RedirectRewrite {
+    rules: Vec<(String, String)>,
+    automatic: bool,
+    scheme: String,
+}
+
+impl Default for RedirectRewrite {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            automatic: true,
+            scheme: "http".to_string(),
+        }
+    }
+}
+
+impl RedirectRewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the prefix `from` of redirect URLs with `to`.
+    pub fn rule(mut self, from: &str, to: &str) -> Self {
+        self.rules.push((from.to_string(), to.to_string()));
+        self
+    }
+
+    /// Whether URLs pointing at the forward URI are rewritten to the public origin. Defaults to
+    /// true.
+    pub fn automatic(mut self, automatic: bool) -> Self {
+        self.automatic = automatic;
+        self
+    }
+
+    /// The scheme of the public origin, for automatic rewriting. Defaults to `http`.
+    pub fn scheme(mut self, scheme: &str) -> Self {
+        self.scheme = scheme.to_string();
+        self
+    }
+
+    /// Rewrites the redirect headers of a response to a request forwarded to `forward_uri`,
+    /// which the client sent to `host`.
+    pub(crate) fn rewrite(&self, forward_uri: &str, host: Option<&str>, headers: &mut HeaderMap) {
+        for name in REDIRECT_HEADERS {
+            let value = match headers.get(name).and_then(|value| value.to_str().ok()) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let rewritten = if name == "refresh" {
+                rewrite_refresh(value, |url| self.rewrite_url(forward_uri, host, url))
+            } else {
+                self.rewrite_url(forward_uri, host, value)
+            };
+
+            if let Some(rewritten) = rewritten {
+                debug!("Rewriting {} {} to {}", name, value, rewritten);
+                if let Ok(rewritten) = HeaderValue::from_str(&rewritten) {
+                    headers.insert(HeaderName::from_static(name), rewritten);
+                }
+            }
+        }
+    }
+
+    fn rewrite_url(&self, forward_uri: &str, host: Option<&str>, url: &str) -> Option<String> {
+        for (from, to) in &self.rules {
+            if let Some(rest) = url.strip_prefix(from.as_str()) {
+                return Some(format!("{}{}", to, rest));
+            }
+        }
+
+        if !self.automatic {
+            return None;
+        }
+
+        let upstream = forward_uri
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        let prefix = upstream
+            .parse::<hyper::Uri>()
+            .ok()
+            .filter(|uri| uri.authority().is_some())
+            .map(|uri| uri.path().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+
+        if let (Some(rest), Some(host)) = (strip_path_prefix(url, upstream), host) {
+            let rest = if rest.is_empty() { "/" } else { rest };
+            return Some(format!("{}://{}{}", self.scheme, host, rest));
+        }
+
+        if !prefix.is_empty() && url.starts_with('/') {
+            if let Some(rest) = strip_path_prefix(url, &prefix) {
+                let rest = if rest.is_empty() { "/" } else { rest };
+                return Some(rest.to_string());
+            }
+        }
+
+        None
+    }
+}
+
+// Strips `prefix` from `url` if it ends at a path segment boundary
+fn strip_path_prefix<'a>(url: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = url.strip_prefix(prefix)?;
+
+    if rest.is_empty() || rest.starts_with(['/', '?', '#']) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+// Rewrites the URL of a `Refresh` value such as `5; url=http://backend/next`
+fn rewrite_refresh<F>(value: &str, rewrite_url: F) -> Option<String>
+where
+    F: FnOnce(&str) -> Option<String>,
+{
+    let start = value.to_ascii_lowercase().find("url=")? + "url=".len();
+    let url = value[start..].trim();
+    let quote = url
+        .chars()
+        .next()
+        .filter(|first| *first == '\'' || *first == '"');
+    let unquoted = match quote {
+        Some(quote) => url.trim_matches(quote),
+        None => url,
+    };
+
+    let rewritten = rewrite_url(unquoted)?;
+    Some(match quote {
+        Some(quote) => format!("{}{}{}{}", &value[..start], quote, rewritten, quote),
+        None => format!("{}{}", &value[..start], rewritten),
+    })
+}
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// Configuration of the rewriting of `Set-Cookie` headers in responses, like
+/// `proxy_cookie_domain` and `proxy_cookie_path` in nginx.
+#[derive(Debug, Clone, Default)]
+pub struct CookieRewrite {
+    domains: Vec<(String, String)>,
+    paths: Vec<(String, String)>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl CookieRewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the `Domain` attribute `from`, compared case-insensitively, with `to`. An empty
+    /// `to` removes the attribute, making the cookie a host-only one.
+    pub fn domain(mut self, from: &str, to: &str) -> Self {
+        self.domains
+            .push((from.trim_start_matches('.').to_string(), to.to_string()));
+        self
+    }
+
+    /// Replaces the prefix `from` of the `Path` attribute with `to`.
+    pub fn path(mut self, from: &str, to: &str) -> Self {
+        self.paths.push((from.to_string(), to.to_string()));
+        self
+    }
+
+    /// Adds the `Secure` attribute to all cookies.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Adds the `HttpOnly` attribute to all cookies.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Sets the `SameSite` attribute of all cookies.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub(crate) fn rewrite(&self, headers: &mut HeaderMap) {
+        let cookies = match headers.entry(SET_COOKIE) {
+            Entry::Occupied(entry) => entry.remove_entry_mult().1.collect::<Vec<_>>(),
+            Entry::Vacant(_) => return,
+        };
+
+        for cookie in cookies {
+            let rewritten = cookie
+                .to_str()
+                .ok()
+                .and_then(|value| HeaderValue::from_str(&self.rewrite_cookie(value)).ok())
+                .unwrap_or(cookie);
+            headers.append(SET_COOKIE, rewritten);
+        }
+    }
+
+    fn rewrite_cookie(&self, cookie: &str) -> String {
+        let mut parts = cookie.split(';').map(str::trim);
+        let mut attributes = vec![parts.next().unwrap_or_default().to_string()];
+        let (mut secure, mut http_only) = (false, false);
+
+        for attribute in parts.filter(|attribute| !attribute.is_empty()) {
+            let (name, value) = match attribute.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (attribute, None),
+            };
+
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("domain", Some(domain)) => match self.rewrite_domain(domain) {
+                    Some(domain) if domain.is_empty() => continue,
+                    Some(domain) => {
+                        attributes.push(format!("{}={}", name, domain));
+                        continue;
+                    }
+                    None => {}
+                },
+                ("path", Some(path)) => {
+                    if let Some(path) = self.rewrite_path(path) {
+                        attributes.push(format!("{}={}", name, path));
+                        continue;
+                    }
+                }
+                ("samesite", _) if self.same_site.is_some() => continue,
+                ("secure", None) => secure = true,
+                ("httponly", None) => http_only = true,
+                _ => {}
+            }
+
+            attributes.push(attribute.to_string());
+        }
+
+        if self.secure && !secure {
+            attributes.push("Secure".to_string());
+        }
+        if self.http_only && !http_only {
+            attributes.push("HttpOnly".to_string());
+        }
+        if let Some(same_site) = self.same_site {
+            attributes.push(format!("SameSite={}", same_site.as_str()));
+        }
+
+        attributes.join("; ")
+    }
+
+    fn rewrite_domain(&self, domain: &str) -> Option<String> {
+        let domain = domain.trim_start_matches('.');
+
+        self.domains
+            .iter()
+            .find(|(from, _)| from.eq_ignore_ascii_case(domain))
+            .map(|(_, to)| to.clone())
+    }
+
+    fn rewrite_path(&self, path: &str) -> Option<String> {
+        self.paths.iter().find_map(|(from, to)| {
+            let rest = strip_path_prefix(path, from.trim_end_matches('/'))?;
+            let rewritten = format!("{}{}", to.trim_end_matches('/'), rest);
+
+            Some(if rewritten.is_empty() {
+                "/".to_string()
+            } else {
+                rewritten
+            })
+        })
+    }
+}
+
+const DEFAULT_BODY_CONTENT_TYPES: [&str; 4] = [
+    "text/html",
+    "text/css",
+    "application/javascript",
+    "text/javascript",
+];
+
+// Characters after which a root-relative URL starts in HTML attributes, CSS `url()` and
+// JavaScript strings
+const URL_OPENERS: [u8; 4] = [b'"', b'\'', b'`', b'('];
+
+// What may follow a pattern for it to be replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Boundary {
+    // An absolute base must be followed by the end of its path segment or of the URL, so that
+    // `http://host/app` does not match `http://host/application`
+    UrlEnd,
+    // A root-relative pattern ending in `/` must not match the start of a `//` URL
+    NotSlash,
+    Any,
+}
+
+impl Boundary {
+    // `next` is None at the end of the body
+    fn allows(self, next: Option<u8>) -> bool {
+        match (self, next) {
+            (_, None) | (Boundary::Any, _) => true,
+            (Boundary::NotSlash, Some(next)) => next != b'/',
+            (Boundary::UrlEnd, Some(next)) => {
+                matches!(
+                    next,
+                    b'/' | b'?' | b'#' | b'"' | b'\'' | b'`' | b')' | b'<' | b'>'
+                ) || next.is_ascii_whitespace()
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Replacement {
+    pattern: Vec<u8>,
+    replacement: Vec<u8>,
+    boundary: Boundary,
+}
+
+/// Configuration of the rewriting of upstream URLs in response bodies, for legacy applications
+/// mounted under a path prefix.
+///
+/// Absolute URLs starting with the upstream base are rewritten to the public base, and
+/// root-relative URLs in quotes or CSS `url()` starting with the upstream path prefix get the
+/// public path prefix instead. Bodies are rewritten as they are streamed. Encoded bodies are
+/// left alone, so [`Decompression`](crate::decompression::Decompression) should be enabled
+/// along with it.
+#[derive(Debug, Clone)]
+pub struct BodyRewrite {
+    replacements: Arc<Vec<Replacement>>,
+    content_types: Vec<String>,
+}
+
+impl BodyRewrite {
+    /// Rewrites URLs from `upstream_base`, such as `http://10.0.0.1:8080/app`, to
+    /// `public_base`, such as `https://example.com/legacy` or `/legacy`.
+    pub fn new(upstream_base: &str, public_base: &str) -> Self {
+        let upstream_base = upstream_base.trim_end_matches('/');
+        let public_base = public_base.trim_end_matches('/');
+
+        let mut replacements = vec![Replacement {
+            pattern: upstream_base.as_bytes().to_vec(),
+            replacement: public_base.as_bytes().to_vec(),
+            boundary: Boundary::UrlEnd,
+        }];
+
+        let upstream_prefix = path_prefix(upstream_base);
+        let public_prefix = path_prefix(public_base);
+        if upstream_prefix != public_prefix {
+            for opener in URL_OPENERS {
+                let mut pattern = vec![opener];
+                pattern.extend_from_slice(upstream_prefix.as_bytes());
+                pattern.push(b'/');
+                let mut replacement = vec![opener];
+                replacement.extend_from_slice(public_prefix.as_bytes());
+                replacement.push(b'/');
+
+                replacements.push(Replacement {
+                    pattern,
+                    replacement,
+                    boundary: if upstream_prefix.is_empty() {
+                        Boundary::NotSlash
+                    } else {
+                        Boundary::Any
+                    },
+                });
+            }
+        }
+
+        Self {
+            replacements: Arc::new(replacements),
+            content_types: DEFAULT_BODY_CONTENT_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        }
+    }
+
+    /// Content types whose bodies are rewritten. An entry ending in `/` matches a whole
+    /// top-level type. Defaults to HTML, CSS and JavaScript.
+    pub fn content_types<S: AsRef<str>>(mut self, content_types: &[S]) -> Self {
+        self.content_types = content_types
+            .iter()
+            .map(|t| t.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    fn applies(&self, method: &Method, response: &Response<Body>) -> bool {
+        let status = response.status();
+        let headers = response.headers();
+
+        if method == Method::HEAD
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+            || headers.contains_key(CONTENT_RANGE)
+        {
+            return false;
+        }
+
+        let content_type = match media_type(headers) {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+
+        let allowed = matches_media_type(&self.content_types, &content_type);
+
+        if allowed && has_content_encoding(headers) {
+            debug!("Not rewriting encoded {} body", content_type);
+            return false;
+        }
+
+        allowed
+    }
+
+    /// Rewrites the body of `response` while it is streamed, if its content type is enabled.
+    pub(crate) fn rewrite(&self, method: &Method, response: Response<Body>) -> Response<Body> {
+        if !self.applies(method, &response) {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        weaken_etag(&mut parts.headers);
+
+        let replacements = self.replacements.clone();
+        let mut held = Vec::new();
+        let body = body::filter(body, move |chunk, last| {
+            held.extend_from_slice(chunk);
+            let (output, consumed) = replace(&replacements, &held, last);
+            held.drain(..consumed);
+            output
+        });
+
+        Response::from_parts(parts, body)
+    }
+}
+
+fn path_prefix(base: &str) -> String {
+    let path = match base.parse::<hyper::Uri>() {
+        Ok(uri) if uri.authority().is_some() => uri.path().to_string(),
+        _ => base.to_string(),
+    };
+
+    path.trim_end_matches('/').to_string()
+}
+
+// Replaces the patterns in `data`, returning the output and how many bytes were consumed. Unless
+// this is the `last` data, bytes that could be the start of a pattern are held back.
+fn replace(replacements: &[Replacement], data: &[u8], last: bool) -> (Bytes, usize) {
+    let mut output = BytesMut::with_capacity(data.len());
+    let mut i = 0;
+
+    'scan: while i < data.len() {
+        let rest = &data[i..];
+
+        for replacement in replacements {
+            let pattern = &replacement.pattern[..];
+
+            let bounded = replacement.boundary != Boundary::Any;
+
+            if rest.len() < pattern.len() + usize::from(bounded) {
+                if !last && pattern.starts_with(&rest[..rest.len().min(pattern.len())]) {
+                    break 'scan;
+                }
+                if rest.len() < pattern.len() || !rest.starts_with(pattern) {
+                    continue;
+                }
+            } else if !rest.starts_with(pattern)
+                || !replacement.boundary.allows(Some(rest[pattern.len()]))
+            {
+                continue;
+            }
+
+            output.extend_from_slice(&replacement.replacement);
+            i += pattern.len();
+            continue 'scan;
+        }
+
+        output.extend_from_slice(&rest[..1]);
+        i += 1;
+    }
+
+    (output.freeze(), i)
+}
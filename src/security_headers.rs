@@ -0,0 +1,143 @@
+//! Security response headers.
+//!
+//! [`SecurityHeaders`] sets `Strict-Transport-Security`, `X-Content-Type-Options`,
+//! `X-Frame-Options`, `Referrer-Policy`, `Content-Security-Policy` and `Permissions-Policy` on
+//! responses, replacing the values sent by the upstream unless
+//! [`overwrite`](SecurityHeaders::overwrite) is disabled. Headers revealing the upstream
+//! software, `Server` and `X-Powered-By` by default, are removed.
+//!
+//! `Strict-Transport-Security` is ignored by browsers on plain HTTP, so it should only be set
+//! when the proxy is served over HTTPS.
+//!
+//! ```
+//! use hyper::header::HeaderValue;
+//! use hyper_reverse_proxy::security_headers::SecurityHeaders;
+//!
+//! let security_headers = SecurityHeaders::recommended()
+//!     .content_security_policy(HeaderValue::from_static("default-src 'self'"))
+//!     .permissions_policy(HeaderValue::from_static("camera=(), microphone=()"));
+//! ```
+
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, SERVER,
+    STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use std::time::Duration;
+
+const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+const X_POWERED_BY: HeaderName = HeaderName::from_static("x-powered-by");
+
+/// A security headers policy, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    removed_headers: Vec<HeaderName>,
+    overwrite: bool,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            headers: Vec::new(),
+            removed_headers: vec![SERVER, X_POWERED_BY],
+            overwrite: true,
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// A policy that only removes `Server` and `X-Powered-By`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy with HSTS for a year including subdomains, `X-Content-Type-Options: nosniff`,
+    /// `X-Frame-Options: SAMEORIGIN` and `Referrer-Policy: strict-origin-when-cross-origin`.
+    pub fn recommended() -> Self {
+        Self::new()
+            .hsts(Duration::from_secs(365 * 24 * 60 * 60), true, false)
+            .nosniff()
+            .frame_options(HeaderValue::from_static("SAMEORIGIN"))
+            .referrer_policy(HeaderValue::from_static("strict-origin-when-cross-origin"))
+    }
+
+    /// Sets `Strict-Transport-Security`. Preloading requires `include_subdomains`.
+    pub fn hsts(self, max_age: Duration, include_subdomains: bool, preload: bool) -> Self {
+        let mut value = format!("max-age={}", max_age.as_secs());
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if preload {
+            value.push_str("; preload");
+        }
+
+        match HeaderValue::from_str(&value) {
+            Ok(value) => self.header(STRICT_TRANSPORT_SECURITY, value),
+            Err(_) => self,
+        }
+    }
+
+    /// Sets `X-Content-Type-Options: nosniff`.
+    pub fn nosniff(self) -> Self {
+        self.header(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"))
+    }
+
+    /// Sets `X-Frame-Options`, `DENY` or `SAMEORIGIN`.
+    pub fn frame_options(self, value: HeaderValue) -> Self {
+        self.header(X_FRAME_OPTIONS, value)
+    }
+
+    /// Sets `Referrer-Policy`.
+    pub fn referrer_policy(self, value: HeaderValue) -> Self {
+        self.header(REFERRER_POLICY, value)
+    }
+
+    /// Sets `Content-Security-Policy`.
+    pub fn content_security_policy(self, value: HeaderValue) -> Self {
+        self.header(CONTENT_SECURITY_POLICY, value)
+    }
+
+    /// Sets `Permissions-Policy`.
+    pub fn permissions_policy(self, value: HeaderValue) -> Self {
+        self.header(PERMISSIONS_POLICY, value)
+    }
+
+    /// Sets any other header.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.retain(|(existing, _)| *existing != name);
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Also removes this header from responses.
+    pub fn remove_header(mut self, name: HeaderName) -> Self {
+        self.removed_headers.push(name);
+        self
+    }
+
+    /// Keeps this header, which is removed by default.
+    pub fn keep_header(mut self, name: HeaderName) -> Self {
+        self.removed_headers.retain(|removed| *removed != name);
+        self
+    }
+
+    /// Whether the headers sent by the upstream are replaced. If false, they are only set on
+    /// responses without them. Defaults to true.
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Sets and removes the headers of a response.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.removed_headers {
+            headers.remove(name);
+        }
+
+        for (name, value) in &self.headers {
+            if self.overwrite || !headers.contains_key(name) {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+}
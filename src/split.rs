@@ -0,0 +1,188 @@
+//! Splitting of traffic between upstreams, for canary releases and A/B tests.
+//!
+//! A [`TrafficSplit`] picks the forward URI of a request from a set of weighted upstreams.
+//! Requests matching a header or cookie rule go to the upstream of the first matching rule.
+//! Other requests are assigned by hashing a sticky key, the client IP by default, into one of
+//! 10000 buckets, each upstream owning a range of them in proportion to its weight. A user keeps
+//! landing on the same upstream as long as the weights do not change, and when the weight of the
+//! last upstream grows, as when ramping up a canary, only the users moving to it change upstream.
+//! [`ReverseProxy::call_with_split`](crate::ReverseProxy::call_with_split) forwards requests to
+//! the selected upstream.
+//!
+//! ```
+//! use hyper_reverse_proxy::split::{Stickiness, TrafficSplit};
+//!
+//! let split = TrafficSplit::new()
+//!     .upstream("http://stable:8080", 95)
+//!     .upstream("http://canary:8080", 5)
+//!     .route_header("x-canary", "always", "http://canary:8080")
+//!     .sticky_by(Stickiness::Cookie("session".to_string()));
+//!
+//! let request = hyper::Request::get("/").body(hyper::Body::empty()).unwrap();
+//! let forward_uri = split.select("10.0.0.1".parse().unwrap(), &request);
+//! assert!(forward_uri.is_some());
+//! ```
+
+use hyper::header::HeaderName;
+use hyper::Request;
+use std::net::IpAddr;
+
+const BUCKETS: u64 = 10_000;
+
+/// What keeps a user on the same upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stickiness {
+    /// The client IP address.
+    ClientIp,
+    /// The value of a request header, falling back to the client IP when it is missing.
+    Header(HeaderName),
+    /// The value of a cookie, falling back to the client IP when it is missing.
+    Cookie(String),
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Header(HeaderName, String),
+    Cookie(String, String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    matcher: Matcher,
+    upstream: String,
+}
+
+/// Weighted selection of upstreams with header and cookie overrides.
+#[derive(Debug, Clone)]
+pub struct TrafficSplit {
+    upstreams: Vec<(String, u32)>,
+    rules: Vec<Rule>,
+    stickiness: Stickiness,
+}
+
+impl Default for TrafficSplit {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            rules: Vec::new(),
+            stickiness: Stickiness::ClientIp,
+        }
+    }
+}
+
+impl TrafficSplit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an upstream, given like the forward URI of
+    /// [`ReverseProxy::call`](crate::ReverseProxy::call), receiving a share of the traffic
+    /// proportional to its weight.
+    pub fn upstream<S: Into<String>>(mut self, forward_uri: S, weight: u32) -> Self {
+        self.upstreams.push((forward_uri.into(), weight));
+        self
+    }
+
+    /// Sends requests whose header `name` equals `value` to `forward_uri`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid header name.
+    pub fn route_header<S: Into<String>>(
+        mut self,
+        name: &str,
+        value: &str,
+        forward_uri: S,
+    ) -> Self {
+        let name = HeaderName::from_bytes(name.as_bytes()).expect("invalid header name");
+        self.rules.push(Rule {
+            matcher: Matcher::Header(name, value.to_string()),
+            upstream: forward_uri.into(),
+        });
+        self
+    }
+
+    /// Sends requests whose cookie `name` equals `value` to `forward_uri`.
+    pub fn route_cookie<S: Into<String>>(
+        mut self,
+        name: &str,
+        value: &str,
+        forward_uri: S,
+    ) -> Self {
+        self.rules.push(Rule {
+            matcher: Matcher::Cookie(name.to_string(), value.to_string()),
+            upstream: forward_uri.into(),
+        });
+        self
+    }
+
+    /// What keeps a user on the same upstream. Defaults to [`Stickiness::ClientIp`].
+    pub fn sticky_by(mut self, stickiness: Stickiness) -> Self {
+        self.stickiness = stickiness;
+        self
+    }
+
+    /// The forward URI for `request`, or None if no upstream has a weight.
+    pub fn select<B>(&self, client_ip: IpAddr, request: &Request<B>) -> Option<&str> {
+        let headers = request.headers();
+
+        for rule in &self.rules {
+            let matches = match &rule.matcher {
+                Matcher::Header(name, value) => headers
+                    .get_all(name)
+                    .iter()
+                    .any(|candidate| candidate.as_bytes() == value.as_bytes()),
+                Matcher::Cookie(name, value) => {
+                    crate::cookie(headers, name) == Some(value.as_str())
+                }
+            };
+
+            if matches {
+                debug!("Routing request to {} by rule", rule.upstream);
+                return Some(&rule.upstream);
+            }
+        }
+
+        let total = self
+            .upstreams
+            .iter()
+            .map(|(_, weight)| u64::from(*weight))
+            .sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+
+        let key = match &self.stickiness {
+            Stickiness::ClientIp => None,
+            Stickiness::Header(name) => headers.get(name).map(|value| value.as_bytes()),
+            Stickiness::Cookie(name) => crate::cookie(headers, name).map(str::as_bytes),
+        };
+        let hash = match key {
+            Some(key) => fnv1a(key),
+            None => match client_ip {
+                IpAddr::V4(ip) => fnv1a(&ip.octets()),
+                IpAddr::V6(ip) => fnv1a(&ip.octets()),
+            },
+        };
+
+        // The upstreams own consecutive ranges of buckets, ending at their cumulative weight
+        let bucket = hash % BUCKETS;
+        let mut cumulative = 0;
+        for (upstream, weight) in &self.upstreams {
+            cumulative += u64::from(*weight);
+            if *weight > 0 && bucket < cumulative * BUCKETS / total {
+                return Some(upstream);
+            }
+        }
+
+        None
+    }
+}
+
+// A hash that is stable across processes and releases, so users stay on their upstream when the
+// proxy restarts
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,161 @@
+//! Rejection of ambiguous requests.
+//!
+//! A request that a proxy and an upstream could parse differently can be used to smuggle a
+//! second request past the proxy. [`validate_request`] is run on every request before it is
+//! forwarded, and the requests it rejects fail with [`ProxyError::InvalidRequest`], answered
+//! with `400 Bad Request`:
+//!
+//! * requests with both `Content-Length` and `Transfer-Encoding`,
+//! * requests with several `Content-Length` values, even equal ones, or an invalid one,
+//! * requests with a `Transfer-Encoding` whose final coding is not `chunked`,
+//! * non-ASCII values of the headers that frame or route requests, such as `Host` or
+//!   `Transfer-Encoding`,
+//! * header sections larger than [`MAX_HEADER_SECTION`],
+//! * absolute-form request targets whose authority is not the `Host` header.
+//!
+//! [`ProxyError::InvalidRequest`]: crate::ProxyError::InvalidRequest
+
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, TE, TRANSFER_ENCODING,
+    UPGRADE,
+};
+use hyper::http::uri::{Authority, Scheme};
+use hyper::Request;
+
+/// The largest header section accepted, counting every header as `name: value\r\n`.
+pub const MAX_HEADER_SECTION: usize = 64 * 1024;
+
+// Headers that implementations may parse differently when they are not plain ASCII
+const ASCII_HEADERS: [HeaderName; 6] = [
+    HOST,
+    CONTENT_LENGTH,
+    TRANSFER_ENCODING,
+    CONNECTION,
+    UPGRADE,
+    TE,
+];
+
+/// The reason a request was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    ConflictingFraming,
+    DuplicateContentLength,
+    InvalidContentLength,
+    InvalidTransferEncoding,
+    InvalidHeaderValue,
+    HeaderSectionTooLarge,
+    AuthorityMismatch,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Violation::ConflictingFraming => "both content-length and transfer-encoding",
+            Violation::DuplicateContentLength => "several content-length values",
+            Violation::InvalidContentLength => "invalid content-length",
+            Violation::InvalidTransferEncoding => "transfer-encoding not ending with chunked",
+            Violation::InvalidHeaderValue => "non-ASCII characters in a header value",
+            Violation::HeaderSectionTooLarge => "header section too large",
+            Violation::AuthorityMismatch => "request target authority does not match host",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Checks that the request can only be understood one way, see the
+/// [module documentation](self).
+pub fn validate_request<B>(request: &Request<B>) -> Result<(), Violation> {
+    let headers = request.headers();
+
+    check_header_values(headers)?;
+    check_framing(headers)?;
+
+    if let Some(authority) = request.uri().authority() {
+        if let Some(host) = headers.get(HOST) {
+            if !same_authority(authority, request.uri().scheme(), host) {
+                return Err(Violation::AuthorityMismatch);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_header_values(headers: &HeaderMap) -> Result<(), Violation> {
+    let mut size = 0;
+
+    for (name, value) in headers {
+        if ASCII_HEADERS.contains(name) && !value.as_bytes().is_ascii() {
+            return Err(Violation::InvalidHeaderValue);
+        }
+
+        size += name.as_str().len() + value.len() + 4;
+        if size > MAX_HEADER_SECTION {
+            return Err(Violation::HeaderSectionTooLarge);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_framing(headers: &HeaderMap) -> Result<(), Violation> {
+    let mut lengths = headers.get_all(CONTENT_LENGTH).iter();
+    let content_length = lengths.next();
+    if lengths.next().is_some() {
+        return Err(Violation::DuplicateContentLength);
+    }
+
+    if let Some(value) = content_length {
+        let value = value.as_bytes();
+        if value.contains(&b',') {
+            return Err(Violation::DuplicateContentLength);
+        }
+        if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+            return Err(Violation::InvalidContentLength);
+        }
+    }
+
+    let codings = headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| Violation::InvalidTransferEncoding)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if codings.is_empty() {
+        return Ok(());
+    }
+    if content_length.is_some() {
+        return Err(Violation::ConflictingFraming);
+    }
+
+    let last = codings
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .next_back();
+    match last {
+        Some(coding) if coding.eq_ignore_ascii_case("chunked") => Ok(()),
+        _ => Err(Violation::InvalidTransferEncoding),
+    }
+}
+
+// Compares authorities ignoring case and the default port of the scheme
+fn same_authority(authority: &Authority, scheme: Option<&Scheme>, host: &HeaderValue) -> bool {
+    let host = match host.to_str().ok().and_then(|h| h.parse::<Authority>().ok()) {
+        Some(host) => host,
+        None => return false,
+    };
+    let default_port = match scheme.map(Scheme::as_str) {
+        Some("https") => Some(443),
+        Some("http") => Some(80),
+        _ => None,
+    };
+    let port = |authority: &Authority| authority.port_u16().or(default_port);
+
+    authority.host().eq_ignore_ascii_case(host.host()) && port(authority) == port(&host)
+}
@@ -0,0 +1,348 @@
+//! A minimal web application firewall.
+//!
+//! A [`Waf`] evaluates its [`Rule`]s on every request before it is forwarded. A rule matches
+//! when all its conditions do, each condition testing the method, path, query, a header or the
+//! body of the request. A matching rule then takes its [`Action`]: blocking the request with
+//! `403 Forbidden`, logging it, or tagging it with the rule id in a request header for the
+//! upstream. Rules are evaluated in order, and the first blocking rule stops the evaluation.
+//!
+//! Paths, queries and form bodies are percent-decoded before they are tested. Body conditions
+//! require [request buffering](crate::buffer::RequestBuffering): without it, or when the body
+//! was spilled to disk, only its size is known, from the buffer or `Content-Length`.
+//!
+//! [`builtin_rules`] detects path traversal, SQL injection, cross-site scripting and command
+//! injection. Pattern-based detection is easy to evade and can block legitimate requests, so
+//! the built-in rules are a baseline to tune, for instance by logging their matches first.
+//!
+//! ```
+//! use hyper::Method;
+//! use hyper_reverse_proxy::waf::{self, Action, Condition, Rule, Target, Waf};
+//!
+//! let waf = Waf::new()
+//!     .rules(waf::builtin_rules().into_iter().map(|rule| rule.action(Action::Tag)))
+//!     .rule(
+//!         Rule::new("large-upload")
+//!             .when(Target::Method, Condition::equals(Method::POST.as_str()))
+//!             .when(Target::Body, Condition::larger_than(10 * 1024 * 1024)),
+//!     );
+//! ```
+
+use crate::buffer::BufferedBody;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use std::net::IpAddr;
+
+const DEFAULT_TAG_HEADER: HeaderName = HeaderName::from_static("x-waf-rules");
+
+/// The part of a request tested by a [`Condition`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Method,
+    Path,
+    Query,
+    /// Every value of the header is tested, a condition matches if one of them does.
+    Header(HeaderName),
+    Body,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Contains(String),
+    Equals(String),
+    Regex(Regex),
+    LargerThan(u64),
+}
+
+/// A test on a [`Target`] of a request.
+#[derive(Debug, Clone)]
+pub struct Condition(Kind);
+
+impl Condition {
+    /// Whether the target contains `literal`, ignoring case.
+    pub fn contains(literal: &str) -> Self {
+        Condition(Kind::Contains(literal.to_lowercase()))
+    }
+
+    /// Whether the target is exactly `value`.
+    pub fn equals(value: &str) -> Self {
+        Condition(Kind::Equals(value.to_string()))
+    }
+
+    /// Whether the target matches a regular expression.
+    pub fn regex(regex: &str) -> Result<Self, regex::Error> {
+        Ok(Condition(Kind::Regex(Regex::new(regex)?)))
+    }
+
+    /// Whether the target is larger than this many bytes, before decoding.
+    pub fn larger_than(bytes: u64) -> Self {
+        Condition(Kind::LargerThan(bytes))
+    }
+
+    // `text` is `None` when only the size of the target is known
+    fn matches(&self, text: Option<&str>, size: u64) -> bool {
+        match (&self.0, text) {
+            (Kind::LargerThan(max), _) => size > *max,
+            (Kind::Contains(literal), Some(text)) => text.to_lowercase().contains(literal),
+            (Kind::Equals(value), Some(text)) => text == value,
+            (Kind::Regex(regex), Some(text)) => regex.is_match(text),
+            (_, None) => false,
+        }
+    }
+}
+
+/// What to do with the requests matched by a [`Rule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Answers with `403 Forbidden`.
+    Block,
+    /// Logs the match and forwards the request.
+    Log,
+    /// Adds the rule id to the [tag header](Waf::tag_header) and forwards the request.
+    Tag,
+}
+
+/// A firewall rule, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Rule {
+    id: String,
+    conditions: Vec<(Vec<Target>, Condition)>,
+    action: Action,
+}
+
+impl Rule {
+    /// A rule without conditions, which must be given at least one before it is added to a
+    /// [`Waf`].
+    pub fn new<S: Into<String>>(id: S) -> Self {
+        Self {
+            id: id.into(),
+            conditions: Vec::new(),
+            action: Action::Block,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Adds a condition on `target`.
+    pub fn when(self, target: Target, condition: Condition) -> Self {
+        self.when_any(vec![target], condition)
+    }
+
+    /// Adds a condition matching if it holds for any of `targets`.
+    pub fn when_any<I: IntoIterator<Item = Target>>(
+        mut self,
+        targets: I,
+        condition: Condition,
+    ) -> Self {
+        self.conditions
+            .push((targets.into_iter().collect(), condition));
+        self
+    }
+
+    /// The action taken on matching requests. Defaults to [`Action::Block`].
+    pub fn action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    fn matches(&self, request: &Request<Body>) -> bool {
+        self.conditions.iter().all(|(targets, condition)| {
+            targets
+                .iter()
+                .any(|target| target_matches(target, condition, request))
+        })
+    }
+}
+
+fn target_matches(target: &Target, condition: &Condition, request: &Request<Body>) -> bool {
+    match target {
+        Target::Method => {
+            let method = request.method().as_str();
+            condition.matches(Some(method), method.len() as u64)
+        }
+        Target::Path => {
+            let path = request.uri().path();
+            condition.matches(Some(&decode(path, false)), path.len() as u64)
+        }
+        Target::Query => {
+            let query = request.uri().query().unwrap_or("");
+            condition.matches(Some(&decode(query, true)), query.len() as u64)
+        }
+        Target::Header(name) => request.headers().get_all(name).iter().any(|value| {
+            condition.matches(
+                Some(&String::from_utf8_lossy(value.as_bytes())),
+                value.len() as u64,
+            )
+        }),
+        Target::Body => match request.extensions().get::<BufferedBody>() {
+            Some(buffered) => {
+                let text = buffered.as_bytes().map(|bytes| {
+                    let text = String::from_utf8_lossy(bytes);
+                    if is_form(request) {
+                        decode(&text, true)
+                    } else {
+                        text.into_owned()
+                    }
+                });
+                condition.matches(text.as_deref(), buffered.len())
+            }
+            None => {
+                let length = request
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(0);
+                condition.matches(None, length)
+            }
+        },
+    }
+}
+
+fn is_form(request: &Request<Body>) -> bool {
+    request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"))
+}
+
+fn decode(text: &str, plus_as_space: bool) -> String {
+    if plus_as_space && text.contains('+') {
+        percent_decode_str(&text.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned()
+    } else {
+        percent_decode_str(text).decode_utf8_lossy().into_owned()
+    }
+}
+
+/// The built-in rules, with the ids `path-traversal`, `sql-injection`, `xss` and
+/// `command-injection`, all blocking.
+pub fn builtin_rules() -> Vec<Rule> {
+    let rule = |id: &str, targets: &[Target], regex: &str| {
+        let condition = Condition::regex(regex).expect("invalid built-in rule");
+        Rule::new(id).when_any(targets.to_vec(), condition)
+    };
+    let input = [Target::Query, Target::Body];
+
+    vec![
+        rule(
+            "path-traversal",
+            &[Target::Path, Target::Query],
+            r"(?i)(\.\.[/\\]|[/\\]\.\.$|%2e%2e|/etc/(passwd|shadow)\b|\b(boot|win)\.ini\b)",
+        ),
+        rule(
+            "sql-injection",
+            &input,
+            r"(?i)(\bunion\b[\s(/*]+(all[\s(/*]+)?select\b|'\s*(or|and)\s+'?\w+'?\s*=\s*'?\w+|;\s*(drop|delete|insert|update|truncate|shutdown)\b|\b(sleep|benchmark|pg_sleep)\s*\(|\bwaitfor\s+delay\b|'\s*(--|#))",
+        ),
+        rule(
+            "xss",
+            &input,
+            r"(?i)(<\s*/?\s*script\b|javascript\s*:|<[^>]*\bon[a-z]+\s*=|<\s*iframe\b)",
+        ),
+        rule(
+            "command-injection",
+            &input,
+            r"(?i)(;|\||&&|\$\(|`)\s*(cat|ls|id|whoami|uname|wget|curl|nc|bash|sh|powershell)(\s|$)",
+        ),
+    ]
+}
+
+/// A set of firewall rules, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Waf {
+    rules: Vec<Rule>,
+    tag_header: HeaderName,
+}
+
+impl Default for Waf {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            tag_header: DEFAULT_TAG_HEADER,
+        }
+    }
+}
+
+impl Waf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule, evaluated after the rules already added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rule has no conditions, as it would match every request.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        assert!(
+            !rule.conditions.is_empty(),
+            "WAF rule {} has no conditions",
+            rule.id
+        );
+        self.rules.push(rule);
+        self
+    }
+
+    /// Adds rules, evaluated after the rules already added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the rules has no conditions.
+    pub fn rules<I: IntoIterator<Item = Rule>>(self, rules: I) -> Self {
+        rules.into_iter().fold(self, Self::rule)
+    }
+
+    /// The request header listing the ids of the matched [`Action::Tag`] rules. Defaults to
+    /// `X-Waf-Rules`. It is always removed from incoming requests, so clients cannot forge it.
+    pub fn tag_header(mut self, name: HeaderName) -> Self {
+        self.tag_header = name;
+        self
+    }
+
+    /// Evaluates the rules on the request, tagging it for forwarding. Returns the `403`
+    /// response if a blocking rule matches.
+    pub fn check(&self, client_ip: IpAddr, request: &mut Request<Body>) -> Option<Response<Body>> {
+        request.headers_mut().remove(&self.tag_header);
+        let mut tags = Vec::new();
+
+        for rule in self.rules.iter().filter(|rule| rule.matches(request)) {
+            match rule.action {
+                Action::Block => {
+                    warn!(
+                        "WAF rule {} blocked {} {} from {}",
+                        rule.id,
+                        request.method(),
+                        request.uri().path(),
+                        client_ip
+                    );
+                    return Some(crate::error_pages::generated(StatusCode::FORBIDDEN));
+                }
+                Action::Log => warn!(
+                    "WAF rule {} matched {} {} from {}",
+                    rule.id,
+                    request.method(),
+                    request.uri().path(),
+                    client_ip
+                ),
+                Action::Tag => tags.push(rule.id.as_str()),
+            }
+        }
+
+        if !tags.is_empty() {
+            match HeaderValue::from_str(&tags.join(", ")) {
+                Ok(value) => {
+                    request.headers_mut().insert(&self.tag_header, value);
+                }
+                Err(_) => debug!("Not tagging request with invalid rule ids"),
+            }
+        }
+
+        None
+    }
+}
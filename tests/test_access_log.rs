@@ -0,0 +1,136 @@
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+use hyper_reverse_proxy::access_log::{AccessLog, AccessLogFormat, AccessLogRecord};
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn proxy(
+    format: AccessLogFormat,
+) -> (
+    ReverseProxy<hyper::client::HttpConnector>,
+    Arc<Mutex<Vec<String>>>,
+) {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let sink_lines = lines.clone();
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_access_log(AccessLog::new(format, move |line: &str| {
+            sink_lines.lock().unwrap().push(line.to_string())
+        }));
+
+    (proxy, lines)
+}
+
+fn hello_handler(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async { Ok(Response::new(Body::from("hello world"))) })
+    }));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_common_log_format(ctx: &mut HttpTestContext) {
+    hello_handler(ctx);
+    let (proxy, lines) = proxy(AccessLogFormat::Common);
+    let client_ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+    let resp = proxy
+        .call(
+            client_ip,
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/hello?x=1").body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(lines.lock().unwrap().is_empty());
+
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("10.0.0.1 - - ["));
+    assert!(lines[0].ends_with("] \"GET /hello?x=1 HTTP/1.1\" 200 11"));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_json_log_format(ctx: &mut HttpTestContext) {
+    hello_handler(ctx);
+    let (proxy, lines) = proxy(AccessLogFormat::Json);
+
+    let resp = proxy
+        .call(
+            "10.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/hello")
+                .header("user-agent", "test \"agent\"")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].contains("\"status\":200,\"bytes\":11,"));
+    assert!(lines[0].contains(&format!(
+        "\"forwarded_uri\":\"http://127.0.0.1:{}/hello\"",
+        ctx.port
+    )));
+    assert!(lines[0].contains(&format!("\"upstream_addr\":\"127.0.0.1:{}\"", ctx.port)));
+    assert!(lines[0].contains("\"user_agent\":\"test \\\"agent\\\"\""));
+    assert!(lines[0].contains("\"upgrade\":null"));
+}
+
+#[tokio::test]
+async fn test_logs_status_of_failed_requests() {
+    let (proxy, lines) = proxy(AccessLogFormat::Common);
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let result = proxy
+        .call(
+            "10.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", port),
+            Request::get("/down").body(Body::empty()).unwrap(),
+        )
+        .await;
+    assert!(result.is_err());
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].ends_with("\"GET /down HTTP/1.1\" 502 -"));
+}
+
+#[test]
+fn test_formats_timestamps() {
+    let record = AccessLogRecord {
+        timestamp: UNIX_EPOCH + Duration::from_secs(784_111_777),
+        client_ip: "10.0.0.1".parse().unwrap(),
+        method: Method::GET,
+        uri: "/".to_string(),
+        forwarded_uri: "http://upstream/".to_string(),
+        version: Version::HTTP_11,
+        status: StatusCode::OK,
+        bytes: 0,
+        upstream_latency: Duration::from_micros(1500),
+        upstream_addr: "upstream".to_string(),
+        upgrade: None,
+        referer: None,
+        user_agent: None,
+    };
+
+    assert!(record
+        .format(AccessLogFormat::Common)
+        .contains("[06/Nov/1994:08:49:37 +0000]"));
+    let json = record.format(AccessLogFormat::Json);
+    assert!(json.starts_with("{\"timestamp\":\"1994-11-06T08:49:37Z\",\"client_ip\":\"10.0.0.1\""));
+    assert!(json.contains("\"upstream_latency_ms\":1.5,"));
+}
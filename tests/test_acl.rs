@@ -0,0 +1,100 @@
+use hyper::header::HeaderMap;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::acl::{AccessControl, IpNet, IpTrie};
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::IpAddr;
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn ip(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+}
+
+fn net(net: &str) -> IpNet {
+    net.parse().unwrap()
+}
+
+#[test]
+fn test_parses_networks() {
+    assert_eq!(net("10.0.0.0/8").prefix(), 8);
+    assert_eq!(net("2001:db8::1").prefix(), 128);
+    assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    assert!("10.0.0/8".parse::<IpNet>().is_err());
+
+    assert!(net("10.0.0.0/8").contains(ip("10.255.0.1")));
+    assert!(!net("10.0.0.0/8").contains(ip("11.0.0.1")));
+    assert!(net("0.0.0.0/0").contains(ip("::ffff:192.0.2.1")));
+    assert!(!net("0.0.0.0/0").contains(ip("2001:db8::1")));
+}
+
+#[test]
+fn test_matches_longest_prefix() {
+    let trie = vec![
+        (net("10.0.0.0/8"), "wide"),
+        (net("10.1.0.0/16"), "narrow"),
+        (net("10.1.2.3"), "host"),
+        (net("2001:db8::/32"), "v6"),
+        (net("::ffff:192.0.2.0/120"), "mapped"),
+    ]
+    .into_iter()
+    .collect::<IpTrie<_>>();
+
+    assert_eq!(trie.longest_match(ip("10.9.9.9")), Some(&"wide"));
+    assert_eq!(trie.longest_match(ip("10.1.9.9")), Some(&"narrow"));
+    assert_eq!(trie.longest_match(ip("10.1.2.3")), Some(&"host"));
+    assert_eq!(trie.longest_match(ip("2001:db8:1::1")), Some(&"v6"));
+    assert_eq!(trie.longest_match(ip("192.0.2.1")), Some(&"mapped"));
+    assert_eq!(trie.longest_match(ip("::ffff:10.1.2.3")), Some(&"host"));
+    assert_eq!(trie.longest_match(ip("11.0.0.1")), None);
+}
+
+#[test]
+fn test_checks_forwarded_client_behind_trusted_proxy() {
+    let acl = AccessControl::new()
+        .allow(net("203.0.113.0/24"))
+        .trusted_proxy(net("10.0.0.0/8"));
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "x-forwarded-for",
+        "198.51.100.1, 203.0.113.7, 10.0.0.2".parse().unwrap(),
+    );
+
+    assert_eq!(acl.client_ip(ip("10.0.0.1"), &headers), ip("203.0.113.7"));
+    assert!(acl.allows(ip("10.0.0.1"), &headers));
+    // The header is ignored when it does not come from a trusted proxy
+    assert!(!acl.allows(ip("192.0.2.1"), &headers));
+    assert!(acl.allows(ip("203.0.113.9"), &headers));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_answers_denied_clients_with_forbidden(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move { Ok(Response::new(Body::from("upstream"))) })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_access_control(
+        AccessControl::new()
+            .deny(net("192.0.2.0/24"))
+            .forbidden_body("text/plain", "go away"),
+    );
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    let send = |client_ip: &str| {
+        proxy.call(
+            ip(client_ip),
+            &upstream,
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+    };
+
+    let resp = send("192.0.2.1").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(resp.headers()["content-type"], "text/plain");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "go away");
+
+    let resp = send("198.51.100.1").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "upstream");
+}
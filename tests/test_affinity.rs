@@ -0,0 +1,164 @@
+use hyper::header::{COOKIE, SET_COOKIE};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use hyper_reverse_proxy::affinity::SessionAffinity;
+use hyper_reverse_proxy::ReverseProxy;
+use std::sync::Arc;
+use test_context::test_context;
+use test_context::AsyncTestContext;
+use tokiotest_httpserver::HttpTestContext;
+
+fn respond(ctx: &mut HttpTestContext, name: &'static str) {
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        Box::pin(async move { Ok(Response::new(Body::from(name))) })
+    }));
+}
+
+async fn send(
+    proxy: &ReverseProxy<hyper::client::HttpConnector>,
+    affinity: &SessionAffinity,
+    cookie: Option<&str>,
+) -> (StatusCode, HeaderMap, String) {
+    let mut request = Request::get("/");
+    if let Some(cookie) = cookie {
+        request = request.header(COOKIE, cookie);
+    }
+
+    let resp = proxy
+        .call_with_affinity(
+            "127.0.0.1".parse().unwrap(),
+            affinity,
+            request.body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    (status, headers, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn cookie(headers: &HeaderMap) -> String {
+    let set_cookie = headers[SET_COOKIE].to_str().unwrap();
+    assert!(set_cookie.contains("HttpOnly"));
+    set_cookie.split(';').next().unwrap().to_string()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_routes_to_affine_backend(ctx: &mut HttpTestContext) {
+    let mut other = <HttpTestContext as AsyncTestContext>::setup().await;
+    respond(ctx, "first");
+    respond(ctx, "first");
+    respond(&mut other, "second");
+    let proxy = ReverseProxy::new(hyper::Client::new());
+    let affinity = SessionAffinity::new(
+        [
+            format!("http://127.0.0.1:{}", ctx.port),
+            format!("http://127.0.0.1:{}", other.port),
+        ],
+        b"secret",
+    );
+
+    let (_, headers, body) = send(&proxy, &affinity, None).await;
+    assert_eq!(body, "first");
+    let cookie = cookie(&headers);
+    assert!(!cookie.contains("127.0.0.1"));
+
+    // Without the cookie the next request would go to the second backend
+    let (_, headers, body) = send(&proxy, &affinity, Some(&cookie)).await;
+    assert_eq!(body, "first");
+    assert!(!headers.contains_key(SET_COOKIE));
+
+    let (_, headers, body) = send(&proxy, &affinity, Some("proxy_affinity=forged")).await;
+    assert_eq!(body, "second");
+    assert!(headers.contains_key(SET_COOKIE));
+
+    AsyncTestContext::teardown(other).await;
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_moves_away_from_unhealthy_backend(ctx: &mut HttpTestContext) {
+    respond(ctx, "healthy");
+    respond(ctx, "healthy");
+    let down = "http://127.0.0.1:1".to_string();
+    let proxy = ReverseProxy::new(hyper::Client::new());
+    let affinity = SessionAffinity::new(
+        [down.clone(), format!("http://127.0.0.1:{}", ctx.port)],
+        b"secret",
+    );
+
+    // The first request goes to the backend that is down, which marks it unhealthy, and is
+    // retried on the healthy one
+    let (_, headers, body) = send(&proxy, &affinity, None).await;
+    assert_eq!(body, "healthy");
+    let cookie = cookie(&headers);
+
+    affinity.mark_unhealthy(&format!("http://127.0.0.1:{}", ctx.port));
+    let (status, _, _) = send(&proxy, &affinity, Some(&cookie)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    affinity.mark_healthy(&format!("http://127.0.0.1:{}", ctx.port));
+    let (_, headers, body) = send(&proxy, &affinity, Some(&cookie)).await;
+    assert_eq!(body, "healthy");
+    assert!(!headers.contains_key(SET_COOKIE));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_repins_client_when_backend_goes_down(ctx: &mut HttpTestContext) {
+    let mut other = <HttpTestContext as AsyncTestContext>::setup().await;
+    respond(&mut other, "first");
+    respond(ctx, "second");
+    let affinity = SessionAffinity::new(
+        [
+            format!("http://127.0.0.1:{}", other.port),
+            format!("http://127.0.0.1:{}", ctx.port),
+        ],
+        b"secret",
+    );
+
+    let proxy = ReverseProxy::new(hyper::Client::new());
+    let (_, headers, body) = send(&proxy, &affinity, None).await;
+    assert_eq!(body, "first");
+    let pinned = cookie(&headers);
+
+    AsyncTestContext::teardown(other).await;
+
+    // A new client, so that no pooled connection to the stopped backend is reused
+    let proxy = ReverseProxy::new(hyper::Client::new());
+    let (status, headers, body) = send(&proxy, &affinity, Some(&pinned)).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "second"));
+    assert_ne!(cookie(&headers), pinned);
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_does_not_forward_affinity_cookie(ctx: &mut HttpTestContext) {
+    respond(ctx, "first");
+    for _ in 0..2 {
+        ctx.add(Arc::new(|req: Request<Body>| {
+            Box::pin(async move {
+                let cookies = req
+                    .headers()
+                    .get_all(COOKIE)
+                    .iter()
+                    .map(|value| value.to_str().unwrap().to_string())
+                    .collect::<Vec<_>>();
+                Ok(Response::new(Body::from(cookies.join("\n"))))
+            })
+        }));
+    }
+    let proxy = ReverseProxy::new(hyper::Client::new());
+    let affinity = SessionAffinity::new([format!("http://127.0.0.1:{}", ctx.port)], b"secret");
+
+    let (_, headers, _) = send(&proxy, &affinity, None).await;
+    let pinned = cookie(&headers);
+
+    let (_, _, body) = send(&proxy, &affinity, Some(&format!("a=1; {}; b=2", pinned))).await;
+    assert_eq!(body, "a=1; b=2");
+
+    let (_, _, body) = send(&proxy, &affinity, Some(&pinned)).await;
+    assert_eq!(body, "");
+}
@@ -0,0 +1,253 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hyper::header::{HeaderName, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::auth::{Auth, Htpasswd, Identity, JwtValidator};
+use hyper_reverse_proxy::ReverseProxy;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+// SubjectPublicKeyInfo prefix of an uncompressed P-256 public key
+const P256_SPKI_PREFIX: &str = "3059301306072a8648ce3d020106082a8648ce3d030107034200";
+
+fn subject_header() -> HeaderName {
+    HeaderName::from_static("x-auth-subject")
+}
+
+fn request(authorization: Option<String>) -> Request<Body> {
+    let mut request = Request::get("/");
+    if let Some(authorization) = authorization {
+        request = request.header(AUTHORIZATION, authorization);
+    }
+    request
+        .header("x-auth-subject", "forged")
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn token<F: Fn(&[u8]) -> Vec<u8>>(alg: &str, claims: Value, sign: F) -> String {
+    let header = URL_SAFE_NO_PAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string());
+    let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+    let message = format!("{}.{}", header, claims);
+    let signature = URL_SAFE_NO_PAD.encode(sign(message.as_bytes()));
+    format!("{}.{}", message, signature)
+}
+
+fn bearer(token: &str) -> Option<String> {
+    Some(format!("Bearer {}", token))
+}
+
+fn hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_checks_basic_credentials() {
+    let htpasswd = Htpasswd::parse(&format!(
+        "# users\nalice:{}\nbob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\ncarol:$apr1$unsupported\n",
+        bcrypt::hash("wonderland", 4).unwrap()
+    ));
+    let auth = Auth::new()
+        .basic(htpasswd)
+        .realm("staff")
+        .forward_claim("sub", subject_header());
+    let basic = |credentials: &str| Some(format!("Basic {}", STANDARD.encode(credentials)));
+
+    for (user, password) in [("alice", "wonderland"), ("bob", "password")] {
+        let mut request = request(basic(&format!("{}:{}", user, password)));
+        assert!(auth.check(&mut request).await.is_none());
+        assert_eq!(request.headers()["x-auth-subject"], user);
+        assert_eq!(
+            request.extensions().get::<Identity>().unwrap().sub(),
+            Some(user)
+        );
+        assert!(request.headers().contains_key(AUTHORIZATION));
+    }
+
+    for credentials in ["alice:wrong", "carol:unsupported", "nobody:password"] {
+        let response = auth.check(&mut request(basic(credentials))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            "Basic realm=\"staff\", charset=\"UTF-8\""
+        );
+    }
+
+    let mut request = request(None);
+    assert!(auth.check(&mut request).await.is_some());
+    assert!(!request.headers().contains_key("x-auth-subject"));
+}
+
+#[test]
+fn test_verifies_unknown_users_at_highest_cost() {
+    let htpasswd = Htpasswd::parse(&format!(
+        "alice:{}\nbob:{}\ncarol:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+        bcrypt::hash("wonderland", 5).unwrap(),
+        bcrypt::hash("builder", 4).unwrap(),
+    ));
+    assert!(format!("{:?}", htpasswd).contains("dummy_cost: Some(5)"));
+    assert!(!htpasswd.verify("nobody", "wrong"));
+
+    let htpasswd = Htpasswd::parse("carol:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=");
+    assert!(format!("{:?}", htpasswd).contains("dummy_cost: None"));
+}
+
+#[tokio::test]
+async fn test_checks_api_keys() {
+    let auth = Auth::new()
+        .api_key("key-1", "ci")
+        .forward_claim("sub", subject_header())
+        .strip_credentials(true);
+
+    let mut request = request(bearer("key-1"));
+    assert!(auth.check(&mut request).await.is_none());
+    assert_eq!(request.headers()["x-auth-subject"], "ci");
+    assert!(!request.headers().contains_key(AUTHORIZATION));
+
+    let response = auth
+        .check(&mut self::request(bearer("key-2")))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()[WWW_AUTHENTICATE],
+        "Bearer realm=\"proxy\", error=\"invalid_token\""
+    );
+
+    let response = auth.check(&mut self::request(None)).await.unwrap();
+    assert_eq!(
+        response.headers()[WWW_AUTHENTICATE],
+        "Bearer realm=\"proxy\""
+    );
+}
+
+#[tokio::test]
+async fn test_validates_jwt() {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    let ec =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+    let mut spki = hex(P256_SPKI_PREFIX);
+    spki.extend_from_slice(ec.public_key().as_ref());
+    let pem = format!(
+        "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+        STANDARD.encode(spki)
+    );
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let ed = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let jwks = json!({ "keys": [
+        { "kty": "RSA", "use": "enc", "n": "AQAB", "e": "AQAB" },
+        { "kty": "OKP", "crv": "Ed25519", "x": URL_SAFE_NO_PAD.encode(ed.public_key()) },
+    ] });
+
+    let auth = Auth::new()
+        .jwt(
+            JwtValidator::new()
+                .pem(None, &pem)
+                .unwrap()
+                .jwks(&jwks.to_string())
+                .unwrap()
+                .secret(None, b"secret")
+                .issuer("https://login.example")
+                .audience("api"),
+        )
+        .forward_claim("sub", subject_header())
+        .forward_claim("roles", HeaderName::from_static("x-auth-roles"));
+    let claims = |exp: u64, aud: &str| {
+        json!({
+            "sub": "user-1",
+            "roles": ["admin"],
+            "iss": "https://login.example",
+            "aud": [aud],
+            "exp": exp,
+        })
+    };
+    let es256 = |claims| {
+        token("ES256", claims, |m| {
+            ec.sign(&rng, m).unwrap().as_ref().to_vec()
+        })
+    };
+    let eddsa = |claims| token("EdDSA", claims, |m| ed.sign(m).as_ref().to_vec());
+    let hs256 = |claims, secret: &[u8]| {
+        token("HS256", claims, |m| {
+            let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret);
+            ring::hmac::sign(&key, m).as_ref().to_vec()
+        })
+    };
+
+    let valid = now() + 60;
+    for token in [
+        es256(claims(valid, "api")),
+        eddsa(claims(valid, "api")),
+        hs256(claims(valid, "api"), b"secret"),
+    ] {
+        let mut request = request(bearer(&token));
+        assert!(auth.check(&mut request).await.is_none());
+        assert_eq!(request.headers()["x-auth-subject"], "user-1");
+        assert_eq!(request.headers()["x-auth-roles"], "[\"admin\"]");
+    }
+
+    for token in [
+        es256(claims(now() - 60, "api")),
+        eddsa(claims(valid, "other")),
+        hs256(claims(valid, "api"), b"wrong secret"),
+        token("none", claims(valid, "api"), |_| Vec::new()),
+        hs256(
+            json!({ "iss": "https://login.example", "aud": ["api"] }),
+            b"secret",
+        ),
+        "not.a.token".to_string(),
+    ] {
+        let response = auth.check(&mut request(bearer(&token))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_authenticates_before_forwarding(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let subject = req.headers()["x-auth-subject"].clone();
+            Ok(Response::new(Body::from(subject.as_bytes().to_vec())))
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_auth(
+        Auth::new()
+            .api_key("key-1", "ci")
+            .forward_claim("sub", subject_header()),
+    );
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+
+    let resp = proxy
+        .call("127.0.0.1".parse().unwrap(), &upstream, request(None))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &upstream,
+            request(bearer("key-1")),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "ci");
+}
@@ -0,0 +1,167 @@
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::buffer::{BufferedBody, RequestBuffering};
+use hyper_reverse_proxy::ReverseProxy;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn echo_handler(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .unwrap_or_default();
+            Ok(Response::new(Body::from(body)))
+        })
+    }));
+}
+
+fn chunked(chunks: usize, size: usize) -> Body {
+    Body::wrap_stream(futures::stream::iter(
+        (0..chunks).map(move |_| Ok::<_, Infallible>(Bytes::from(vec![b'a'; size]))),
+    ))
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_hook_inspects_buffered_body(ctx: &mut HttpTestContext) {
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_request_buffering(RequestBuffering::new())
+        .with_request_hook(|_client_ip: IpAddr, req: &mut Request<Body>| {
+            let body = req.extensions().get::<BufferedBody>().unwrap();
+
+            if body.as_bytes().unwrap().as_ref() == b"forbidden" {
+                Some(
+                    Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            } else {
+                None
+            }
+        });
+
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/").body(Body::from("forbidden")).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    echo_handler(ctx);
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/").body(Body::from("allowed")).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+        "allowed"
+    );
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_large_body_spills_to_disk(ctx: &mut HttpTestContext) {
+    echo_handler(ctx);
+
+    let temp_dir = std::env::temp_dir().join(format!("hrp-test-buffer-{}", ctx.port));
+    std::fs::create_dir_all(&temp_dir).unwrap();
+
+    let spilled = Arc::new(Mutex::new(None));
+    let hook_spilled = spilled.clone();
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_request_buffering(
+            RequestBuffering::new()
+                .memory_threshold(1024)
+                .temp_dir(&temp_dir),
+        )
+        .with_request_hook(move |_client_ip: IpAddr, req: &mut Request<Body>| {
+            let body = req.extensions().get::<BufferedBody>().unwrap();
+            *hook_spilled.lock().unwrap() = Some((body.as_bytes().is_none(), body.len()));
+            None
+        });
+
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/").body(chunked(8, 512)).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        hyper::body::to_bytes(resp.into_body()).await.unwrap().len(),
+        4096
+    );
+    assert_eq!(*spilled.lock().unwrap(), Some((true, 4096)));
+    assert!(removed(&temp_dir).await);
+
+    std::fs::remove_dir(&temp_dir).unwrap();
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_rejects_bodies_over_max_size(ctx: &mut HttpTestContext) {
+    let temp_dir = std::env::temp_dir().join(format!("hrp-test-buffer-{}", ctx.port));
+    std::fs::create_dir_all(&temp_dir).unwrap();
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_request_buffering(
+        RequestBuffering::new()
+            .memory_threshold(1024)
+            .max_size(2048)
+            .temp_dir(&temp_dir),
+    );
+
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/").body(chunked(8, 512)).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(removed(&temp_dir).await);
+
+    std::fs::remove_dir(&temp_dir).unwrap();
+}
+
+// Spilled files are removed in the background once their body is dropped
+async fn removed(temp_dir: &std::path::Path) -> bool {
+    for _ in 0..100 {
+        if std::fs::read_dir(temp_dir).unwrap().count() == 0 {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_buffered_body_replays() {
+    let temp_dir = std::env::temp_dir();
+    let buffered = RequestBuffering::new()
+        .memory_threshold(16)
+        .temp_dir(&temp_dir)
+        .buffer(chunked(4, 16))
+        .await
+        .unwrap();
+
+    assert_eq!(buffered.len(), 64);
+    for _ in 0..2 {
+        let replayed = hyper::body::to_bytes(buffered.to_body()).await.unwrap();
+        assert_eq!(replayed, buffered.to_bytes().await.unwrap());
+        assert_eq!(replayed.len(), 64);
+    }
+}
@@ -0,0 +1,484 @@
+use hyper::body::Bytes;
+use hyper::header::{AGE, CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper_reverse_proxy::auth::Auth;
+use hyper_reverse_proxy::buffer::RequestBuffering;
+use hyper_reverse_proxy::cache::Cache;
+use hyper_reverse_proxy::ReverseProxy;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn respond(
+    ctx: &mut HttpTestContext,
+    headers: &'static [(&'static str, &'static str)],
+    body: &'static str,
+) {
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        Box::pin(async move {
+            let mut response = Response::builder();
+            for (name, value) in headers {
+                response = response.header(*name, *value);
+            }
+            Ok(response.body(Body::from(body)).unwrap())
+        })
+    }));
+}
+
+async fn request(
+    proxy: &ReverseProxy<hyper::client::HttpConnector>,
+    ctx: &HttpTestContext,
+    request: Request<Body>,
+) -> (StatusCode, hyper::HeaderMap, String) {
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            request,
+        )
+        .await
+        .unwrap();
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    (status, headers, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn get(path: &str) -> Request<Body> {
+    Request::get(path).body(Body::empty()).unwrap()
+}
+
+fn proxy() -> ReverseProxy<hyper::client::HttpConnector> {
+    ReverseProxy::new(hyper::Client::new()).with_cache(Cache::new())
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_serves_fresh_response_from_cache(ctx: &mut HttpTestContext) {
+    respond(ctx, &[("cache-control", "max-age=60")], "cached");
+    let proxy = proxy();
+
+    let (status, _, body) = request(&proxy, ctx, get("/fresh")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "cached"));
+
+    // The upstream has no handler left and would answer with a 500
+    let (status, headers, body) = request(&proxy, ctx, get("/fresh")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "cached"));
+    assert!(headers.contains_key(AGE));
+
+    let (status, _, body) = request(
+        &proxy,
+        ctx,
+        Request::head("/fresh").body(Body::empty()).unwrap(),
+    )
+    .await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, ""));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_revalidates_stale_response(ctx: &mut HttpTestContext) {
+    respond(
+        ctx,
+        &[("cache-control", "max-age=0"), ("etag", "\"v1\"")],
+        "original",
+    );
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            assert_eq!(req.headers()[IF_NONE_MATCH], "\"v1\"");
+            Ok(Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(ETAG, "\"v1\"")
+                .body(Body::empty())
+                .unwrap())
+        })
+    }));
+    let proxy = proxy();
+
+    request(&proxy, ctx, get("/stale")).await;
+    let (status, headers, body) = request(&proxy, ctx, get("/stale")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "original"));
+    assert_eq!(headers[ETAG], "\"v1\"");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_answers_client_conditional_request(ctx: &mut HttpTestContext) {
+    respond(
+        ctx,
+        &[("cache-control", "max-age=60"), ("etag", "\"v1\"")],
+        "body",
+    );
+    let proxy = proxy();
+
+    request(&proxy, ctx, get("/conditional")).await;
+    let (status, _, body) = request(
+        &proxy,
+        ctx,
+        Request::get("/conditional")
+            .header(IF_NONE_MATCH, "\"v1\"")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!((status, body.as_str()), (StatusCode::NOT_MODIFIED, ""));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_does_not_store_no_store(ctx: &mut HttpTestContext) {
+    respond(ctx, &[("cache-control", "no-store")], "first");
+    respond(ctx, &[("cache-control", "no-store")], "second");
+    let proxy = proxy();
+
+    assert_eq!(request(&proxy, ctx, get("/no-store")).await.2, "first");
+    assert_eq!(request(&proxy, ctx, get("/no-store")).await.2, "second");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_does_not_store_authenticated_responses(ctx: &mut HttpTestContext) {
+    respond(ctx, &[("cache-control", "max-age=60")], "private");
+    respond(ctx, &[("cache-control", "max-age=60")], "public");
+    // A cache shared by an authenticated and a public route
+    let cache = Cache::new();
+    let authenticated = ReverseProxy::new(hyper::Client::new())
+        .with_auth(Auth::new().api_key("key-1", "ci").strip_credentials(true))
+        .with_cache(cache.clone());
+    let public = ReverseProxy::new(hyper::Client::new()).with_cache(cache);
+
+    let request_with_key = Request::get("/report")
+        .header("authorization", "Bearer key-1")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        request(&authenticated, ctx, request_with_key).await.2,
+        "private"
+    );
+    assert_eq!(request(&public, ctx, get("/report")).await.2, "public");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_keeps_variants_apart(ctx: &mut HttpTestContext) {
+    respond(
+        ctx,
+        &[("cache-control", "max-age=60"), ("vary", "accept-language")],
+        "hello",
+    );
+    respond(
+        ctx,
+        &[("cache-control", "max-age=60"), ("vary", "accept-language")],
+        "bonjour",
+    );
+    let proxy = proxy();
+    let get_lang = |lang: &str| {
+        Request::get("/greeting")
+            .header("accept-language", lang)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    assert_eq!(request(&proxy, ctx, get_lang("en")).await.2, "hello");
+    assert_eq!(request(&proxy, ctx, get_lang("fr")).await.2, "bonjour");
+
+    let (_, headers, body) = request(&proxy, ctx, get_lang("en")).await;
+    assert_eq!(body, "hello");
+    assert_eq!(headers[VARY], "accept-language");
+    assert_eq!(request(&proxy, ctx, get_lang("fr")).await.2, "bonjour");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_unsafe_request_invalidates(ctx: &mut HttpTestContext) {
+    respond(ctx, &[("cache-control", "max-age=60")], "before");
+    respond(ctx, &[], "posted");
+    respond(ctx, &[("cache-control", "max-age=60")], "after");
+    let proxy = proxy();
+
+    assert_eq!(request(&proxy, ctx, get("/resource")).await.2, "before");
+    let post = Request::builder()
+        .method(Method::POST)
+        .uri("/resource")
+        .body(Body::from("update"))
+        .unwrap();
+    assert_eq!(request(&proxy, ctx, post).await.2, "posted");
+    assert_eq!(request(&proxy, ctx, get("/resource")).await.2, "after");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_serves_stale_if_error(ctx: &mut HttpTestContext) {
+    respond(
+        ctx,
+        &[
+            ("cache-control", "max-age=0, stale-if-error=60"),
+            ("etag", "\"v1\""),
+        ],
+        "stale",
+    );
+    let proxy = proxy();
+
+    request(&proxy, ctx, get("/unstable")).await;
+
+    // The upstream answers the revalidation with a 500
+    let (status, headers, body) = request(&proxy, ctx, get("/unstable")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "stale"));
+    assert_eq!(headers[CACHE_CONTROL], "max-age=0, stale-if-error=60");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_coalesces_concurrent_misses(ctx: &mut HttpTestContext) {
+    respond(ctx, &[("cache-control", "max-age=60")], "shared");
+    let proxy = proxy();
+
+    // Only one handler is registered, a second upstream request would get a 500
+    let (first, second) = futures::join!(
+        request(&proxy, ctx, get("/popular")),
+        request(&proxy, ctx, get("/popular")),
+    );
+    assert_eq!((first.0, first.2.as_str()), (StatusCode::OK, "shared"));
+    assert_eq!((second.0, second.2.as_str()), (StatusCode::OK, "shared"));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_does_not_coalesce_uncacheable_responses(ctx: &mut HttpTestContext) {
+    respond(ctx, &[("cache-control", "private")], "first");
+    respond(ctx, &[("cache-control", "private")], "second");
+    let proxy = proxy();
+
+    let (first, second) = futures::join!(
+        request(&proxy, ctx, get("/private")),
+        request(&proxy, ctx, get("/private")),
+    );
+    assert_eq!(first.2, "first");
+    assert_eq!(second.2, "second");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_caches_with_retried_buffering(ctx: &mut HttpTestContext) {
+    respond(ctx, &[("cache-control", "max-age=60")], "cached");
+    let proxy = proxy().with_request_buffering(RequestBuffering::new().retries(2));
+
+    let (status, _, body) = request(&proxy, ctx, get("/retried")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "cached"));
+
+    let (status, headers, body) = request(&proxy, ctx, get("/retried")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "cached"));
+    assert!(headers.contains_key(AGE));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_stops_coalescing_large_responses(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data(Bytes::from("large-body")).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                sender.send_data(Bytes::from("-end")).await.unwrap();
+            });
+            Ok(Response::builder()
+                .header("cache-control", "max-age=60")
+                .body(body)
+                .unwrap())
+        })
+    }));
+    respond(ctx, &[("cache-control", "max-age=60")], "own");
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_cache(Cache::new().max_object_size(4));
+
+    // The second request arrives once the body went over the limit and no longer joins
+    let (first, second) = futures::join!(request(&proxy, ctx, get("/large")), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        request(&proxy, ctx, get("/large")).await
+    });
+    assert_eq!(first.2, "large-body-end");
+    assert_eq!(second.2, "own");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_only_if_cached_does_not_serve_stale_responses(ctx: &mut HttpTestContext) {
+    respond(
+        ctx,
+        &[("cache-control", "max-age=0, must-revalidate")],
+        "stale",
+    );
+    let proxy = proxy();
+
+    request(&proxy, ctx, get("/must-revalidate")).await;
+
+    let only_if_cached = Request::get("/must-revalidate")
+        .header(CACHE_CONTROL, "only-if-cached")
+        .body(Body::empty())
+        .unwrap();
+    let (status, _, _) = request(&proxy, ctx, only_if_cached).await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+}
+
+// Starts an upstream answering slowly with a response that may be served stale while it is
+// revalidated, and counting the requests it receives
+async fn slow_upstream(requests: Arc<AtomicUsize>) -> SocketAddr {
+    let make_svc = make_service_fn(move |_conn: &AddrStream| {
+        let requests = requests.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header(CACHE_CONTROL, "max-age=0, stale-while-revalidate=60")
+                            .body(Body::from("swr"))
+                            .unwrap(),
+                    )
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    addr
+}
+
+#[tokio::test]
+async fn test_revalidates_in_background_once() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let addr = slow_upstream(requests.clone()).await;
+    let proxy = proxy();
+    let send = || async {
+        let resp = proxy
+            .call(
+                "127.0.0.1".parse().unwrap(),
+                &format!("http://{}", addr),
+                get("/swr"),
+            )
+            .await
+            .unwrap();
+        hyper::body::to_bytes(resp.into_body()).await.unwrap()
+    };
+
+    send().await;
+    for _ in 0..3 {
+        assert_eq!(send().await, "swr");
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_does_not_wait_for_stalled_clients(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for _ in 0..32 {
+                    sender.send_data(Bytes::from("chunk")).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            });
+            Ok(Response::builder()
+                .header("cache-control", "max-age=60")
+                .body(body)
+                .unwrap())
+        })
+    }));
+    let proxy = proxy();
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    let call = || proxy.call("127.0.0.1".parse().unwrap(), &upstream, get("/stalled"));
+
+    // The leading client never reads its body
+    let stalled = call().await.unwrap();
+    let joined = call().await.unwrap();
+    let body = tokio::time::timeout(
+        Duration::from_secs(10),
+        hyper::body::to_bytes(joined.into_body()),
+    )
+    .await
+    .expect("the joined client waited for the stalled one")
+    .unwrap();
+    assert_eq!(body, "chunk".repeat(32));
+
+    assert!(hyper::body::to_bytes(stalled.into_body()).await.is_err());
+}
+
+// Starts an upstream answering slowly with a response varying on `Accept-Language`, and
+// reporting the largest number of requests it handled at the same time
+async fn varying_upstream(concurrency: Arc<(AtomicUsize, AtomicUsize)>) -> SocketAddr {
+    let make_svc = make_service_fn(move |_conn: &AddrStream| {
+        let concurrency = concurrency.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let concurrency = concurrency.clone();
+                async move {
+                    let current = concurrency.0.fetch_add(1, Ordering::SeqCst) + 1;
+                    concurrency.1.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    concurrency.0.fetch_sub(1, Ordering::SeqCst);
+
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header(CACHE_CONTROL, "max-age=60")
+                            .header(VARY, "accept-language")
+                            .body(Body::from(
+                                req.headers()["accept-language"].as_bytes().to_vec(),
+                            ))
+                            .unwrap(),
+                    )
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    addr
+}
+
+#[tokio::test]
+async fn test_does_not_coalesce_other_variants() {
+    let concurrency = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+    let addr = varying_upstream(concurrency.clone()).await;
+    let proxy = proxy();
+    let send = |language: &'static str| {
+        let proxy = &proxy;
+        async move {
+            let resp = proxy
+                .call(
+                    "127.0.0.1".parse().unwrap(),
+                    &format!("http://{}", addr),
+                    Request::get("/varying")
+                        .header("accept-language", language)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            hyper::body::to_bytes(resp.into_body()).await.unwrap()
+        }
+    };
+
+    assert_eq!(send("en").await, "en");
+
+    // Knowing the response varies, the requests for other variants do not wait for each other
+    let (de, fr) = futures::join!(send("de"), send("fr"));
+    assert_eq!((de, fr), (Bytes::from("de"), Bytes::from("fr")));
+    assert_eq!(concurrency.1.load(Ordering::SeqCst), 2);
+}
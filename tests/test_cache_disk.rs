@@ -0,0 +1,192 @@
+use hyper::body::Bytes;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use hyper_reverse_proxy::cache::{Cache, CacheStorage, CachedBody, CachedResponse, DiskStorage};
+use hyper_reverse_proxy::ReverseProxy;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn respond(ctx: &mut HttpTestContext, body: &'static str) {
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        Box::pin(async move {
+            Ok(Response::builder()
+                .header("cache-control", "max-age=60")
+                .body(Body::from(body))
+                .unwrap())
+        })
+    }));
+}
+
+async fn get(
+    proxy: &ReverseProxy<hyper::client::HttpConnector>,
+    ctx: &HttpTestContext,
+    path: &str,
+) -> (StatusCode, String) {
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get(path).body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn proxy(dir: &Path, max_size: u64) -> ReverseProxy<hyper::client::HttpConnector> {
+    let storage = DiskStorage::open(dir, max_size).unwrap();
+    ReverseProxy::new(hyper::Client::new()).with_cache(Cache::new().storage(storage))
+}
+
+fn cache_dir(name: &str, ctx: &HttpTestContext) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hrp-test-cache-{}-{}", name, ctx.port));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn objects(dir: &Path) -> usize {
+    std::fs::read_dir(dir.join("objects")).unwrap().count()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_survives_restart(ctx: &mut HttpTestContext) {
+    respond(ctx, "persisted");
+    let dir = cache_dir("restart", ctx);
+
+    let (status, body) = get(&proxy(&dir, 1024), ctx, "/asset").await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "persisted"));
+
+    // The upstream has no handler left and would answer with a 500
+    let (status, body) = get(&proxy(&dir, 1024), ctx, "/asset").await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "persisted"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_stores_identical_bodies_once(ctx: &mut HttpTestContext) {
+    respond(ctx, "same");
+    respond(ctx, "same");
+    let dir = cache_dir("dedup", ctx);
+    let proxy = proxy(&dir, 1024);
+
+    get(&proxy, ctx, "/one").await;
+    get(&proxy, ctx, "/two").await;
+    assert_eq!(objects(&dir), 1);
+
+    assert_eq!(get(&proxy, ctx, "/one").await.1, "same");
+    assert_eq!(get(&proxy, ctx, "/two").await.1, "same");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_evicts_least_recently_used(ctx: &mut HttpTestContext) {
+    respond(ctx, "aaaa");
+    respond(ctx, "bbbb");
+    respond(ctx, "cccc");
+    respond(ctx, "refetched");
+    let dir = cache_dir("evict", ctx);
+    let proxy = proxy(&dir, 8);
+
+    get(&proxy, ctx, "/a").await;
+    get(&proxy, ctx, "/b").await;
+    // Using /a makes /b the least recently used response
+    assert_eq!(get(&proxy, ctx, "/a").await.1, "aaaa");
+    get(&proxy, ctx, "/c").await;
+    assert_eq!(objects(&dir), 2);
+
+    assert_eq!(get(&proxy, ctx, "/a").await.1, "aaaa");
+    assert_eq!(get(&proxy, ctx, "/c").await.1, "cccc");
+    assert_eq!(get(&proxy, ctx, "/b").await.1, "refetched");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_replays_journal_after_restart(ctx: &mut HttpTestContext) {
+    respond(ctx, "aaaa");
+    respond(ctx, "bbbb");
+    respond(ctx, "refetched");
+    let dir = cache_dir("journal", ctx);
+
+    {
+        let proxy = proxy(&dir, 4);
+        get(&proxy, ctx, "/a").await;
+        // Evicts /a, both changes only being in the journal
+        get(&proxy, ctx, "/b").await;
+    }
+    assert!(std::fs::metadata(dir.join("journal.jsonl")).unwrap().len() > 0);
+
+    let proxy = proxy(&dir, 1024);
+    assert_eq!(
+        std::fs::metadata(dir.join("journal.jsonl")).unwrap().len(),
+        0
+    );
+    assert_eq!(get(&proxy, ctx, "/b").await.1, "bbbb");
+    assert_eq!(get(&proxy, ctx, "/a").await.1, "refetched");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_keeps_usage_order_after_restart(ctx: &mut HttpTestContext) {
+    respond(ctx, "aaaa");
+    respond(ctx, "bbbb");
+    respond(ctx, "cccc");
+    respond(ctx, "refetched");
+    let dir = cache_dir("usage", ctx);
+
+    {
+        let proxy = proxy(&dir, 8);
+        get(&proxy, ctx, "/a").await;
+        get(&proxy, ctx, "/b").await;
+        assert_eq!(get(&proxy, ctx, "/a").await.1, "aaaa");
+    }
+
+    // /b is still the least recently used response after the restart
+    let proxy = proxy(&dir, 8);
+    get(&proxy, ctx, "/c").await;
+    assert_eq!(get(&proxy, ctx, "/a").await.1, "aaaa");
+    assert_eq!(get(&proxy, ctx, "/b").await.1, "refetched");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_streams_bodies_from_disk() {
+    let dir = std::env::temp_dir().join(format!("hrp-test-cache-stream-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let storage = DiskStorage::open(&dir, 1024).unwrap();
+
+    let response = CachedResponse {
+        status: StatusCode::OK,
+        headers: HeaderMap::new(),
+        body: CachedBody::Memory(Bytes::from("streamed")),
+        response_time: SystemTime::now(),
+        initial_age: Duration::default(),
+        vary: Vec::new(),
+    };
+    storage.insert("key", response).await;
+
+    let cached = storage.get("key", &HeaderMap::new()).await.unwrap();
+    match cached.body {
+        CachedBody::File { path, len } => {
+            assert_eq!(len, 8);
+            assert_eq!(std::fs::read(path).unwrap(), b"streamed");
+        }
+        CachedBody::Memory(_) => panic!("body read into memory"),
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
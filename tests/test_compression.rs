@@ -0,0 +1,110 @@
+use async_compression::tokio::bufread::GzipDecoder;
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use hyper::{Body, Request, Response};
+use hyper_reverse_proxy::compression::{Compression, Encoding};
+use hyper_reverse_proxy::ReverseProxy;
+use std::sync::Arc;
+use test_context::test_context;
+use tokio::io::AsyncReadExt;
+use tokiotest_httpserver::HttpTestContext;
+
+fn text_handler(ctx: &mut HttpTestContext, content_type: &'static str, size: usize) {
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        Box::pin(async move {
+            Ok(Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .header(CONTENT_LENGTH, size)
+                .body(Body::from("a".repeat(size)))
+                .unwrap())
+        })
+    }));
+}
+
+async fn get(ctx: &HttpTestContext, accept_encoding: &str) -> Response<Body> {
+    ReverseProxy::new(hyper::Client::new())
+        .with_compression(Compression::new())
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/")
+                .header(ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_compresses_text(ctx: &mut HttpTestContext) {
+    text_handler(ctx, "text/html; charset=utf-8", 4096);
+
+    let resp = get(ctx, "gzip, deflate").await;
+    assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+    assert_eq!(resp.headers()[VARY], "accept-encoding");
+    assert!(resp.headers().get(CONTENT_LENGTH).is_none());
+
+    let compressed = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(compressed.len() < 4096);
+
+    let mut decompressed = String::new();
+    GzipDecoder::new(&compressed[..])
+        .read_to_string(&mut decompressed)
+        .await
+        .unwrap();
+    assert_eq!(decompressed, "a".repeat(4096));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_skips_small_responses(ctx: &mut HttpTestContext) {
+    text_handler(ctx, "text/plain", 100);
+
+    let resp = get(ctx, "gzip").await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(resp.headers()[CONTENT_LENGTH], "100");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_skips_other_content_types(ctx: &mut HttpTestContext) {
+    text_handler(ctx, "image/png", 4096);
+
+    let resp = get(ctx, "gzip").await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert!(resp.headers().get(VARY).is_none());
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_skips_event_streams(ctx: &mut HttpTestContext) {
+    text_handler(ctx, "text/event-stream", 4096);
+
+    let resp = get(ctx, "gzip").await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_no_accepted_encoding(ctx: &mut HttpTestContext) {
+    text_handler(ctx, "text/plain", 4096);
+
+    let resp = get(ctx, "gzip;q=0, identity").await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(resp.headers()[VARY], "accept-encoding");
+    assert_eq!(resp.headers()[CONTENT_LENGTH], "4096");
+}
+
+#[test]
+fn test_negotiate() {
+    let compression = Compression::new();
+    let negotiate = |value: &'static str| {
+        compression.negotiate(Some(&hyper::header::HeaderValue::from_static(value)))
+    };
+
+    assert_eq!(negotiate("gzip, br"), Some(Encoding::Brotli));
+    assert_eq!(negotiate("gzip;q=1, br;q=0.5"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("*;q=0.1, br;q=0"), Some(Encoding::Zstd));
+    assert_eq!(negotiate("identity"), None);
+}
@@ -0,0 +1,148 @@
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::concurrency::{Aimd, ConcurrencyLimit};
+use hyper_reverse_proxy::ReverseProxy;
+use std::sync::Arc;
+use std::time::Duration;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+const UPSTREAM: &str = "http://127.0.0.1:8080/";
+
+#[tokio::test]
+async fn test_sheds_requests_over_the_limit() {
+    let limit = ConcurrencyLimit::new().global(2).per_upstream(1);
+
+    let first = limit.acquire(UPSTREAM).await.unwrap();
+    assert!(limit.acquire(UPSTREAM).await.is_none());
+    let other = limit.acquire("http://127.0.0.1:8081/").await.unwrap();
+    // The global limit is reached as well
+    assert!(limit.acquire("http://127.0.0.1:8082/").await.is_none());
+
+    drop(first);
+    assert!(limit.acquire(UPSTREAM).await.is_some());
+    drop(other);
+}
+
+#[tokio::test]
+async fn test_queues_requests_until_timeout() {
+    let limit = ConcurrencyLimit::new()
+        .global(1)
+        .queue(1, Duration::from_millis(200));
+
+    let first = limit.acquire(UPSTREAM).await.unwrap();
+    let waiting = {
+        let limit = limit.clone();
+        tokio::spawn(async move { limit.acquire(UPSTREAM).await.is_some() })
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    // The queue is full
+    assert!(limit.acquire(UPSTREAM).await.is_none());
+
+    drop(first);
+    assert!(waiting.await.unwrap());
+
+    let first = limit.acquire(UPSTREAM).await.unwrap();
+    assert!(limit.acquire(UPSTREAM).await.is_none());
+    drop(first);
+}
+
+#[tokio::test]
+async fn test_adapts_limit_to_upstream() {
+    let limit = ConcurrencyLimit::new()
+        .per_upstream(4)
+        .adaptive(Aimd::new(Duration::from_secs(1)).backoff(0.5));
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(4));
+
+    limit.acquire(UPSTREAM).await.unwrap().observe(false);
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(2));
+    limit.acquire(UPSTREAM).await.unwrap().observe(false);
+    limit.acquire(UPSTREAM).await.unwrap().observe(false);
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(1));
+
+    // Grows by one after a full limit of successes
+    limit.acquire(UPSTREAM).await.unwrap().observe(true);
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(2));
+    limit.acquire(UPSTREAM).await.unwrap().observe(true);
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(2));
+    limit.acquire(UPSTREAM).await.unwrap().observe(true);
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(3));
+}
+
+#[tokio::test]
+async fn test_queued_requests_do_not_hold_global_slots() {
+    let limit = ConcurrencyLimit::new()
+        .global(2)
+        .per_upstream(1)
+        .queue(1, Duration::from_millis(500));
+
+    let first = limit.acquire(UPSTREAM).await.unwrap();
+    let waiting = {
+        let limit = limit.clone();
+        tokio::spawn(async move { limit.acquire(UPSTREAM).await.is_some() })
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let other = limit.acquire("http://127.0.0.1:8081/").await;
+    assert!(other.is_some());
+    drop(other);
+
+    drop(first);
+    assert!(waiting.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_decreases_limit_once_per_burst_of_slow_responses() {
+    let limit = ConcurrencyLimit::new()
+        .per_upstream(8)
+        .adaptive(Aimd::new(Duration::from_millis(10)).backoff(0.5));
+
+    let mut permits = Vec::new();
+    for _ in 0..4 {
+        permits.push(limit.acquire(UPSTREAM).await.unwrap());
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    for permit in &permits {
+        permit.observe(true);
+    }
+    drop(permits);
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(4));
+
+    // Requests sent after the decrease shrink the limit again
+    let permit = limit.acquire(UPSTREAM).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    permit.observe(true);
+    assert_eq!(limit.upstream_limit(UPSTREAM), Some(2));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_answers_shed_requests_with_unavailable(ctx: &mut HttpTestContext) {
+    for _ in 0..2 {
+        ctx.add(Arc::new(|_req: Request<Body>| {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Response::new(Body::from("upstream")))
+            })
+        }));
+    }
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_concurrency_limit(ConcurrencyLimit::new().global(1));
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    let send = || {
+        proxy.call(
+            "127.0.0.1".parse().unwrap(),
+            &upstream,
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+    };
+
+    let (first, second) = futures::join!(send(), send());
+    assert_eq!(second.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // The slot is released once the first response body is read
+    let body = hyper::body::to_bytes(first.unwrap().into_body())
+        .await
+        .unwrap();
+    assert_eq!(body, "upstream");
+    assert_eq!(send().await.unwrap().status(), StatusCode::OK);
+}
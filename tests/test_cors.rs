@@ -0,0 +1,188 @@
+use hyper::header::HeaderValue;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper_reverse_proxy::cors::Cors;
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+fn preflight(origin: &str, method: &str) -> Request<Body> {
+    Request::options("/")
+        .header("origin", origin)
+        .header("access-control-request-method", method)
+        .header("access-control-request-headers", "content-type, x-token")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_answers_preflight_requests(ctx: &mut HttpTestContext) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let upstream_calls = calls.clone();
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        upstream_calls.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { Ok(Response::new(Body::empty())) })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_cors(
+        Cors::new()
+            .allow_origin("https://app.example.com")
+            .allow_methods([Method::GET, Method::PUT])
+            .max_age(Duration::from_secs(600)),
+    );
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+
+    let resp = proxy
+        .call(
+            CLIENT_IP,
+            &upstream,
+            preflight("https://app.example.com", "PUT"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let headers = resp.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+    assert_eq!(headers["access-control-allow-methods"], "GET, PUT");
+    assert_eq!(
+        headers["access-control-allow-headers"],
+        "content-type, x-token"
+    );
+    assert_eq!(headers["access-control-max-age"], "600");
+    assert!(headers.get_all("vary").iter().any(|vary| vary == "Origin"));
+
+    let resp = proxy
+        .call(
+            CLIENT_IP,
+            &upstream,
+            preflight("https://evil.example", "PUT"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+
+    let resp = proxy
+        .call(
+            CLIENT_IP,
+            &upstream,
+            preflight("https://app.example.com", "DELETE"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_adds_headers_to_proxied_responses(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move {
+            Ok(Response::builder()
+                .header("access-control-allow-origin", "*")
+                .header("x-request-id", "42")
+                .body(Body::from("upstream"))
+                .unwrap())
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_cors(
+        Cors::new()
+            .allow_origin("https://app.example.com")
+            .allow_credentials(true)
+            .expose_headers([hyper::header::HeaderName::from_static("x-request-id")]),
+    );
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    let send = |origin: &'static str| {
+        proxy.call(
+            CLIENT_IP,
+            &upstream,
+            Request::get("/")
+                .header("origin", origin)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let resp = send("https://app.example.com").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let headers = resp.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+    assert_eq!(headers["access-control-expose-headers"], "x-request-id");
+    assert_eq!(headers["vary"], "Origin");
+
+    // The wildcard sent by the upstream is dropped for other origins
+    let resp = send("https://evil.example").await.unwrap();
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+    assert_eq!(resp.headers()["vary"], "Origin");
+}
+
+#[test]
+fn test_matches_wildcard_and_regex_origins() {
+    let cors = Cors::new()
+        .allow_origin("https://*.example.com")
+        .allow_origin_regex(r"^http://localhost:\d+$")
+        .unwrap();
+    let allowed_origin = |origin: &'static str| {
+        let mut headers = hyper::HeaderMap::new();
+        cors.apply(Some(&HeaderValue::from_static(origin)), &mut headers);
+        headers.get("access-control-allow-origin").cloned()
+    };
+
+    assert_eq!(
+        allowed_origin("https://app.example.com").unwrap(),
+        "https://app.example.com"
+    );
+    assert!(allowed_origin("https://a.b.example.com").is_some());
+    assert!(allowed_origin("http://localhost:3000").is_some());
+    assert!(allowed_origin("https://example.com").is_none());
+    assert!(allowed_origin("http://app.example.com").is_none());
+    assert!(allowed_origin("https://app.example.com.evil").is_none());
+    assert!(allowed_origin("http://localhost:3000.evil").is_none());
+
+    // Expressions match whole origins
+    let cors = Cors::new()
+        .allow_origin_regex(r"https://.*\.example\.com")
+        .unwrap();
+    let mut headers = hyper::HeaderMap::new();
+    cors.apply(
+        Some(&HeaderValue::from_static(
+            "https://x.example.com.attacker.io",
+        )),
+        &mut headers,
+    );
+    assert!(headers.get("access-control-allow-origin").is_none());
+    cors.apply(
+        Some(&HeaderValue::from_static("https://x.example.com")),
+        &mut headers,
+    );
+    assert!(headers.get("access-control-allow-origin").is_some());
+
+    let any = Cors::new().allow_origin("*");
+    let mut headers = hyper::HeaderMap::new();
+    any.apply(
+        Some(&HeaderValue::from_static("https://a.example")),
+        &mut headers,
+    );
+    assert_eq!(headers["access-control-allow-origin"], "*");
+    assert!(headers.get("vary").is_none());
+}
+
+#[test]
+#[should_panic(expected = "credentials cannot be allowed for any origin")]
+fn test_rejects_credentials_for_any_origin() {
+    let _ = Cors::new().allow_credentials(true).allow_origin("*");
+}
@@ -0,0 +1,151 @@
+use async_compression::tokio::bufread::{BrotliDecoder, GzipEncoder};
+use futures::TryStreamExt;
+use hyper::body::Bytes;
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::compression::Compression;
+use hyper_reverse_proxy::decompression::Decompression;
+use hyper_reverse_proxy::limits::BodyLimits;
+use hyper_reverse_proxy::ReverseProxy;
+use std::sync::Arc;
+use test_context::test_context;
+use tokio::io::AsyncReadExt;
+use tokiotest_httpserver::HttpTestContext;
+
+const TEXT: &str = "hello from the upstream, hello from the upstream";
+
+// Answers with a gzip encoded body when the request accepts it
+fn gzip_handler(ctx: &mut HttpTestContext, status: StatusCode) {
+    ctx.add(Arc::new(move |req: Request<Body>| {
+        Box::pin(async move {
+            let accepts_gzip = req
+                .headers()
+                .get(ACCEPT_ENCODING)
+                .is_some_and(|value| value.to_str().unwrap().contains("gzip"));
+            assert!(accepts_gzip);
+
+            let mut compressed = Vec::new();
+            GzipEncoder::new(TEXT.as_bytes())
+                .read_to_end(&mut compressed)
+                .await
+                .unwrap();
+
+            Ok(Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "text/plain")
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from(compressed))
+                .unwrap())
+        })
+    }));
+}
+
+fn uppercase(response: &mut Response<Body>) {
+    let body = std::mem::take(response.body_mut());
+    *response.body_mut() =
+        Body::wrap_stream(body.map_ok(|chunk| Bytes::from(chunk.to_ascii_uppercase())));
+}
+
+async fn get(
+    proxy: ReverseProxy<hyper::client::HttpConnector>,
+    ctx: &HttpTestContext,
+    accept_encoding: &str,
+) -> Response<Body> {
+    proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/")
+                .header(ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_hooks_see_decoded_body(ctx: &mut HttpTestContext) {
+    gzip_handler(ctx, StatusCode::OK);
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_decompression(Decompression::new())
+        .with_response_hook(uppercase);
+
+    let resp = get(proxy, ctx, "identity").await;
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(
+        hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+        TEXT.to_uppercase()
+    );
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_passes_through_accepted_encoding(ctx: &mut HttpTestContext) {
+    gzip_handler(ctx, StatusCode::OK);
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_decompression(Decompression::new());
+
+    let resp = get(proxy, ctx, "gzip").await;
+    assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_reencodes_as_negotiated(ctx: &mut HttpTestContext) {
+    gzip_handler(ctx, StatusCode::OK);
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_decompression(Decompression::new())
+        .with_response_hook(uppercase)
+        .with_compression(Compression::new());
+
+    let resp = get(proxy, ctx, "br").await;
+    assert_eq!(resp.headers()[CONTENT_ENCODING], "br");
+
+    let compressed = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let mut decompressed = String::new();
+    BrotliDecoder::new(&compressed[..])
+        .read_to_string(&mut decompressed)
+        .await
+        .unwrap();
+    assert_eq!(decompressed, TEXT.to_uppercase());
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_decodes_error_responses(ctx: &mut HttpTestContext) {
+    gzip_handler(ctx, StatusCode::INTERNAL_SERVER_ERROR);
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_decompression(Decompression::new());
+
+    let resp = get(proxy, ctx, "identity").await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+    assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), TEXT);
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_limits_decoded_body(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move {
+            let mut compressed = Vec::new();
+            GzipEncoder::new(&[0u8; 64 * 1024][..])
+                .read_to_end(&mut compressed)
+                .await
+                .unwrap();
+            assert!(compressed.len() < 1024);
+
+            Ok(Response::builder()
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from(compressed))
+                .unwrap())
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_decompression(Decompression::new())
+        .with_limits(BodyLimits::new().max_response_body(1024));
+
+    let resp = get(proxy, ctx, "identity").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(hyper::body::to_bytes(resp.into_body()).await.is_err());
+}
@@ -0,0 +1,115 @@
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::error_pages::ErrorPages;
+use hyper_reverse_proxy::limits::BodyLimits;
+use hyper_reverse_proxy::ReverseProxy;
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+async fn into_parts(response: Response<Body>) -> (StatusCode, String, String) {
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    (
+        status,
+        content_type,
+        String::from_utf8(body.to_vec()).unwrap(),
+    )
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_renders_generated_errors_as_json(ctx: &mut HttpTestContext) {
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_limits(BodyLimits::new().max_request_body(4))
+        .with_error_pages(ErrorPages::new());
+
+    let response = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/")
+                .header(ACCEPT, "application/json, text/html;q=0.9")
+                .header("content-length", "10")
+                .body(Body::from("too large!"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        into_parts(response).await,
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "application/json".to_string(),
+            "{\"status\":413,\"error\":\"Payload Too Large\"}".to_string()
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_responds_to_failures_with_error_page() {
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_error_pages(ErrorPages::new().html(StatusCode::BAD_GATEWAY, "<p>{reason}</p>"));
+
+    let response = proxy
+        .respond(
+            "127.0.0.1".parse().unwrap(),
+            "http://127.0.0.1:1",
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await;
+
+    assert_eq!(
+        into_parts(response).await,
+        (
+            StatusCode::BAD_GATEWAY,
+            "text/html; charset=utf-8".to_string(),
+            "<p>Bad Gateway</p>".to_string()
+        )
+    );
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_intercepts_upstream_errors(ctx: &mut HttpTestContext) {
+    for _ in 0..2 {
+        ctx.add(Arc::new(|_req: Request<Body>| {
+            Box::pin(async move {
+                Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body(Body::from("internal details"))
+                    .unwrap())
+            })
+        }));
+    }
+    let pages = ErrorPages::new().html(StatusCode::NOT_FOUND, "<h1>Nothing here</h1>");
+    let send = |proxy: ReverseProxy<hyper::client::HttpConnector>| {
+        let uri = format!("http://127.0.0.1:{}", ctx.port);
+        async move {
+            let response = proxy
+                .call(
+                    "127.0.0.1".parse().unwrap(),
+                    &uri,
+                    Request::get("/missing").body(Body::empty()).unwrap(),
+                )
+                .await
+                .unwrap();
+            into_parts(response).await.2
+        }
+    };
+
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_error_pages(pages.clone());
+    assert_eq!(send(proxy).await, "internal details");
+
+    let proxy =
+        ReverseProxy::new(hyper::Client::new()).with_error_pages(pages.intercept_upstream(true));
+    assert_eq!(send(proxy).await, "<h1>Nothing here</h1>");
+}
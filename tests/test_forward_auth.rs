@@ -0,0 +1,169 @@
+use hyper::header::{HeaderName, AUTHORIZATION, LOCATION};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper_reverse_proxy::forward_auth::ForwardAuth;
+use hyper_reverse_proxy::ReverseProxy;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use test_context::test_context;
+use test_context::AsyncTestContext;
+use tokiotest_httpserver::HttpTestContext;
+
+fn proxy(auth_port: u16) -> ReverseProxy<hyper::client::HttpConnector> {
+    ReverseProxy::new(hyper::Client::new()).with_forward_auth(
+        ForwardAuth::new(format!("http://127.0.0.1:{}/verify", auth_port))
+            .request_header(AUTHORIZATION)
+            .response_header(HeaderName::from_static("x-auth-user")),
+    )
+}
+
+fn request(authorization: &str) -> Request<Body> {
+    Request::post("/orders?page=2")
+        .header(AUTHORIZATION, authorization)
+        .header("x-auth-user", "forged")
+        .header("cookie", "private")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_forwards_allowed_requests(ctx: &mut HttpTestContext) {
+    let mut auth = <HttpTestContext as AsyncTestContext>::setup().await;
+    auth.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let header = |name: &str| req.headers()[name].to_str().unwrap().to_string();
+            assert_eq!(req.method(), "GET");
+            assert_eq!(req.uri(), "/verify");
+            assert_eq!(header("x-forwarded-method"), "POST");
+            assert_eq!(header("x-forwarded-uri"), "/orders?page=2");
+            assert_eq!(header("x-forwarded-for"), "192.0.2.1");
+            assert_eq!(header("authorization"), "Bearer valid");
+            assert!(!req.headers().contains_key("cookie"));
+
+            Ok(Response::builder()
+                .header("x-auth-user", "alice")
+                .body(Body::empty())
+                .unwrap())
+        })
+    }));
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let user = req.headers()["x-auth-user"].as_bytes().to_vec();
+            Ok(Response::new(Body::from(user)))
+        })
+    }));
+
+    let resp = proxy(auth.port)
+        .call(
+            "192.0.2.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            request("Bearer valid"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "alice");
+
+    AsyncTestContext::teardown(auth).await;
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_returns_denying_response(ctx: &mut HttpTestContext) {
+    let mut auth = <HttpTestContext as AsyncTestContext>::setup().await;
+    auth.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move {
+            Ok(Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, "https://login.example/")
+                .body(Body::from("login first"))
+                .unwrap())
+        })
+    }));
+
+    let resp = proxy(auth.port)
+        .call(
+            "192.0.2.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            request("Bearer expired"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    assert_eq!(resp.headers()[LOCATION], "https://login.example/");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "login first");
+
+    AsyncTestContext::teardown(auth).await;
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_fails_closed_when_service_is_down(ctx: &mut HttpTestContext) {
+    let resp = proxy(1)
+        .call(
+            "192.0.2.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            request("Bearer valid"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+// An authorization service allowing every request with a body, recording the client ports
+async fn counting_service(ports: Arc<Mutex<HashSet<u16>>>) -> SocketAddr {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        ports.lock().unwrap().insert(conn.remote_addr().port());
+        async {
+            Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+                // The body arrives after the headers, so it is not read along with them
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    sender.send_data("allowed".into()).await.unwrap();
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    addr
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_reuses_service_connection(ctx: &mut HttpTestContext) {
+    for _ in 0..2 {
+        ctx.add(Arc::new(|_req: Request<Body>| {
+            Box::pin(async move { Ok(Response::new(Body::empty())) })
+        }));
+    }
+    let ports = Arc::new(Mutex::new(HashSet::new()));
+    let addr = counting_service(ports.clone()).await;
+    let proxy = proxy(addr.port());
+
+    for _ in 0..2 {
+        let resp = proxy
+            .call(
+                "192.0.2.1".parse().unwrap(),
+                &format!("http://127.0.0.1:{}", ctx.port),
+                request("Bearer valid"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    }
+
+    assert_eq!(ports.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,139 @@
+use hyper::header::HeaderMap;
+use hyper::{Body, Request, Response};
+use hyper_reverse_proxy::forwarded::{append_forwarded_for, forwarded_for, X_FORWARDED_FOR};
+use hyper_reverse_proxy::{ProxyError, ReverseProxy};
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn headers(values: &[&'static str]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for value in values {
+        headers.append(X_FORWARDED_FOR, value.parse().unwrap());
+    }
+    headers
+}
+
+fn appended(values: &[&'static str], client_ip: &str) -> Vec<String> {
+    let mut headers = headers(values);
+    append_forwarded_for(&mut headers, client_ip.parse().unwrap()).unwrap();
+
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .map(|value| value.to_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn test_appends_to_chains() {
+    assert_eq!(appended(&[], "10.0.0.1"), ["10.0.0.1"]);
+    assert_eq!(
+        appended(&["203.0.113.7"], "10.0.0.1"),
+        ["203.0.113.7, 10.0.0.1"]
+    );
+    assert_eq!(
+        appended(&["203.0.113.7,198.51.100.1 ,  "], "::1"),
+        ["203.0.113.7, 198.51.100.1, ::1"]
+    );
+    assert_eq!(
+        appended(&["203.0.113.7", "198.51.100.1, 10.0.0.2"], "10.0.0.3"),
+        ["203.0.113.7, 198.51.100.1, 10.0.0.2, 10.0.0.3"]
+    );
+}
+
+#[test]
+fn test_drops_invalid_values_when_appending() {
+    let mut headers = headers(&["203.0.113.7"]);
+    headers.append(
+        X_FORWARDED_FOR,
+        hyper::header::HeaderValue::from_bytes(b"198.51.100.\xff").unwrap(),
+    );
+    append_forwarded_for(&mut headers, "10.0.0.1".parse().unwrap()).unwrap();
+
+    assert_eq!(headers[X_FORWARDED_FOR], "203.0.113.7, 10.0.0.1");
+}
+
+#[test]
+fn test_reads_chains() {
+    assert!(forwarded_for(&headers(&[])).unwrap().is_empty());
+    assert_eq!(
+        forwarded_for(&headers(&["a, b", "c"])).unwrap(),
+        ["a", "b", "c"]
+    );
+
+    let mut invalid = HeaderMap::new();
+    invalid.insert(
+        X_FORWARDED_FOR,
+        hyper::header::HeaderValue::from_bytes(b"\xff").unwrap(),
+    );
+    assert!(matches!(
+        forwarded_for(&invalid),
+        Err(ProxyError::ForwardHeaderError)
+    ));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_forwards_appended_chain(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let chain = req
+                .headers()
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect::<Vec<_>>()
+                .join(" | ");
+            Ok(Response::new(Body::from(chain)))
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new());
+
+    let resp = proxy
+        .call(
+            "192.0.2.10".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/")
+                .header(X_FORWARDED_FOR, "203.0.113.7")
+                .header(X_FORWARDED_FOR, "198.51.100.1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    assert_eq!(body, "203.0.113.7, 198.51.100.1, 192.0.2.10");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_forwards_requests_with_invalid_chain(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let chain = req.headers()[X_FORWARDED_FOR].to_str().unwrap().to_string();
+            Ok(Response::new(Body::from(chain)))
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new());
+
+    let resp = proxy
+        .call(
+            "192.0.2.10".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/")
+                .header(
+                    X_FORWARDED_FOR,
+                    hyper::header::HeaderValue::from_bytes("café".as_bytes()).unwrap(),
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), hyper::StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    assert_eq!(body, "192.0.2.10");
+}
@@ -119,7 +119,7 @@ async fn handle(
 }
 
 #[async_trait::async_trait]
-impl<'a> AsyncTestContext for ProxyTestContext {
+impl AsyncTestContext for ProxyTestContext {
     async fn setup() -> ProxyTestContext {
         let http_back: HttpTestContext = AsyncTestContext::setup().await;
         let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
@@ -148,7 +148,7 @@ impl<'a> AsyncTestContext for ProxyTestContext {
         }
     }
     async fn teardown(self) {
-        let _ = AsyncTestContext::teardown(self.http_back);
+        AsyncTestContext::teardown(self.http_back).await;
         let _ = self.sender.send(()).unwrap();
         let _ = tokio::join!(self.proxy_handler);
     }
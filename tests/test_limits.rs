@@ -0,0 +1,134 @@
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::limits::BodyLimits;
+use hyper_reverse_proxy::{ProxyError, ReverseProxy};
+use std::convert::Infallible;
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn proxy(limits: BodyLimits) -> ReverseProxy<hyper::client::HttpConnector> {
+    ReverseProxy::new(hyper::Client::new()).with_limits(limits)
+}
+
+fn chunked(chunks: usize, size: usize) -> Body {
+    Body::wrap_stream(futures::stream::iter(
+        (0..chunks).map(move |_| Ok::<_, Infallible>(Bytes::from(vec![b'a'; size]))),
+    ))
+}
+
+fn echo_handler(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .unwrap_or_default();
+            Ok(Response::new(Body::from(body)))
+        })
+    }));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_declared_request_body_too_large(ctx: &mut HttpTestContext) {
+    let resp = proxy(BodyLimits::new().max_request_body(4))
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/upload")
+                .header("content-length", "5")
+                .body(Body::from("hello"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_streamed_request_body_too_large(ctx: &mut HttpTestContext) {
+    echo_handler(ctx);
+
+    let resp = proxy(BodyLimits::new().max_request_body(1024))
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/upload").body(chunked(8, 512)).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_request_body_within_limit(ctx: &mut HttpTestContext) {
+    echo_handler(ctx);
+
+    let resp = proxy(BodyLimits::new().max_request_body(1024))
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/upload").body(chunked(2, 512)).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        hyper::body::to_bytes(resp.into_body()).await.unwrap().len(),
+        1024
+    );
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_declared_response_body_too_large(ctx: &mut HttpTestContext) {
+    echo_handler(ctx);
+
+    let result = proxy(BodyLimits::new().max_response_body(4))
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::post("/").body(Body::from("hello")).unwrap(),
+        )
+        .await;
+    assert!(matches!(result, Err(ProxyError::ResponseBodyTooLarge)));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_streamed_response_body_too_large(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async { Ok(Response::new(chunked(8, 512))) })
+    }));
+
+    let resp = proxy(BodyLimits::new().max_response_body(1024))
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(hyper::body::to_bytes(resp.into_body()).await.is_err());
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_head_response_is_not_limited(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async { Ok(Response::new(Body::from(vec![b'a'; 2048]))) })
+    }));
+
+    let resp = proxy(BodyLimits::new().max_response_body(1024))
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::head("/").body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-length"], "2048");
+}
@@ -0,0 +1,134 @@
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper_reverse_proxy::buffer::RequestBuffering;
+use hyper_reverse_proxy::mirror::Mirror;
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use test_context::test_context;
+use test_context::AsyncTestContext;
+use tokio::sync::mpsc;
+use tokiotest_httpserver::HttpTestContext;
+
+// Starts a mirror upstream reporting the method, path and body of the request it receives
+async fn mirror_upstream() -> (
+    HttpTestContext,
+    mpsc::UnboundedReceiver<(Method, String, String)>,
+) {
+    let mut ctx = <HttpTestContext as AsyncTestContext>::setup().await;
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    ctx.add(Arc::new(move |req: Request<Body>| {
+        let sender = sender.clone();
+        Box::pin(async move {
+            let method = req.method().clone();
+            let path = req.uri().path().to_string();
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            sender
+                .send((method, path, String::from_utf8(body.to_vec()).unwrap()))
+                .unwrap();
+            Ok(Response::new(Body::from("ignored")))
+        })
+    }));
+
+    (ctx, receiver)
+}
+
+fn echo(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move { Ok(Response::new(req.into_body())) })
+    }));
+}
+
+fn post(body: &'static str) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri("/submit")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn send(
+    proxy: &ReverseProxy<hyper::client::HttpConnector>,
+    ctx: &HttpTestContext,
+    request: Request<Body>,
+) -> (StatusCode, String) {
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            request,
+        )
+        .await
+        .unwrap();
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_mirrors_streamed_request(ctx: &mut HttpTestContext) {
+    echo(ctx);
+    let (mirror_ctx, mut mirrored) = mirror_upstream().await;
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_mirror(Mirror::new(format!("http://127.0.0.1:{}", mirror_ctx.port)));
+
+    let (status, body) = send(&proxy, ctx, post("payload")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "payload"));
+
+    let (method, path, body) = mirrored.recv().await.unwrap();
+    assert_eq!(method, Method::POST);
+    assert_eq!(path, "/submit");
+    assert_eq!(body, "payload");
+
+    AsyncTestContext::teardown(mirror_ctx).await;
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_mirrors_buffered_request(ctx: &mut HttpTestContext) {
+    echo(ctx);
+    let (mirror_ctx, mut mirrored) = mirror_upstream().await;
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_request_buffering(RequestBuffering::new())
+        .with_mirror(Mirror::new(format!("http://127.0.0.1:{}", mirror_ctx.port)));
+
+    let (status, body) = send(&proxy, ctx, post("buffered")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "buffered"));
+    assert_eq!(mirrored.recv().await.unwrap().2, "buffered");
+
+    AsyncTestContext::teardown(mirror_ctx).await;
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_mirrors_body_replaced_by_hook(ctx: &mut HttpTestContext) {
+    echo(ctx);
+    let (mirror_ctx, mut mirrored) = mirror_upstream().await;
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_request_buffering(RequestBuffering::new())
+        .with_request_hook(|_client_ip: IpAddr, req: &mut Request<Body>| {
+            *req.body_mut() = Body::from("replaced");
+            None
+        })
+        .with_mirror(Mirror::new(format!("http://127.0.0.1:{}", mirror_ctx.port)));
+
+    let (status, body) = send(&proxy, ctx, post("original")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "replaced"));
+    assert_eq!(mirrored.recv().await.unwrap().2, "replaced");
+
+    AsyncTestContext::teardown(mirror_ctx).await;
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_failing_mirror_does_not_affect_response(ctx: &mut HttpTestContext) {
+    echo(ctx);
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_mirror(Mirror::new("http://127.0.0.1:1").timeout(Duration::from_millis(100)));
+
+    let (status, body) = send(&proxy, ctx, post("unaffected")).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "unaffected"));
+}
@@ -0,0 +1,203 @@
+use async_trait::async_trait;
+use hyper::header::{HeaderName, AUTHORIZATION, RETRY_AFTER};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::auth::Auth;
+use hyper_reverse_proxy::rate_limit::{Key, MemoryStore, Quota, RateLimit, RateLimitStore};
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn ip(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+}
+
+fn request(api_key: Option<&str>) -> Request<Body> {
+    let mut request = Request::get("/");
+    if let Some(api_key) = api_key {
+        request = request.header("x-api-key", api_key);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn test_allows_bursts_then_steady_rate() {
+    let limit = RateLimit::new(Quota::per_minute(2));
+
+    let first = limit.check(ip("10.0.0.1"), &request(None)).await.unwrap();
+    assert!(first.allowed);
+    assert_eq!((first.limit, first.remaining), (2, 1));
+
+    let second = limit.check(ip("10.0.0.1"), &request(None)).await.unwrap();
+    assert!(second.allowed);
+    assert_eq!(second.remaining, 0);
+    assert!(second.reset > Duration::from_secs(59));
+
+    let third = limit.check(ip("10.0.0.1"), &request(None)).await.unwrap();
+    assert!(!third.allowed);
+    let retry_after = third.retry_after.unwrap();
+    assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+
+    let other = limit.check(ip("10.0.0.2"), &request(None)).await.unwrap();
+    assert!(other.allowed);
+}
+
+async fn allowed(limit: &RateLimit, api_key: Option<&str>, client_ip: &str) -> bool {
+    let request = request(api_key);
+    limit.check(ip(client_ip), &request).await.unwrap().allowed
+}
+
+#[tokio::test]
+async fn test_keys_by_header_and_custom_extractor() {
+    let limit =
+        RateLimit::new(Quota::per_hour(1)).key(Key::Header(HeaderName::from_static("x-api-key")));
+
+    assert!(allowed(&limit, Some("a"), "10.0.0.1").await);
+    assert!(!allowed(&limit, Some("a"), "10.0.0.2").await);
+    assert!(allowed(&limit, Some("b"), "10.0.0.1").await);
+    assert!(allowed(&limit, None, "10.0.0.1").await);
+    assert!(!allowed(&limit, None, "10.0.0.1").await);
+
+    let limit = RateLimit::new(Quota::per_hour(1)).key(|_: IpAddr, request: &Request<Body>| {
+        request
+            .headers()
+            .get("x-api-key")
+            .map(|_| "authenticated".to_string())
+    });
+    assert!(
+        limit
+            .check(ip("10.0.0.1"), &request(Some("a")))
+            .await
+            .unwrap()
+            .allowed
+    );
+    assert!(
+        !limit
+            .check(ip("10.0.0.2"), &request(Some("b")))
+            .await
+            .unwrap()
+            .allowed
+    );
+    assert!(limit.check(ip("10.0.0.1"), &request(None)).await.is_none());
+}
+
+struct SharedStore(Arc<MemoryStore>);
+
+#[async_trait]
+impl RateLimitStore for SharedStore {
+    async fn get(&self, key: &str) -> Option<u64> {
+        self.0.get(key).await
+    }
+
+    async fn compare_and_swap(&self, key: &str, current: Option<u64>, new: u64) -> bool {
+        self.0.compare_and_swap(key, current, new).await
+    }
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_answers_limited_requests_with_too_many_requests(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move { Ok(Response::new(Body::from("upstream"))) })
+    }));
+    // Two proxy instances enforcing one limit through a shared store
+    let store = Arc::new(MemoryStore::new());
+    let proxies = [
+        ReverseProxy::new(hyper::Client::new()).with_rate_limit(
+            RateLimit::new(Quota::per_minute(1)).store(SharedStore(store.clone())),
+        ),
+        ReverseProxy::new(hyper::Client::new())
+            .with_rate_limit(RateLimit::new(Quota::per_minute(1)).store(SharedStore(store))),
+    ];
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+
+    let resp = proxies[0]
+        .call(ip("10.0.0.1"), &upstream, request(None))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["ratelimit-limit"], "1");
+    assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+    assert_eq!(resp.headers()["ratelimit-reset"], "60");
+    assert!(!resp.headers().contains_key(RETRY_AFTER));
+
+    let resp = proxies[1]
+        .call(ip("10.0.0.1"), &upstream, request(None))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()[RETRY_AFTER], "60");
+    assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+}
+
+#[tokio::test]
+async fn test_large_bursts_do_not_overflow() {
+    let limit = RateLimit::new(Quota::per_hour(1).burst(u32::MAX));
+
+    let decision = limit.check(ip("10.0.0.1"), &request(None)).await.unwrap();
+    assert!(decision.allowed);
+}
+
+async fn send_with_key(
+    proxy: &ReverseProxy<hyper::client::HttpConnector>,
+    ctx: &HttpTestContext,
+    key: &str,
+) -> Response<Body> {
+    let request = Request::get("/")
+        .header(AUTHORIZATION, format!("Bearer {}", key))
+        .body(Body::empty())
+        .unwrap();
+    proxy
+        .call(
+            ip("10.0.0.1"),
+            &format!("http://127.0.0.1:{}", ctx.port),
+            request,
+        )
+        .await
+        .unwrap()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_keys_by_authenticated_identity(ctx: &mut HttpTestContext) {
+    for _ in 0..2 {
+        ctx.add(Arc::new(|_req: Request<Body>| {
+            Box::pin(async move { Ok(Response::new(Body::empty())) })
+        }));
+    }
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_auth(Auth::new().api_key("key-1", "ci").api_key("key-2", "cd"))
+        .with_rate_limit(RateLimit::new(Quota::per_hour(1)).key(Key::Identity));
+
+    let send = |key| send_with_key(&proxy, ctx, key);
+
+    // Rejected credentials do not count against the limit of the client IP
+    assert_eq!(send("wrong").await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(send("key-1").await.status(), StatusCode::OK);
+    assert_eq!(send("key-1").await.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send("key-2").await.status(), StatusCode::OK);
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_limits_clients_guessing_credentials(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move { Ok(Response::new(Body::empty())) })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_auth(Auth::new().api_key("key-1", "ci"))
+        .with_rate_limit(RateLimit::new(Quota::per_hour(3)));
+
+    let send = |key| send_with_key(&proxy, ctx, key);
+
+    assert_eq!(send("wrong-1").await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(send("wrong-2").await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(send("key-1").await.status(), StatusCode::OK);
+    assert_eq!(
+        send("wrong-3").await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(send("key-1").await.status(), StatusCode::TOO_MANY_REQUESTS);
+}
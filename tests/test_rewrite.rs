@@ -0,0 +1,210 @@
+use hyper::header::{CONTENT_LOCATION, HOST, LOCATION, SET_COOKIE};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use hyper_reverse_proxy::rewrite::{BodyRewrite, CookieRewrite, RedirectRewrite, SameSite};
+use hyper_reverse_proxy::ReverseProxy;
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn redirect(ctx: &mut HttpTestContext, headers: Vec<(&'static str, String)>) {
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        let headers = headers.clone();
+        Box::pin(async move {
+            let mut response = Response::builder().status(StatusCode::FOUND);
+            for (name, value) in headers {
+                response = response.header(name, value);
+            }
+            Ok(response.body(Body::empty()).unwrap())
+        })
+    }));
+}
+
+async fn send(proxy: &ReverseProxy<hyper::client::HttpConnector>, forward_uri: &str) -> HeaderMap {
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            forward_uri,
+            Request::get("/page")
+                .header(HOST, "proxy.example")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    resp.headers().clone()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_rewrites_upstream_origin_and_prefix(ctx: &mut HttpTestContext) {
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    redirect(
+        ctx,
+        vec![
+            ("location", format!("{}/app/login?next=1", upstream)),
+            ("content-location", "/app/page".to_string()),
+            ("refresh", format!("5; url='{}/app'", upstream)),
+        ],
+    );
+    redirect(
+        ctx,
+        vec![("location", "http://elsewhere.example/app/login".to_string())],
+    );
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_redirect_rewrite(RedirectRewrite::new().scheme("https"));
+
+    let headers = send(&proxy, &format!("{}/app/", upstream)).await;
+    assert_eq!(headers[LOCATION], "https://proxy.example/login?next=1");
+    assert_eq!(headers[CONTENT_LOCATION], "/page");
+    assert_eq!(headers["refresh"], "5; url='https://proxy.example/'");
+
+    let headers = send(&proxy, &format!("{}/app/", upstream)).await;
+    assert_eq!(headers[LOCATION], "http://elsewhere.example/app/login");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_applies_explicit_rules(ctx: &mut HttpTestContext) {
+    redirect(
+        ctx,
+        vec![("location", "http://internal:8080/admin".to_string())],
+    );
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_redirect_rewrite(
+        RedirectRewrite::new().automatic(false).rule(
+            "http://internal:8080/",
+            "https://public.example/backoffice/",
+        ),
+    );
+
+    let headers = send(&proxy, &format!("http://127.0.0.1:{}", ctx.port)).await;
+    assert_eq!(headers[LOCATION], "https://public.example/backoffice/admin");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_rewrites_cookie_attributes(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move {
+            Ok(Response::builder()
+                .header(
+                    "set-cookie",
+                    "session=abc; Domain=.backend.internal; Path=/; SameSite=None",
+                )
+                .header("set-cookie", "pref=1; path=/settings; Secure")
+                .header("set-cookie", "other=2; Domain=unrelated.example")
+                .body(Body::empty())
+                .unwrap())
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_cookie_rewrite(
+        CookieRewrite::new()
+            .domain("backend.internal", "public.example")
+            .domain("unrelated.example", "")
+            .path("/", "/app/")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax),
+    );
+
+    let headers = send(&proxy, &format!("http://127.0.0.1:{}", ctx.port)).await;
+    let cookies = headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .map(|value| value.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        cookies,
+        [
+            "session=abc; Domain=public.example; Path=/app/; Secure; HttpOnly; SameSite=Lax",
+            "pref=1; path=/app/settings; Secure; HttpOnly; SameSite=Lax",
+            "other=2; Secure; HttpOnly; SameSite=Lax",
+        ]
+    );
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_rewrites_urls_in_streamed_body(ctx: &mut HttpTestContext) {
+    let upstream = format!("http://127.0.0.1:{}/app", ctx.port);
+    let html = format!(
+        "<a href=\"{}/login\">Login</a><img src='/app/logo.png'><link href=\"//cdn.example/app/x.css\"><p>/app/ stays</p>",
+        upstream
+    );
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        let html = html.clone();
+        Box::pin(async move {
+            // Split the body in small chunks, so URLs straddle chunk boundaries
+            let chunks = html
+                .as_bytes()
+                .chunks(3)
+                .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+                .collect::<Vec<_>>();
+            Ok(Response::builder()
+                .header("content-type", "text/html; charset=utf-8")
+                .body(Body::wrap_stream(futures::stream::iter(chunks)))
+                .unwrap())
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_body_rewrite(BodyRewrite::new(&upstream, "https://public.example/legacy"));
+
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &upstream,
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    assert_eq!(
+        body,
+        "<a href=\"https://public.example/legacy/login\">Login</a><img src='/legacy/logo.png'><link href=\"//cdn.example/app/x.css\"><p>/app/ stays</p>"
+    );
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_does_not_rewrite_longer_paths(ctx: &mut HttpTestContext) {
+    let upstream = format!("http://127.0.0.1:{}/app", ctx.port);
+    let html = format!(
+        "<a href=\"{0}lication/x\"></a><a href=\"{0}\"></a><a href=\"{0}?q=1\"></a>{0}",
+        upstream
+    );
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        let html = html.clone();
+        Box::pin(async move {
+            let chunks = html
+                .as_bytes()
+                .chunks(3)
+                .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+                .collect::<Vec<_>>();
+            Ok(Response::builder()
+                .header("content-type", "text/html")
+                .body(Body::wrap_stream(futures::stream::iter(chunks)))
+                .unwrap())
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_body_rewrite(BodyRewrite::new(&upstream, "https://public.example/legacy"));
+
+    let resp = proxy
+        .call(
+            "127.0.0.1".parse().unwrap(),
+            &upstream,
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+    assert_eq!(
+        body,
+        format!(
+            "<a href=\"{}lication/x\"></a><a href=\"https://public.example/legacy\"></a><a href=\"https://public.example/legacy?q=1\"></a>https://public.example/legacy",
+            upstream
+        )
+    );
+}
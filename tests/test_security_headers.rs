@@ -0,0 +1,92 @@
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::security_headers::SecurityHeaders;
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+fn upstream_response() -> Response<Body> {
+    Response::builder()
+        .header("server", "Apache/2.4.1")
+        .header("x-powered-by", "PHP/8.1")
+        .header("x-frame-options", "ALLOW-FROM https://old.example")
+        .header("content-security-policy", "default-src *")
+        .body(Body::from("upstream"))
+        .unwrap()
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_sets_headers_and_strips_fingerprints(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move { Ok(upstream_response()) })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_security_headers(
+        SecurityHeaders::recommended()
+            .hsts(Duration::from_secs(600), true, true)
+            .content_security_policy(HeaderValue::from_static("default-src 'self'"))
+            .permissions_policy(HeaderValue::from_static("camera=()")),
+    );
+
+    let resp = proxy
+        .call(
+            CLIENT_IP,
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    let headers = resp.headers();
+    assert_eq!(
+        headers["strict-transport-security"],
+        "max-age=600; includeSubDomains; preload"
+    );
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(headers["x-frame-options"], "SAMEORIGIN");
+    assert_eq!(
+        headers["referrer-policy"],
+        "strict-origin-when-cross-origin"
+    );
+    assert_eq!(headers["content-security-policy"], "default-src 'self'");
+    assert_eq!(headers["permissions-policy"], "camera=()");
+    assert!(headers.get("server").is_none());
+    assert!(headers.get("x-powered-by").is_none());
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_keeps_upstream_headers_without_overwrite(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move { Ok(upstream_response()) })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_security_headers(
+        SecurityHeaders::new()
+            .frame_options(HeaderValue::from_static("DENY"))
+            .nosniff()
+            .keep_header(HeaderName::from_static("server"))
+            .overwrite(false),
+    );
+
+    let resp = proxy
+        .call(
+            CLIENT_IP,
+            &format!("http://127.0.0.1:{}", ctx.port),
+            Request::get("/").body(Body::empty()).unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let headers = resp.headers();
+    assert_eq!(headers["x-frame-options"], "ALLOW-FROM https://old.example");
+    assert_eq!(headers["content-security-policy"], "default-src *");
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(headers["server"], "Apache/2.4.1");
+    assert!(headers.get("x-powered-by").is_none());
+}
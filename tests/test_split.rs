@@ -0,0 +1,131 @@
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::split::{Stickiness, TrafficSplit};
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::IpAddr;
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+fn request(headers: &[(&str, &str)]) -> Request<Body> {
+    let mut request = Request::get("/");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+fn ip(i: u32) -> IpAddr {
+    IpAddr::from((0x0a00_0000 + i).to_be_bytes())
+}
+
+#[test]
+fn test_splits_by_weight() {
+    let split = TrafficSplit::new()
+        .upstream("http://stable", 90)
+        .upstream("http://canary", 10)
+        .upstream("http://disabled", 0);
+
+    let canary = (0..10_000)
+        .filter(|i| split.select(ip(*i), &request(&[])) == Some("http://canary"))
+        .count();
+    assert!(
+        (800..1200).contains(&canary),
+        "{} requests to canary",
+        canary
+    );
+
+    assert!((0..1000).all(|i| split.select(ip(i), &request(&[])) != Some("http://disabled")));
+    assert_eq!(TrafficSplit::new().select(ip(0), &request(&[])), None);
+}
+
+#[test]
+fn test_routes_by_header_and_cookie() {
+    let split = TrafficSplit::new()
+        .upstream("http://stable", 100)
+        .route_header("x-canary", "1", "http://canary")
+        .route_cookie("beta", "yes", "http://beta");
+
+    assert_eq!(
+        split.select(ip(1), &request(&[("x-canary", "1")])),
+        Some("http://canary")
+    );
+    assert_eq!(
+        split.select(ip(1), &request(&[("cookie", "theme=dark; beta=yes")])),
+        Some("http://beta")
+    );
+    assert_eq!(
+        split.select(ip(1), &request(&[("cookie", "beta=no")])),
+        Some("http://stable")
+    );
+}
+
+#[test]
+fn test_sticks_to_upstream_per_user() {
+    let split = TrafficSplit::new()
+        .upstream("http://a", 50)
+        .upstream("http://b", 50)
+        .sticky_by(Stickiness::Cookie("user".to_string()));
+
+    for user in 0..100 {
+        let cookie = format!("user={}", user);
+        let first = split.select(ip(0), &request(&[("cookie", &cookie)]));
+        assert!((1..100).all(|i| split.select(ip(i), &request(&[("cookie", &cookie)])) == first));
+    }
+
+    // Without the cookie, the client IP decides
+    let first = split.select(ip(7), &request(&[]));
+    assert!((0..100).all(|_| split.select(ip(7), &request(&[])) == first));
+}
+
+#[test]
+fn test_ramping_up_only_moves_users_to_canary() {
+    let split = |canary| {
+        TrafficSplit::new()
+            .upstream("http://stable", 100 - canary)
+            .upstream("http://canary", canary)
+    };
+    let (before, after) = (split(5), split(10));
+
+    for i in 0..10_000 {
+        let was = before.select(ip(i), &request(&[]));
+        let is = after.select(ip(i), &request(&[]));
+        assert!(
+            was == is || is == Some("http://canary"),
+            "{:?} -> {:?}",
+            was,
+            is
+        );
+    }
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_proxies_to_selected_upstream(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move { Ok(Response::new(Body::from(req.uri().path().to_string()))) })
+    }));
+
+    let proxy = ReverseProxy::new(hyper::Client::new());
+    let split = TrafficSplit::new()
+        .upstream("http://127.0.0.1:1/stable", 100)
+        .route_header(
+            "x-canary",
+            "1",
+            format!("http://127.0.0.1:{}/canary", ctx.port),
+        );
+
+    let resp = proxy
+        .call_with_split(ip(1), &split, request(&[("x-canary", "1")]))
+        .await
+        .unwrap();
+    assert_eq!(
+        hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+        "/canary/"
+    );
+
+    let resp = proxy
+        .call_with_split(ip(1), &TrafficSplit::new(), request(&[]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
@@ -0,0 +1,66 @@
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use hyper_reverse_proxy::access_log::{AccessLog, AccessLogFormat};
+use hyper_reverse_proxy::limits::BodyLimits;
+use hyper_reverse_proxy::ReverseProxy;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+// An HTTP/2 upstream answering like a gRPC server, with the status in trailers
+async fn grpc_upstream() -> SocketAddr {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data("message".into()).await.unwrap();
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                sender.send_trailers(trailers).await.unwrap();
+            });
+            Ok::<_, Infallible>(Response::new(body))
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into())
+        .http2_only(true)
+        .serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    addr
+}
+
+#[tokio::test]
+async fn test_forwards_trailers_through_body_wrappers() {
+    let addr = grpc_upstream().await;
+    let proxy = ReverseProxy::new(Client::builder().http2_only(true).build_http())
+        .with_access_log(AccessLog::new(AccessLogFormat::Common, |_: &str| {}))
+        .with_limits(BodyLimits::new().max_response_body(1024));
+
+    let resp = proxy
+        .call(
+            CLIENT_IP,
+            &format!("http://{}", addr),
+            Request::post("/helloworld.Greeter/SayHello")
+                .header("te", "trailers")
+                .body(Body::from("request"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let mut body = resp.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(data, b"message");
+
+    let trailers = hyper::body::HttpBody::trailers(&mut body)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+}
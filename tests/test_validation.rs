@@ -0,0 +1,142 @@
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::validation::{validate_request, Violation, MAX_HEADER_SECTION};
+use hyper_reverse_proxy::{ProxyError, ReverseProxy};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+fn request(uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+    let mut builder = Request::post(uri);
+    for (name, value) in headers {
+        builder = builder.header(*name, *value);
+    }
+    builder.body(Body::empty()).unwrap()
+}
+
+fn validate(uri: &str, headers: &[(&str, &str)]) -> Result<(), Violation> {
+    validate_request(&request(uri, headers))
+}
+
+#[test]
+fn test_rejects_ambiguous_framing() {
+    assert_eq!(validate("/", &[("content-length", "5")]), Ok(()));
+    assert_eq!(
+        validate("/", &[("transfer-encoding", "gzip, chunked")]),
+        Ok(())
+    );
+
+    assert_eq!(
+        validate(
+            "/",
+            &[("content-length", "5"), ("transfer-encoding", "chunked")]
+        ),
+        Err(Violation::ConflictingFraming)
+    );
+    assert_eq!(
+        validate("/", &[("content-length", "5"), ("content-length", "5")]),
+        Err(Violation::DuplicateContentLength)
+    );
+    assert_eq!(
+        validate("/", &[("content-length", "5, 6")]),
+        Err(Violation::DuplicateContentLength)
+    );
+    assert_eq!(
+        validate("/", &[("content-length", "+5")]),
+        Err(Violation::InvalidContentLength)
+    );
+    assert_eq!(
+        validate("/", &[("transfer-encoding", "chunked, gzip")]),
+        Err(Violation::InvalidTransferEncoding)
+    );
+    assert_eq!(
+        validate(
+            "/",
+            &[("transfer-encoding", "chunked"), ("transfer-encoding", "x")]
+        ),
+        Err(Violation::InvalidTransferEncoding)
+    );
+}
+
+#[test]
+fn test_rejects_invalid_headers() {
+    let mut req = request("/", &[]);
+    req.headers_mut().insert(
+        "host",
+        HeaderValue::from_bytes(b"app.ex\xc3\xa4mple").unwrap(),
+    );
+    assert_eq!(validate_request(&req), Err(Violation::InvalidHeaderValue));
+
+    // Other headers may carry non-ASCII text
+    let mut req = request("/", &[]);
+    req.headers_mut().insert(
+        "content-disposition",
+        HeaderValue::from_bytes(b"attachment; filename=\"\xc3\xa4.txt\"").unwrap(),
+    );
+    assert_eq!(validate_request(&req), Ok(()));
+
+    let mut req = request("/", &[]);
+    let value = "a".repeat(1024);
+    for i in 0..=MAX_HEADER_SECTION / 1024 {
+        let name = HeaderName::from_bytes(format!("x-header-{}", i).as_bytes()).unwrap();
+        req.headers_mut().insert(name, value.parse().unwrap());
+    }
+    assert_eq!(
+        validate_request(&req),
+        Err(Violation::HeaderSectionTooLarge)
+    );
+}
+
+#[test]
+fn test_rejects_absolute_uris_for_other_hosts() {
+    assert_eq!(
+        validate("http://app.example/", &[("host", "APP.example:80")]),
+        Ok(())
+    );
+    assert_eq!(validate("https://app.example/", &[]), Ok(()));
+    assert_eq!(
+        validate("http://internal.example/", &[("host", "app.example")]),
+        Err(Violation::AuthorityMismatch)
+    );
+    assert_eq!(
+        validate("http://app.example:8080/", &[("host", "app.example")]),
+        Err(Violation::AuthorityMismatch)
+    );
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_answers_smuggling_attempts_with_bad_request(ctx: &mut HttpTestContext) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let upstream_calls = calls.clone();
+    ctx.add(Arc::new(move |_req: Request<Body>| {
+        upstream_calls.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { Ok(Response::new(Body::empty())) })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new());
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    let smuggling = || {
+        request(
+            "/",
+            &[("content-length", "4"), ("transfer-encoding", "chunked")],
+        )
+    };
+
+    let err = proxy
+        .call(CLIENT_IP, &upstream, smuggling())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ProxyError::InvalidRequest(Violation::ConflictingFraming)
+    ));
+    assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+    let resp = proxy.respond(CLIENT_IP, &upstream, smuggling()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
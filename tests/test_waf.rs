@@ -0,0 +1,145 @@
+use hyper::header::HeaderName;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_reverse_proxy::buffer::RequestBuffering;
+use hyper_reverse_proxy::waf::{self, Action, Condition, Rule, Target, Waf};
+use hyper_reverse_proxy::ReverseProxy;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use test_context::test_context;
+use tokiotest_httpserver::HttpTestContext;
+
+const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+fn blocked(waf: &Waf, uri: &str) -> bool {
+    let mut req = Request::get(uri).body(Body::empty()).unwrap();
+    waf.check(CLIENT_IP, &mut req).is_some()
+}
+
+#[test]
+fn test_builtin_rules_block_attacks() {
+    let waf = Waf::new().rules(waf::builtin_rules());
+
+    assert!(blocked(&waf, "/static/..%2f..%2fetc/passwd"));
+    assert!(blocked(&waf, "/download?file=..%5C..%5Cboot.ini"));
+    assert!(blocked(&waf, "/items?id=1'+OR+'1'='1"));
+    assert!(blocked(
+        &waf,
+        "/items?id=1+UNION+ALL+SELECT+password+FROM+users"
+    ));
+    assert!(blocked(&waf, "/search?q=%3Cscript%3Ealert(1)%3C/script%3E"));
+    assert!(blocked(&waf, "/search?q=%3Cimg+src=x+onerror=alert(1)%3E"));
+    assert!(blocked(&waf, "/ping?host=127.0.0.1;cat+/etc/hosts"));
+
+    assert!(!blocked(&waf, "/"));
+    assert!(!blocked(&waf, "/docs/select-from-a-list?q=union+station"));
+    assert!(!blocked(&waf, "/search?q=rock+%26+roll&page=2"));
+    assert!(!blocked(&waf, "/files/report..final.pdf"));
+    assert!(!blocked(&waf, "/items?a=1;id=5"));
+    assert!(!blocked(&waf, "/items?x=1&&id=2"));
+    assert!(blocked(&waf, "/ping?host=127.0.0.1;id"));
+}
+
+#[test]
+#[should_panic(expected = "WAF rule everything has no conditions")]
+fn test_rejects_rules_without_conditions() {
+    let _ = Waf::new().rule(Rule::new("everything"));
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_inspects_buffered_bodies(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|_req: Request<Body>| {
+        Box::pin(async move { Ok(Response::new(Body::from("upstream"))) })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new())
+        .with_request_buffering(RequestBuffering::new())
+        .with_waf(
+            Waf::new()
+                .rules(waf::builtin_rules())
+                .rule(Rule::new("large-body").when(Target::Body, Condition::larger_than(16))),
+        );
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    let send = |content_type: &'static str, body: &'static str| {
+        proxy.call(
+            CLIENT_IP,
+            &upstream,
+            Request::post("/comments")
+                .header("content-type", content_type)
+                .body(Body::from(body))
+                .unwrap(),
+        )
+    };
+
+    let resp = send("application/x-www-form-urlencoded", "c=%3Cscript%3E")
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = send("text/plain", "0123456789abcdefg").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = send("text/plain", "nice post").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "upstream");
+}
+
+#[test_context(HttpTestContext)]
+#[tokio::test]
+async fn test_tags_matching_requests(ctx: &mut HttpTestContext) {
+    ctx.add(Arc::new(|req: Request<Body>| {
+        Box::pin(async move {
+            let tags = req
+                .headers()
+                .get("x-waf-rules")
+                .map(|value| value.to_str().unwrap().to_string())
+                .unwrap_or_default();
+            Ok(Response::new(Body::from(tags)))
+        })
+    }));
+    let proxy = ReverseProxy::new(hyper::Client::new()).with_waf(
+        Waf::new()
+            .rules(
+                waf::builtin_rules()
+                    .into_iter()
+                    .map(|rule| rule.action(Action::Tag)),
+            )
+            .rule(
+                Rule::new("curl")
+                    .when(
+                        Target::Header(HeaderName::from_static("user-agent")),
+                        Condition::contains("CURL/"),
+                    )
+                    .action(Action::Tag),
+            )
+            .rule(
+                Rule::new("admin-delete")
+                    .when(Target::Method, Condition::equals("DELETE"))
+                    .when(Target::Path, Condition::regex("^/admin/").unwrap())
+                    .action(Action::Log),
+            ),
+    );
+    let upstream = format!("http://127.0.0.1:{}", ctx.port);
+    let send = |uri: &'static str, user_agent: &'static str| {
+        proxy.call(
+            CLIENT_IP,
+            &upstream,
+            Request::delete(uri)
+                .header("user-agent", user_agent)
+                .header("x-waf-rules", "forged")
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let resp = send("/admin/users?id=1'+or+'a'='a", "curl/8.0")
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "sql-injection, curl");
+
+    let resp = send("/admin/users", "Mozilla/5.0").await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "");
+}
@@ -80,7 +80,7 @@ async fn handle(
 }
 
 #[async_trait::async_trait]
-impl<'a> AsyncTestContext for ProxyTestContext {
+impl AsyncTestContext for ProxyTestContext {
     async fn setup() -> ProxyTestContext {
         tokio::spawn(async {
             tokio::time::sleep(Duration::from_secs(5)).await;